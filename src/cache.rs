@@ -0,0 +1,246 @@
+use crate::output::{ensure_output_dir, fnv1a_64, OutputError};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CacheError {
+    #[error("Failed to access prompt cache: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Failed to serialize prompt cache: {0}")]
+    SerializeError(#[from] serde_json::Error),
+    #[error(transparent)]
+    OutputError(#[from] OutputError),
+}
+
+/// Name of the cache file inside the output directory. Its extension is not a capture
+/// extension, so capture cleanup and history never pick it up.
+const CACHE_FILENAME: &str = "prompt_cache.cache";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    summary: String,
+    /// Unix timestamp (seconds) of when the summary was stored
+    created: i64,
+}
+
+impl CacheEntry {
+    fn is_expired(&self, ttl_minutes: u32, now: i64) -> bool {
+        now - self.created > ttl_minutes as i64 * 60
+    }
+}
+
+/// Summaries keyed by a hash of the exact prompt that produced them.
+#[derive(Debug, Default)]
+pub struct PromptCache {
+    path: PathBuf,
+    entries: HashMap<String, CacheEntry>,
+    /// TTL of the last `evict_expired`, applied again to the entries on disk when saving
+    evicted_ttl: Option<u32>,
+}
+
+/// Cache key for a prompt (64-bit FNV-1a as 16 hex chars).
+pub fn prompt_key(prompt: &str) -> String {
+    format!("{:016x}", fnv1a_64(prompt.as_bytes()))
+}
+
+impl PromptCache {
+    /// Opens the cache file in the default output directory, creating the directory as
+    /// `ensure_output_dir` does.
+    pub fn open(restrict_permissions: bool) -> Result<Self, CacheError> {
+        let dir = ensure_output_dir(restrict_permissions)?;
+        Ok(Self::open_at(&dir.join(CACHE_FILENAME)))
+    }
+
+    /// Opens the cache stored at `path`. A missing or unreadable file gives an empty cache.
+    pub fn open_at(path: &Path) -> Self {
+        Self { path: path.to_path_buf(), entries: read_entries(path), evicted_ttl: None }
+    }
+
+    /// Returns the cached summary for `prompt` if it was stored less than `ttl_minutes` ago.
+    pub fn get(&self, prompt: &str, ttl_minutes: u32) -> Option<&str> {
+        self.entries
+            .get(&prompt_key(prompt))
+            .filter(|entry| !entry.is_expired(ttl_minutes, Utc::now().timestamp()))
+            .map(|entry| entry.summary.as_str())
+    }
+
+    pub fn insert(&mut self, prompt: &str, summary: &str) {
+        self.entries.insert(
+            prompt_key(prompt),
+            CacheEntry { summary: summary.to_string(), created: Utc::now().timestamp() },
+        );
+    }
+
+    /// Drops entries older than `ttl_minutes`, returning how many were removed.
+    pub fn evict_expired(&mut self, ttl_minutes: u32) -> usize {
+        let now = Utc::now().timestamp();
+        self.evicted_ttl = Some(ttl_minutes);
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| !entry.is_expired(ttl_minutes, now));
+        before - self.entries.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Writes the cache back to disk. Entries other runs saved since this cache was opened
+    /// are kept (the newer entry wins for the same prompt), under a lock file so parallel
+    /// runs don't drop each other's entries. The file is replaced atomically so readers
+    /// never see a half-written cache.
+    pub fn save(&self) -> Result<(), CacheError> {
+        let _lock = lock_exclusive(&self.path.with_extension("lock"))?;
+        let mut entries = read_entries(&self.path);
+        if let Some(ttl_minutes) = self.evicted_ttl {
+            let now = Utc::now().timestamp();
+            entries.retain(|_, entry| !entry.is_expired(ttl_minutes, now));
+        }
+        for (key, entry) in &self.entries {
+            if entries.get(key).is_none_or(|saved| saved.created <= entry.created) {
+                entries.insert(key.clone(), entry.clone());
+            }
+        }
+
+        let content = serde_json::to_string(&entries)?;
+        let tmp_path = self.path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&tmp_path, content)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Reads the entries stored at `path`. A missing or unreadable file has none.
+fn read_entries(path: &Path) -> HashMap<String, CacheEntry> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Opens `path` (creating it) and takes an exclusive lock on it, released when the
+/// returned file is dropped. Without flock (non-Unix) the file is opened unlocked.
+fn lock_exclusive(path: &Path) -> std::io::Result<fs::File> {
+    let file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+        // SAFETY: flock only operates on the open descriptor owned by `file`
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(file)
+}
+
+/// Removes expired entries from the default cache file. Called during periodic cleanup.
+pub fn evict_expired_entries(ttl_minutes: u32, restrict_permissions: bool) {
+    let mut cache = match PromptCache::open(restrict_permissions) {
+        Ok(cache) => cache,
+        Err(e) => {
+            eprintln!("Warning: {}", e);
+            return;
+        }
+    };
+    if cache.evict_expired(ttl_minutes) > 0 {
+        if let Err(e) = cache.save() {
+            eprintln!("Warning: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_cache_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ctx_guard_cache_{}_{}.cache", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_prompt_key_is_stable() {
+        assert_eq!(prompt_key("same prompt"), prompt_key("same prompt"));
+        assert_ne!(prompt_key("same prompt"), prompt_key("other prompt"));
+        assert_eq!(prompt_key("").len(), 16);
+    }
+
+    #[test]
+    fn test_cache_roundtrip() {
+        let path = temp_cache_path("roundtrip");
+        let mut cache = PromptCache::open_at(&path);
+        assert!(cache.get("prompt", 60).is_none());
+
+        cache.insert("prompt", "summary");
+        cache.save().unwrap();
+
+        let reopened = PromptCache::open_at(&path);
+        assert_eq!(reopened.get("prompt", 60), Some("summary"));
+        assert!(reopened.get("another prompt", 60).is_none());
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_cache_ttl_and_eviction() {
+        let mut cache = PromptCache::open_at(&temp_cache_path("ttl"));
+        cache.insert("fresh", "fresh summary");
+        cache.entries.insert(
+            prompt_key("stale"),
+            CacheEntry { summary: "stale summary".to_string(), created: Utc::now().timestamp() - 90 * 60 },
+        );
+
+        assert!(cache.get("stale", 60).is_none());
+        assert_eq!(cache.get("stale", 120), Some("stale summary"));
+
+        assert_eq!(cache.evict_expired(60), 1);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get("fresh", 60), Some("fresh summary"));
+    }
+
+    #[test]
+    fn test_save_keeps_entries_saved_by_other_runs() {
+        let path = temp_cache_path("parallel");
+        let mut first = PromptCache::open_at(&path);
+        let mut second = PromptCache::open_at(&path);
+        first.insert("first prompt", "first summary");
+        second.insert("second prompt", "second summary");
+        first.save().unwrap();
+        second.save().unwrap();
+
+        let reopened = PromptCache::open_at(&path);
+        assert_eq!(reopened.get("first prompt", 60), Some("first summary"));
+        assert_eq!(reopened.get("second prompt", 60), Some("second summary"));
+
+        // Eviction also applies to entries saved after the cache was opened
+        let mut evicting = PromptCache::open_at(&path);
+        let mut writer = PromptCache::open_at(&path);
+        writer.entries.insert(
+            prompt_key("old prompt"),
+            CacheEntry { summary: "old summary".to_string(), created: Utc::now().timestamp() - 90 * 60 },
+        );
+        writer.save().unwrap();
+        evicting.evict_expired(60);
+        evicting.save().unwrap();
+        let reopened = PromptCache::open_at(&path);
+        assert!(reopened.get("old prompt", 120).is_none());
+        assert_eq!(reopened.get("second prompt", 60), Some("second summary"));
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(path.with_extension("lock"));
+    }
+
+    #[test]
+    fn test_corrupt_cache_file_is_ignored() {
+        let path = temp_cache_path("corrupt");
+        fs::write(&path, "not json").unwrap();
+        assert!(PromptCache::open_at(&path).is_empty());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -1,18 +1,88 @@
+use crate::executor::{CommandTimeout, OutputStream, ProcessOptions, Shell, TimeoutSignal};
+use crate::output::{CaptureOptions, SummaryWindow};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
 // Include the default config.toml at compile time
 const DEFAULT_CONFIG: &str = include_str!("../config.toml");
 
+/// Environment variables that override `[provider]` settings from the config file,
+/// e.g. in containers where mounting a config file is inconvenient.
+pub const PROVIDER_TYPE_ENV_VAR: &str = "CTX_GUARD_PROVIDER_TYPE";
+pub const PROVIDER_URL_ENV_VAR: &str = "CTX_GUARD_PROVIDER_URL";
+pub const MODEL_ENV_VAR: &str = "CTX_GUARD_MODEL";
+
+/// Prompt for `cg summarize-session`, combining the summaries of a session's runs.
+const SESSION_PROMPT: &str = "You are summarizing a session of related commands run by an AI agent, in the order they ran.
+
+Session: ${session}
+
+${runs}
+
+In ${summary_words} words or less, say how the session went as a whole: which steps succeeded, \
+which failed and why, and what needs attention next. Use plain text only.
+
+Respond in ${summary_language}.";
+
+/// Prompt used instead of the provider's for `--explain`: remediation steps rather than a
+/// summary. Supports the same placeholders as `prompt`.
+pub const EXPLAIN_PROMPT: &str = "You help an AI agent fix problems reported by commands it runs.
+
+${recent_commands}
+
+${user_context}
+
+Command executed: ${command}
+Exit code: ${exit_code} (${exit_status_text})
+Output:
+
+${output}
+
+Given this command output, list the specific commands to run or edits to make (with file paths \
+and line numbers when the output has them) to fix the problem, most likely fix first. Don't describe \
+what happened beyond what is needed to justify a step. If nothing needs fixing, say so in one sentence. \
+Use ${summary_words} words or less, plain text only, one step per line.
+
+Respond in ${summary_language}.";
+
+/// Prompt for `cg diff`, summarizing how a command's output changed between two runs.
+const DIFF_PROMPT: &str = "You are comparing two runs of a command for an AI agent that changed something in between.
+
+Earlier run: ${old_command}
+Exit code: ${old_exit_code} (${old_exit_status_text})
+
+Later run: ${new_command}
+Exit code: ${new_exit_code} (${new_exit_status_text})
+
+Lines removed from the earlier output start with \"- \", lines added in the later output with \"+ \":
+
+${diff}
+
+In ${summary_words} words or less, say what changed: newly passing or failing tests, resolved \
+or new errors and warnings, and what still needs attention. Ignore changes that only affect \
+timings or timestamps. Use plain text only.
+
+Respond in ${summary_language}.";
+
 #[derive(Debug, Error)]
 pub enum ConfigError {
-    #[error("Failed to read config file: {0}")]
-    ReadError(#[from] std::io::Error),
-    #[error("Failed to parse TOML: {0}")]
-    ParseError(#[from] toml::de::Error),
+    #[error("Failed to read config file {}: {source}", path.display())]
+    ReadError { path: PathBuf, source: std::io::Error },
+    #[error("Failed to write config file {}: {source}", path.display())]
+    WriteError { path: PathBuf, source: std::io::Error },
+    #[error("Failed to parse config file {}: {source}", path.display())]
+    ParseError { path: PathBuf, source: toml::de::Error },
+    #[error("Invalid provider URL '{url}': {reason} (expected something like http://127.0.0.1:1234)")]
+    InvalidUrl { url: String, reason: String },
+    #[error("Unknown provider '{0}' (define it under [providers.{0}])")]
+    UnknownProvider(String),
+    #[error("Invalid settings for provider '{name}': {reason}")]
+    InvalidProvider { name: String, reason: String },
+    #[error("Invalid prompt file {}: {reason}", path.display())]
+    InvalidPromptFile { path: PathBuf, reason: String },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,6 +99,38 @@ pub struct ProviderConfig {
     pub summary_words: u32,
     #[serde(default = "default_output_length_threshold")]
     pub output_length_threshold: u32,
+    /// Prompts longer than this (in characters) are never sent; 0 disables the check
+    #[serde(default = "default_max_prompt_chars")]
+    pub max_prompt_chars: u32,
+    /// Sampling temperature for the summary request
+    #[serde(default = "default_temperature")]
+    pub temperature: f32,
+    /// Maximum number of tokens the model may generate for a summary
+    #[serde(default = "default_max_tokens")]
+    pub max_tokens: u32,
+    /// Upper bound on in-flight requests to this provider (0 = no limit)
+    #[serde(default = "default_max_concurrent_requests")]
+    pub max_concurrent_requests: u32,
+    /// Upper bound on summary requests per minute from this process (0 = no limit)
+    #[serde(default = "default_max_requests_per_minute")]
+    pub max_requests_per_minute: u32,
+    /// Longest a request waits for `max_requests_per_minute` before falling back
+    #[serde(default = "default_rate_limit_wait_secs")]
+    pub rate_limit_wait_secs: u64,
+    /// Path appended to `url` for completion requests, replacing the provider type's
+    /// default (e.g. `/api/v1/chat/completions` behind a gateway)
+    #[serde(default)]
+    pub chat_path: Option<String>,
+    /// Retry with a streaming request when the server only supports streaming
+    #[serde(default = "default_stream_fallback")]
+    pub stream_fallback: bool,
+    /// Ask once more, with a nudge, when the model returns an empty summary
+    #[serde(default = "default_retry_empty_summary")]
+    pub retry_empty_summary: bool,
+    /// Extra fields merged into every request body (e.g. `top_p`, `seed`); they can't
+    /// replace the fields ctx_guard sets itself, like `model` or `messages`
+    #[serde(default)]
+    pub extra_params: toml::Table,
 }
 
 impl Default for ProviderConfig {
@@ -40,6 +142,16 @@ impl Default for ProviderConfig {
             prompt: default_prompt(),
             summary_words: default_summary_words(),
             output_length_threshold: default_output_length_threshold(),
+            max_prompt_chars: default_max_prompt_chars(),
+            temperature: default_temperature(),
+            max_tokens: default_max_tokens(),
+            max_concurrent_requests: default_max_concurrent_requests(),
+            max_requests_per_minute: default_max_requests_per_minute(),
+            rate_limit_wait_secs: default_rate_limit_wait_secs(),
+            chat_path: None,
+            stream_fallback: default_stream_fallback(),
+            retry_empty_summary: default_retry_empty_summary(),
+            extra_params: toml::Table::new(),
         }
     }
 }
@@ -61,8 +173,10 @@ fn default_prompt() -> String {
 
 ${recent_commands}
 
+${user_context}
+
 Command executed: ${command}
-Exit code: ${exit_code}
+Exit code: ${exit_code} (${exit_status_text})
 Output:
 
 ${output}
@@ -93,7 +207,9 @@ Generate a summary in ${summary_words} words or less following these guidelines:
    - If errors exist, suggest concrete actions to resolve them
    - If successful, note any important results or follow-up actions needed
 
-Remember: This summary will help an AI agent decide whether to investigate the full output file or proceed with the next task."#.to_string()
+Remember: This summary will help an AI agent decide whether to investigate the full output file or proceed with the next task.
+
+Respond in ${summary_language}."#.to_string()
 }
 
 fn default_summary_words() -> u32 {
@@ -104,6 +220,38 @@ fn default_output_length_threshold() -> u32 {
     default_summary_words()
 }
 
+fn default_max_prompt_chars() -> u32 {
+    1_000_000
+}
+
+fn default_temperature() -> f32 {
+    0.7
+}
+
+fn default_max_tokens() -> u32 {
+    500
+}
+
+fn default_max_concurrent_requests() -> u32 {
+    2
+}
+
+fn default_max_requests_per_minute() -> u32 {
+    0
+}
+
+fn default_rate_limit_wait_secs() -> u64 {
+    30
+}
+
+fn default_stream_fallback() -> bool {
+    true
+}
+
+fn default_retry_empty_summary() -> bool {
+    false
+}
+
 fn default_clean_up_days() -> u32 {
     5
 }
@@ -112,39 +260,446 @@ fn default_command_context_minutes() -> u32 {
     0
 }
 
+fn default_cleanup_unmarked_files() -> bool {
+    false
+}
+
+fn default_binary_detection() -> bool {
+    true
+}
+
+fn default_filename_hash() -> bool {
+    false
+}
+
+fn default_use_utc() -> bool {
+    false
+}
+
+fn default_skip_small_captures() -> bool {
+    false
+}
+
+fn default_small_capture_max_ms() -> u64 {
+    500
+}
+
+fn default_quiet_trivial_success() -> bool {
+    false
+}
+
+fn default_skip_trivial_captures() -> bool {
+    false
+}
+
+fn default_annotate_summary() -> bool {
+    false
+}
+
+fn default_show_run_id() -> bool {
+    false
+}
+
+fn default_summarize_all_failures() -> bool {
+    false
+}
+
+fn default_summarize_if_duration_exceeds_secs() -> u64 {
+    0
+}
+
+fn default_write_summary_sidecar() -> bool {
+    false
+}
+
+fn default_allowlist_only() -> bool {
+    false
+}
+
+fn default_enforce_summary_length() -> bool {
+    false
+}
+
+fn default_subdir_by_date() -> bool {
+    false
+}
+
+fn default_transcript_header() -> bool {
+    false
+}
+
+fn default_passthrough_commands() -> Vec<String> {
+    Vec::new()
+}
+
+fn default_trace_pipeline() -> bool {
+    false
+}
+
+fn default_include_cwd_in_prompt() -> bool {
+    false
+}
+
+fn default_restrict_permissions() -> bool {
+    true
+}
+
+fn default_stream_to_file() -> bool {
+    false
+}
+
+fn default_verify_summary() -> bool {
+    false
+}
+
+fn default_show_setup_hint() -> bool {
+    true
+}
+
+fn default_include_tail() -> u32 {
+    0
+}
+
+fn default_smart_extensions() -> bool {
+    false
+}
+
+fn default_retry_count() -> u32 {
+    0
+}
+
+fn default_command_timeout_secs() -> u64 {
+    0
+}
+
+fn default_timeout_grace_secs() -> u64 {
+    5
+}
+
+fn default_summary_language() -> String {
+    "English".to_string()
+}
+
+fn default_cache_ttl_minutes() -> u32 {
+    0
+}
+
+fn default_guidance_template() -> String {
+    "The complete output is available at ${output_file}, prefer reading parts of the output from the file (grep, tail, etc.) instead of the whole thing".to_string()
+}
+
+fn default_empty_success_template() -> String {
+    "Command completed successfully in ${duration} seconds with no output.".to_string()
+}
+
+fn default_empty_failure_template() -> String {
+    "Command failed after ${duration} seconds with exit code ${exit_code} and no output.".to_string()
+}
+
+/// Optional context for `Config::format_prompt`, beyond the command and its output.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PromptContext<'a> {
+    /// Recently run commands with their exit codes (`${recent_commands}`)
+    pub recent_commands: Option<&'a [(String, i32)]>,
+    /// One-off guidance from `--append-context` (`${user_context}`)
+    pub user_context: Option<&'a str>,
+    /// Directory the command ran in (`${cwd}`)
+    pub cwd: Option<&'a str>,
+    /// How each step of a compound command went, with `trace_pipeline` (`${pipeline_steps}`)
+    pub pipeline_steps: Option<&'a str>,
+}
+
+/// Wording used for command outcomes in prompts and deterministic messages.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatusLabels {
+    #[serde(default = "default_success_label")]
+    pub success: String,
+    #[serde(default = "default_failure_label")]
+    pub failure: String,
+}
+
+impl Default for StatusLabels {
+    fn default() -> Self {
+        Self {
+            success: default_success_label(),
+            failure: default_failure_label(),
+        }
+    }
+}
+
+fn default_success_label() -> String {
+    "succeeded".to_string()
+}
+
+fn default_failure_label() -> String {
+    "failed".to_string()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     #[serde(default)]
     pub provider: ProviderConfig,
+    /// Named providers (`[providers.<name>]`); unset fields inherit from `[provider]`
+    #[serde(default)]
+    pub providers: HashMap<String, toml::Table>,
     #[serde(default)]
     pub commands: HashMap<String, CommandOverride>,
     #[serde(default = "default_clean_up_days")]
     pub clean_up_days: u32,
     #[serde(default = "default_command_context_minutes")]
     pub command_context_minutes: u32,
+    /// Also clean up files without the ctx_guard metadata header (captures from older versions)
+    #[serde(default = "default_cleanup_unmarked_files")]
+    pub cleanup_unmarked_files: bool,
+    #[serde(default)]
+    pub status_labels: StatusLabels,
+    /// Skip summarization when the output looks like binary data
+    #[serde(default = "default_binary_detection")]
+    pub binary_detection: bool,
+    /// Include a short hash of the command in capture filenames
+    #[serde(default = "default_filename_hash")]
+    pub filename_hash: bool,
+    /// Use UTC instead of local time for capture filenames and metadata timestamps
+    #[serde(default = "default_use_utc")]
+    pub use_utc: bool,
+    /// Summarize failed commands even when their output is below the length threshold
+    #[serde(default = "default_summarize_all_failures")]
+    pub summarize_all_failures: bool,
+    /// Only summarize successful commands that ran longer than this many seconds (0 = always)
+    #[serde(default = "default_summarize_if_duration_exceeds_secs")]
+    pub summarize_if_duration_exceeds_secs: u64,
+    /// Don't save a capture file for quick commands whose output is short enough to print in full
+    #[serde(default = "default_skip_small_captures")]
+    pub skip_small_captures: bool,
+    /// Longest run time (in milliseconds) for a command to count as quick for `skip_small_captures`
+    #[serde(default = "default_small_capture_max_ms")]
+    pub small_capture_max_ms: u64,
+    /// Print nothing for commands that exit with 0 and produce no output
+    #[serde(default = "default_quiet_trivial_success")]
+    pub quiet_trivial_success: bool,
+    /// Don't save a capture file for commands that exit with 0 and produce no output
+    #[serde(default = "default_skip_trivial_captures")]
+    pub skip_trivial_captures: bool,
+    /// Write output to the capture file while the command runs, so killed runs leave a partial capture
+    #[serde(default = "default_stream_to_file")]
+    pub stream_to_file: bool,
+    /// Ask the model to check each summary against the output in a second request
+    #[serde(default = "default_verify_summary")]
+    pub verify_summary: bool,
+    /// Explain how to configure a provider (once) when the untouched default one is unreachable
+    #[serde(default = "default_show_setup_hint")]
+    pub show_setup_hint: bool,
+    /// Print which provider and model produced the summary, and whether it came from the cache
+    #[serde(default = "default_annotate_summary")]
+    pub annotate_summary: bool,
+    /// Print the run's ID after the summary
+    #[serde(default = "default_show_run_id")]
+    pub show_run_id: bool,
+    /// Create the output directory as 0700 and captures as 0600 (Unix)
+    #[serde(default = "default_restrict_permissions")]
+    pub restrict_permissions: bool,
+    /// Add the working directory to prompts whose template has no `${cwd}` placeholder
+    #[serde(default = "default_include_cwd_in_prompt")]
+    pub include_cwd_in_prompt: bool,
+    /// Record which step of a compound command (`a && b; c`) failed and tell the model
+    #[serde(default = "default_trace_pipeline")]
+    pub trace_pipeline: bool,
+    /// Only capture and summarize commands that have an entry under `[commands]`; run
+    /// all others as a plain passthrough
+    #[serde(default = "default_allowlist_only")]
+    pub allowlist_only: bool,
+    /// Programs (matched against the command's first word) that always run as a plain
+    /// passthrough, like editors, pagers and remote shells
+    #[serde(default = "default_passthrough_commands")]
+    pub passthrough_commands: Vec<String>,
+    /// Start the output in capture files with `$ command` and `exit: N` lines, so they
+    /// read like a terminal transcript
+    #[serde(default = "default_transcript_header")]
+    pub transcript_header: bool,
+    /// Write captures to a `YYYY-MM-DD` subdirectory of the output directory
+    #[serde(default = "default_subdir_by_date")]
+    pub subdir_by_date: bool,
+    /// Cut LLM summaries that run past `summary_words` words, ending them with "..."
+    #[serde(default = "default_enforce_summary_length")]
+    pub enforce_summary_length: bool,
+    /// Also write each summary to `<capture>.summary.txt` next to the capture
+    #[serde(default = "default_write_summary_sidecar")]
+    pub write_summary_sidecar: bool,
+    /// Number of raw output lines to print after a generated summary (0 = none)
+    #[serde(default = "default_include_tail")]
+    pub include_tail: u32,
+    /// Stream to put first in the prompt when a command fails
+    #[serde(default)]
+    pub failure_stream_priority: OutputStream,
+    /// Stream written to capture files: "combined", "stdout" or "stderr"
+    #[serde(default)]
+    pub file_streams: OutputStream,
+    /// Stream sent to the model for the summary: "combined", "stdout" or "stderr"
+    #[serde(default)]
+    pub summary_streams: OutputStream,
+    /// Save captures as .json/.diff/.log based on their content instead of always .txt
+    #[serde(default = "default_smart_extensions")]
+    pub smart_extensions: bool,
+    /// How many times to re-run a command that fails with a retryable exit code
+    #[serde(default = "default_retry_count")]
+    pub retry_count: u32,
+    /// Exit codes that trigger a retry; empty means any nonzero exit code
+    #[serde(default)]
+    pub retry_on_exit_codes: Vec<i32>,
+    /// Keep the output of every attempt instead of only the final one
+    #[serde(default)]
+    pub retry_capture_all: bool,
+    /// Stop commands that run longer than this many seconds (0 = no limit)
+    #[serde(default = "default_command_timeout_secs")]
+    pub command_timeout_secs: u64,
+    /// Signal sent to a command that runs past `command_timeout_secs`
+    #[serde(default)]
+    pub timeout_signal: TimeoutSignal,
+    /// Seconds to wait after SIGTERM before sending SIGKILL (0 = never send SIGKILL)
+    #[serde(default = "default_timeout_grace_secs")]
+    pub timeout_grace_secs: u64,
+    /// Language the summary should be written in
+    #[serde(default = "default_summary_language")]
+    pub summary_language: String,
+    /// How long a summary is reused for an identical prompt (0 disables the cache)
+    #[serde(default = "default_cache_ttl_minutes")]
+    pub cache_ttl_minutes: u32,
+    /// Interpreter that runs commands (default: `sh` on Unix, `cmd` on Windows)
+    #[serde(default)]
+    pub shell: Option<String>,
+    /// Flag that makes `shell` run a command string (default: inferred, e.g. `-c`)
+    #[serde(default)]
+    pub shell_flag: Option<String>,
+    /// Send a warmup request while the command runs so the model is loaded in time
+    #[serde(default)]
+    pub preflight_model: bool,
+    /// Collapse carriage-return redraws (progress bars) to the final rendered line
+    #[serde(default)]
+    pub collapse_progress: bool,
+    /// Part of the output sent to the LLM ("whole", "head:N", "tail:N" or "both:N")
+    #[serde(default)]
+    pub summary_window: SummaryWindow,
+    /// Line printed after the summary pointing at the capture file; empty suppresses it
+    #[serde(default = "default_guidance_template")]
+    pub guidance_template: String,
+    /// Message returned when a command succeeds without output
+    #[serde(default = "default_empty_success_template")]
+    pub empty_success_template: String,
+    /// Message returned when a command fails without output
+    #[serde(default = "default_empty_failure_template")]
+    pub empty_failure_template: String,
+    /// Warnings for settings left out of a project config (see `PROJECT_CONFIG_IGNORED_KEYS`)
+    #[serde(skip)]
+    pub ignored_project_settings: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
+#[serde(untagged, try_from = "toml::Value")]
 pub enum CommandOverride {
-    Disabled(bool),
-    SummaryWords { summary_words: u32 },
+    /// Deprecated `"command" = false` form; `false` means `summarize = false`.
+    /// Kept so older configs still parse (see `Config::load_warnings`).
+    Legacy(bool),
+    Settings(CommandSettings),
+}
+
+/// Picks the form by the value's type, so a mistake inside a settings table (such as a
+/// misspelled key) is reported as is rather than as matching neither form.
+impl TryFrom<toml::Value> for CommandOverride {
+    type Error = String;
+
+    fn try_from(value: toml::Value) -> Result<Self, Self::Error> {
+        match value {
+            toml::Value::Boolean(enabled) => Ok(Self::Legacy(enabled)),
+            toml::Value::Table(_) => value.try_into().map(Self::Settings).map_err(|error| error.message().to_string()),
+            other => Err(format!("invalid type: {}, expected a table of command settings", other.type_str())),
+        }
+    }
+}
+
+/// Per-command settings; unset fields fall back to the provider defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CommandSettings {
+    #[serde(default)]
+    pub summary_words: Option<u32>,
+    /// Name of a provider defined under `[providers.<name>]`
+    #[serde(default)]
+    pub provider: Option<String>,
+    /// Run the command but skip the LLM summary when false
+    #[serde(default)]
+    pub summarize: Option<bool>,
+    /// Refuse to run the command at all
+    #[serde(default, alias = "blocked")]
+    pub block: Option<bool>,
+    #[serde(default)]
+    pub summary_window: Option<SummaryWindow>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub max_tokens: Option<u32>,
+    /// Note added when the command fails without printing anything
+    #[serde(default)]
+    pub empty_output_note: Option<String>,
+    /// Report failing without output as an expected outcome instead of an alarm
+    #[serde(default)]
+    pub benign_empty_failure: Option<bool>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            provider: ProviderConfig {
-                r#type: default_provider_type(),
-                url: default_provider_url(),
-                model: default_model(),
-                prompt: default_prompt(),
-                summary_words: default_summary_words(),
-                output_length_threshold: default_output_length_threshold(),
-            },
+            provider: ProviderConfig::default(),
+            providers: HashMap::new(),
             commands: HashMap::new(),
             clean_up_days: default_clean_up_days(),
             command_context_minutes: default_command_context_minutes(),
+            cleanup_unmarked_files: default_cleanup_unmarked_files(),
+            status_labels: StatusLabels::default(),
+            binary_detection: default_binary_detection(),
+            filename_hash: default_filename_hash(),
+            use_utc: default_use_utc(),
+            summarize_all_failures: default_summarize_all_failures(),
+            summarize_if_duration_exceeds_secs: default_summarize_if_duration_exceeds_secs(),
+            skip_small_captures: default_skip_small_captures(),
+            small_capture_max_ms: default_small_capture_max_ms(),
+            quiet_trivial_success: default_quiet_trivial_success(),
+            skip_trivial_captures: default_skip_trivial_captures(),
+            stream_to_file: default_stream_to_file(),
+            verify_summary: default_verify_summary(),
+            show_setup_hint: default_show_setup_hint(),
+            annotate_summary: default_annotate_summary(),
+            show_run_id: default_show_run_id(),
+            write_summary_sidecar: default_write_summary_sidecar(),
+            allowlist_only: default_allowlist_only(),
+            passthrough_commands: default_passthrough_commands(),
+            transcript_header: default_transcript_header(),
+            subdir_by_date: default_subdir_by_date(),
+            enforce_summary_length: default_enforce_summary_length(),
+            include_cwd_in_prompt: default_include_cwd_in_prompt(),
+            trace_pipeline: default_trace_pipeline(),
+            restrict_permissions: default_restrict_permissions(),
+            include_tail: default_include_tail(),
+            failure_stream_priority: OutputStream::default(),
+            file_streams: OutputStream::default(),
+            summary_streams: OutputStream::default(),
+            smart_extensions: default_smart_extensions(),
+            retry_count: default_retry_count(),
+            retry_on_exit_codes: Vec::new(),
+            retry_capture_all: false,
+            command_timeout_secs: default_command_timeout_secs(),
+            timeout_signal: TimeoutSignal::default(),
+            timeout_grace_secs: default_timeout_grace_secs(),
+            summary_language: default_summary_language(),
+            cache_ttl_minutes: default_cache_ttl_minutes(),
+            shell: None,
+            shell_flag: None,
+            preflight_model: false,
+            collapse_progress: false,
+            summary_window: SummaryWindow::default(),
+            guidance_template: default_guidance_template(),
+            empty_success_template: default_empty_success_template(),
+            empty_failure_template: default_empty_failure_template(),
+            ignored_project_settings: Vec::new(),
         }
     }
 }
@@ -154,35 +709,194 @@ impl Config {
         Self::load_from_path(None)
     }
 
+    /// Overlays the `[provider]` type, URL and model given in the `CTX_GUARD_PROVIDER_TYPE`,
+    /// `CTX_GUARD_PROVIDER_URL` and `CTX_GUARD_MODEL` variables, read through `lookup`.
+    /// Empty values are ignored.
+    pub fn apply_env_overrides(&mut self, lookup: impl Fn(&str) -> Option<String>) {
+        let value = |name: &str| lookup(name).filter(|value| !value.trim().is_empty());
+        if let Some(r#type) = value(PROVIDER_TYPE_ENV_VAR) {
+            self.provider.r#type = r#type;
+        }
+        if let Some(url) = value(PROVIDER_URL_ENV_VAR) {
+            self.provider.url = url;
+        }
+        if let Some(model) = value(MODEL_ENV_VAR) {
+            self.provider.model = model;
+        }
+    }
+
+    /// Loads the config, creating the base file with the defaults if it doesn't exist, and
+    /// overlays the provider environment variables. Without an explicit path, a project
+    /// config is merged over the home config (see `config_sources`). Settings resolve as
+    /// command-line flags > environment variables > project config > home config > defaults.
     pub fn load_from_path(config_path: Option<PathBuf>) -> Result<Self, ConfigError> {
-        let config_path = config_path.unwrap_or_else(get_config_path);
-        
-        if !config_path.exists() {
-            // Create the config directory if it doesn't exist
-            if let Some(config_dir) = config_path.parent() {
-                fs::create_dir_all(config_dir)?;
-            }
-            
-            // Write the default config file
-            fs::write(&config_path, DEFAULT_CONFIG)?;
+        let sources = config_sources(config_path);
+        if let Some(base) = sources.first() {
+            write_default_config_if_missing(base)?;
         }
+        Self::load_from_paths(&sources)
+    }
 
-        let contents = fs::read_to_string(&config_path)?;
-        let config: Config = toml::from_str(&contents)?;
+    /// Loads and merges config files, each overriding the ones before it key by key (tables,
+    /// including `[commands]`, are merged recursively), then overlays the provider
+    /// environment variables. Files after the first are project configs: whoever controls
+    /// the directory wrote them, so their `PROJECT_CONFIG_IGNORED_KEYS` are left out.
+    pub fn load_from_paths(paths: &[PathBuf]) -> Result<Self, ConfigError> {
+        let mut merged = toml::Table::new();
+        let mut ignored = Vec::new();
+        for (i, path) in paths.iter().enumerate() {
+            let mut table = read_config_table(path)?;
+            if i > 0 {
+                ignored.extend(remove_project_ignored_keys(&mut table).into_iter().map(|key| {
+                    format!(
+                        "`{}` in the project config {} is ignored: a project can't change the shell, permissions or where prompts are sent. Set it in {} or pass the file with --config",
+                        key,
+                        path.display(),
+                        home_config_path().display()
+                    )
+                }));
+            }
+            merge_tables(&mut merged, table);
+        }
+        // Each file parsed on its own above, so this only fails if the merge itself doesn't
+        let mut config: Config = merged.try_into().map_err(|source| ConfigError::ParseError {
+            path: paths.last().cloned().unwrap_or_default(),
+            source,
+        })?;
+        config.ignored_project_settings = ignored;
+        config.apply_env_overrides(|name| std::env::var(name).ok());
+        config.provider.url = normalize_provider_url(&config.provider.url)?;
+        for name in config.providers.keys() {
+            config.resolve_provider(Some(name))?;
+        }
         Ok(config)
     }
 
     pub fn get_summary_words(&self, command: &str) -> u32 {
         if let Some(override_config) = self.commands.get(command) {
             match override_config {
-                CommandOverride::Disabled(_) => self.provider.summary_words,
-                CommandOverride::SummaryWords { summary_words } => *summary_words,
+                CommandOverride::Legacy(_) => self.provider.summary_words,
+                CommandOverride::Settings(settings) => settings.summary_words.unwrap_or(self.provider.summary_words),
             }
         } else {
             self.provider.summary_words
         }
     }
 
+    /// The settings that decide how captured output is processed.
+    pub fn process_options(&self) -> ProcessOptions {
+        ProcessOptions {
+            collapse_progress: self.collapse_progress,
+            failure_stream_priority: self.failure_stream_priority,
+            summary_streams: self.summary_streams,
+        }
+    }
+
+    /// The settings that decide how captures are written.
+    pub fn capture_options(&self) -> CaptureOptions {
+        CaptureOptions {
+            restrict_permissions: self.restrict_permissions,
+            transcript_header: self.transcript_header,
+            subdir_by_date: self.subdir_by_date,
+        }
+    }
+
+    /// Returns the shell to run commands with. `program` (e.g. from `--shell`) takes
+    /// precedence over the configured shell; the configured flag only applies to the
+    /// configured shell.
+    pub fn shell(&self, program: Option<&str>) -> Shell {
+        match (program, self.shell.as_deref()) {
+            (Some(program), _) => Shell::new(program, None),
+            (None, Some(configured)) => Shell::new(configured, self.shell_flag.as_deref()),
+            (None, None) => Shell::default(),
+        }
+    }
+
+    /// Returns the timeout for commands, if `command_timeout_secs` sets one.
+    pub fn command_timeout(&self) -> Option<CommandTimeout> {
+        (self.command_timeout_secs > 0).then(|| CommandTimeout {
+            limit: std::time::Duration::from_secs(self.command_timeout_secs),
+            signal: self.timeout_signal,
+            grace: std::time::Duration::from_secs(self.timeout_grace_secs),
+        })
+    }
+
+    /// Returns true if `summarize_if_duration_exceeds_secs` is set and a run that took
+    /// `duration` stayed within it, so its output (if it succeeded) is returned as is.
+    pub fn finished_quickly(&self, duration: std::time::Duration) -> bool {
+        self.summarize_if_duration_exceeds_secs > 0
+            && duration <= std::time::Duration::from_secs(self.summarize_if_duration_exceeds_secs)
+    }
+
+    /// Returns the sampling temperature for this command, falling back to the provider's.
+    pub fn get_temperature(&self, command: &str) -> f32 {
+        match self.commands.get(command) {
+            Some(CommandOverride::Settings(settings)) => settings.temperature.unwrap_or(self.provider.temperature),
+            _ => self.provider.temperature,
+        }
+    }
+
+    /// Returns the generation token limit for this command, falling back to the provider's.
+    pub fn get_max_tokens(&self, command: &str) -> u32 {
+        match self.commands.get(command) {
+            Some(CommandOverride::Settings(settings)) => settings.max_tokens.unwrap_or(self.provider.max_tokens),
+            _ => self.provider.max_tokens,
+        }
+    }
+
+    /// Returns the part of the output to summarize for this command.
+    pub fn get_summary_window(&self, command: &str) -> SummaryWindow {
+        match self.commands.get(command) {
+            Some(CommandOverride::Settings(settings)) => settings.summary_window.unwrap_or(self.summary_window),
+            _ => self.summary_window,
+        }
+    }
+
+    /// Returns the settings for a command that fails without output: the configured
+    /// note, if any, and whether that outcome is benign for it.
+    pub fn get_empty_failure_settings(&self, command: &str) -> (Option<&str>, bool) {
+        match self.commands.get(command) {
+            Some(CommandOverride::Settings(settings)) => (
+                settings.empty_output_note.as_deref(),
+                settings.benign_empty_failure.unwrap_or(false),
+            ),
+            _ => (None, false),
+        }
+    }
+
+    /// Returns the name of the provider configured for this command, if any.
+    pub fn get_command_provider(&self, command: &str) -> Option<&str> {
+        match self.commands.get(command) {
+            Some(CommandOverride::Settings(settings)) => settings.provider.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Resolves the provider to use: `None` is the default `[provider]`, a name selects
+    /// `[providers.<name>]` with any unset fields inherited from `[provider]`.
+    pub fn resolve_provider(&self, name: Option<&str>) -> Result<ProviderConfig, ConfigError> {
+        let name = match name {
+            Some(name) => name,
+            None => return Ok(self.provider.clone()),
+        };
+        let overrides = self
+            .providers
+            .get(name)
+            .ok_or_else(|| ConfigError::UnknownProvider(name.to_string()))?;
+        let invalid = |reason: String| ConfigError::InvalidProvider {
+            name: name.to_string(),
+            reason,
+        };
+
+        let mut merged = toml::Table::try_from(&self.provider).map_err(|e| invalid(e.to_string()))?;
+        for (key, value) in overrides {
+            merged.insert(key.clone(), value.clone());
+        }
+        let mut provider: ProviderConfig = merged.try_into().map_err(|e: toml::de::Error| invalid(e.to_string()))?;
+        provider.url = normalize_provider_url(&provider.url)?;
+        Ok(provider)
+    }
+
     /// Returns the minimum output length (in words) required before we attempt
     /// to generate a summary. This is always at least the configured summary length
     /// to avoid summarizing outputs that are already shorter than the summary.
@@ -193,25 +907,162 @@ impl Config {
             .max(summary_words)
     }
 
-    pub fn is_command_disabled(&self, command: &str) -> bool {
-        if let Some(override_config) = self.commands.get(command) {
-            // In TOML, `command = false` means "disabled" (don't generate summary)
-            matches!(override_config, CommandOverride::Disabled(false))
+    /// Returns true if summarization is disabled for this command. The command still
+    /// runs and its output is captured, but no LLM call is made.
+    pub fn is_summary_disabled(&self, command: &str) -> bool {
+        match self.commands.get(command) {
+            Some(CommandOverride::Legacy(enabled)) => !enabled,
+            Some(CommandOverride::Settings(settings)) => settings.summarize == Some(false),
+            None => false,
+        }
+    }
+
+    /// Returns true if `allowlist_only` is set and no `[commands]` key matches the command,
+    /// so it must run as a passthrough without being captured or summarized. Keys may use
+    /// `*` and `?` wildcards here (`cargo test*` allows `cargo test --lib`).
+    pub fn is_outside_allowlist(&self, command: &str) -> bool {
+        self.allowlist_only && !self.commands.keys().any(|key| glob_matches(key, command))
+    }
+
+    /// Returns true if the command's program is in `passthrough_commands`. The program is
+    /// the first word, compared by file name, so `/usr/bin/vim notes.txt` matches `vim`.
+    pub fn is_passthrough_command(&self, command: &str) -> bool {
+        let Some(program) = command.split_whitespace().next() else {
+            return false;
+        };
+        let name = Path::new(program).file_name().and_then(|name| name.to_str()).unwrap_or(program);
+        self.passthrough_commands.iter().any(|listed| listed == name || listed == program)
+    }
+
+    /// Returns true if the command must not be run at all (`"command".block = true`).
+    pub fn is_command_blocked(&self, command: &str) -> bool {
+        matches!(
+            self.commands.get(command),
+            Some(CommandOverride::Settings(CommandSettings { block: Some(true), .. }))
+        )
+    }
+
+    /// Returns a warning for each override using the deprecated `"command" = false` form,
+    /// followed by one for each setting ignored in a project config.
+    pub fn load_warnings(&self) -> Vec<String> {
+        let mut warnings: Vec<String> = self
+            .commands
+            .iter()
+            .filter_map(|(command, override_config)| match override_config {
+                CommandOverride::Legacy(enabled) => Some(format!(
+                    "`\"{}\" = {}` in [commands] is deprecated; use `\"{}\".summarize = {}` (or `.block = true` to refuse running it)",
+                    command, enabled, command, enabled
+                )),
+                CommandOverride::Settings(_) => None,
+            })
+            .collect();
+        warnings.sort();
+        warnings.extend(self.ignored_project_settings.iter().cloned());
+        warnings
+    }
+
+    /// Returns a suggestion for each override key that never matched one of `ran_commands`
+    /// but is a few edits away from one of them, e.g. `cargo test` when `cargo test --` is
+    /// what actually runs. Overrides still only apply on an exact match.
+    pub fn override_key_suggestions(&self, ran_commands: &[String]) -> Vec<String> {
+        let mut suggestions: Vec<String> = self
+            .commands
+            .keys()
+            // Wildcard keys (for allowlist_only) aren't meant to match exactly
+            .filter(|key| !ran_commands.contains(key) && !key.contains(['*', '?']))
+            .filter_map(|key| {
+                let max_distance = (key.chars().count() / 3).max(1);
+                ran_commands
+                    .iter()
+                    .map(|command| (edit_distance(key, command), command))
+                    .filter(|(distance, _)| *distance <= max_distance)
+                    .min()
+                    .map(|(_, command)| format!("the override \"{}\" never matched a recent command; did you mean \"{}\"?", key, command))
+            })
+            .collect();
+        suggestions.sort();
+        suggestions
+    }
+
+    /// True if `[provider]` still has the type, URL and model of the default config, i.e.
+    /// no provider has been set up yet.
+    pub fn is_default_provider(&self) -> bool {
+        self.provider.r#type == default_provider_type()
+            && self.provider.url == default_provider_url()
+            && self.provider.model == default_model()
+    }
+
+    /// Returns the configured wording for a command outcome based on its exit code.
+    pub fn status_label(&self, exit_code: i32) -> &str {
+        if exit_code == 0 {
+            &self.status_labels.success
         } else {
-            false
+            &self.status_labels.failure
+        }
+    }
+
+    /// Renders the guidance line for a capture file, or `None` if it has been
+    /// configured away.
+    pub fn format_guidance(&self, output_file: &std::path::Path) -> Option<String> {
+        if self.guidance_template.trim().is_empty() {
+            return None;
         }
+        Some(self.guidance_template.replace("${output_file}", &output_file.display().to_string()))
+    }
+
+    /// Renders the message for a command that produced no output, filling
+    /// `${duration}` (seconds, one decimal) and `${exit_code}`.
+    pub fn format_empty_output(&self, exit_code: i32, duration: std::time::Duration) -> String {
+        let template = if exit_code == 0 {
+            &self.empty_success_template
+        } else {
+            &self.empty_failure_template
+        };
+        template
+            .replace("${duration}", &format!("{:.1}", duration.as_secs_f64()))
+            .replace("${exit_code}", &exit_code.to_string())
+    }
+
+    /// Builds the prompt for `cg summarize-session` from each run's command, exit code and
+    /// summary, oldest first.
+    pub fn format_session_prompt(&self, session: &str, runs: &[(String, i32, String)]) -> String {
+        let runs_text = runs
+            .iter()
+            .map(|(command, exit_code, summary)| {
+                format!("Command: {}\nExit code: {} ({})\nSummary: {}", command, exit_code, self.status_label(*exit_code), summary.trim())
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        SESSION_PROMPT
+            .replace("${session}", session)
+            .replace("${runs}", &runs_text)
+            .replace("${summary_words}", &self.provider.summary_words.to_string())
+            .replace("${summary_language}", &self.summary_language)
+    }
+
+    /// Builds the prompt for `cg diff` from the (command, exit code) of both runs and the
+    /// line diff between their outputs.
+    pub fn format_diff_prompt(&self, old: (&str, i32), new: (&str, i32), diff: &str) -> String {
+        DIFF_PROMPT
+            .replace("${old_command}", old.0)
+            .replace("${old_exit_code}", &old.1.to_string())
+            .replace("${old_exit_status_text}", self.status_label(old.1))
+            .replace("${new_command}", new.0)
+            .replace("${new_exit_code}", &new.1.to_string())
+            .replace("${new_exit_status_text}", self.status_label(new.1))
+            .replace("${summary_words}", &self.provider.summary_words.to_string())
+            .replace("${summary_language}", &self.summary_language)
+            .replace("${diff}", diff)
     }
 
-    pub fn format_prompt(&self, command: &str, exit_code: i32, output: &str, summary_words: u32, recent_commands: Option<&[(String, i32)]>) -> String {
+    pub fn format_prompt(&self, command: &str, exit_code: i32, output: &str, summary_words: u32, context: &PromptContext) -> String {
+        let PromptContext { recent_commands, user_context, cwd, pipeline_steps } = *context;
         let recent_commands_text = if let Some(commands) = recent_commands {
             if commands.is_empty() {
                 String::new()
             } else {
                 let commands_list: Vec<String> = commands.iter()
-                    .map(|(cmd, code)| {
-                        let status = if *code == 0 { "succeeded" } else { "failed" };
-                        format!("- {}, {}", cmd, status)
-                    })
+                    .map(|(cmd, code)| format!("- {}, {}", cmd, self.status_label(*code)))
                     .collect();
                 format!("recently run commands:\n{}\n\n", commands_list.join("\n"))
             }
@@ -219,28 +1070,256 @@ impl Config {
             String::new()
         };
 
-        self.provider.prompt
+        // Derived from the same window, so it is 0 when command context is disabled
+        let recent_failure_count = recent_commands
+            .map_or(0, |commands| commands.iter().filter(|(_, code)| *code != 0).count());
+
+        let user_context_text = match user_context.map(str::trim) {
+            Some(text) if !text.is_empty() => format!("Additional context from the user:\n{}", text),
+            _ => String::new(),
+        };
+
+        // Templates written before `${user_context}` existed still get the context,
+        // appended after the rest of the prompt.
+        let mut template = self.provider.prompt.clone();
+        if !user_context_text.is_empty() && !template.contains("${user_context}") {
+            template = format!("{}\n\n${{user_context}}", template.trim_end());
+        }
+        // The working directory is only added on request, to keep prompts short
+        if self.include_cwd_in_prompt && cwd.is_some() && !template.contains("${cwd}") {
+            template = format!("{}\n\nWorking directory: ${{cwd}}", template.trim_end());
+        }
+        // Step results only exist for traced compound commands
+        if pipeline_steps.is_some() && !template.contains("${pipeline_steps}") {
+            template = format!("{}\n\n${{pipeline_steps}}", template.trim_end());
+        }
+        // Same for the language instruction, which only matters for non-English summaries
+        let language = self.summary_language.trim();
+        if !language.eq_ignore_ascii_case("english") && !template.contains("${summary_language}") {
+            template = format!("{}\n\nRespond in ${{summary_language}}.", template.trim_end());
+        }
+
+        template
             .replace("${recent_commands}", &recent_commands_text)
+            .replace("${recent_failure_count}", &recent_failure_count.to_string())
+            .replace("${user_context}", &user_context_text)
             .replace("${command}", command)
             .replace("${exit_code}", &exit_code.to_string())
+            .replace("${exit_status_text}", self.status_label(exit_code))
+            .replace("${cwd}", cwd.unwrap_or_default())
+            .replace("${pipeline_steps}", pipeline_steps.unwrap_or_default())
             .replace("${output}", output)
             .replace("${summary_words}", &summary_words.to_string())
+            .replace("${summary_language}", language)
     }
 }
 
-fn get_config_path() -> PathBuf {
-    if let Some(config_dir) = dirs::home_dir() {
-        config_dir.join(".ctx_guard").join("config.toml")
-    } else {
-        PathBuf::from("config.toml")
+/// Reads a prompt template from a file, for `--prompt-file`. The template must contain
+/// `${output}`, or the model would never see the command output.
+pub fn load_prompt_file(path: &Path) -> Result<String, ConfigError> {
+    let invalid = |reason: String| ConfigError::InvalidPromptFile { path: path.to_path_buf(), reason };
+    let prompt = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+    if !prompt.contains("${output}") {
+        return Err(invalid("it must contain ${output}, where the command output goes".to_string()));
     }
+    Ok(prompt)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Levenshtein distance between two strings, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
 
-    #[test]
+/// True if `text` matches `pattern`, where `*` stands for any run of characters and `?`
+/// for exactly one; everything else must match literally.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was, and the text position it currently stands in for
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            // Let the last `*` take one more character and retry from there
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Validates a provider URL, defaulting the scheme to `http://` for bare `host:port`
+/// values so misconfigurations fail at load time instead of at request time.
+pub fn normalize_provider_url(url: &str) -> Result<String, ConfigError> {
+    let invalid = |reason: &str| ConfigError::InvalidUrl {
+        url: url.to_string(),
+        reason: reason.to_string(),
+    };
+
+    let trimmed = url.trim();
+    if trimmed.is_empty() {
+        return Err(invalid("URL is empty"));
+    }
+
+    let normalized = if trimmed.contains("://") {
+        trimmed.to_string()
+    } else {
+        format!("http://{}", trimmed)
+    };
+
+    let parsed = reqwest::Url::parse(&normalized).map_err(|e| invalid(&e.to_string()))?;
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return Err(invalid("scheme must be http or https"));
+    }
+    if parsed.host_str().is_none_or(str::is_empty) {
+        return Err(invalid("URL has no host"));
+    }
+
+    Ok(normalized)
+}
+
+/// File recording that the first-run setup hint was shown, next to the default config.
+pub fn setup_hint_marker_path() -> PathBuf {
+    home_config_path().with_file_name(".setup_hint_shown")
+}
+
+/// Config file used when no project has its own: `~/.ctx_guard/config.toml`.
+pub fn home_config_path() -> PathBuf {
+    if let Some(config_dir) = dirs::home_dir() {
+        config_dir.join(".ctx_guard").join("config.toml")
+    } else {
+        PathBuf::from("config.toml")
+    }
+}
+
+/// Finds the nearest `.ctx_guard/config.toml` in `start` or one of its parent directories,
+/// the way git finds its config.
+pub fn find_project_config(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(".ctx_guard").join("config.toml"))
+        .find(|path| path.is_file())
+}
+
+/// Config files to load, lowest precedence first: the explicit path alone, or the home
+/// config followed by the nearest project config, if there is one.
+pub fn config_sources(config_path: Option<PathBuf>) -> Vec<PathBuf> {
+    if let Some(path) = config_path {
+        return vec![path];
+    }
+    let home = home_config_path();
+    let project = std::env::current_dir()
+        .ok()
+        .and_then(|dir| find_project_config(&dir))
+        // Below the home directory, the search ends at the home config itself
+        .filter(|project| fs::canonicalize(project).ok() != fs::canonicalize(&home).ok());
+    std::iter::once(home).chain(project).collect()
+}
+
+/// Writes the default config to `path`, creating its directory, unless the file exists.
+fn write_default_config_if_missing(path: &Path) -> Result<(), ConfigError> {
+    if path.exists() {
+        return Ok(());
+    }
+    let write_error = |source| ConfigError::WriteError { path: path.to_path_buf(), source };
+    if let Some(config_dir) = path.parent() {
+        fs::create_dir_all(config_dir).map_err(write_error)?;
+    }
+    fs::write(path, DEFAULT_CONFIG).map_err(write_error)
+}
+
+/// Reads one config file as a table, after checking it is a valid config on its own.
+fn read_config_table(path: &Path) -> Result<toml::Table, ConfigError> {
+    let contents = fs::read_to_string(path).map_err(|source| ConfigError::ReadError { path: path.to_path_buf(), source })?;
+    let contents = strip_leading_noise(&contents);
+    let parse_error = |source| ConfigError::ParseError { path: path.to_path_buf(), source };
+    // Parsing as a Config reports wrong types with their line, column and snippet
+    toml::from_str::<Config>(&contents).map_err(parse_error)?;
+    toml::from_str(&contents).map_err(parse_error)
+}
+
+/// Strips a UTF-8 byte order mark (added by some Windows editors) and any other
+/// whitespace before the first setting, such as a non-breaking space pasted from a web
+/// page, which TOML rejects. Skipped line breaks are put back, so errors still report
+/// the line numbers of the file.
+fn strip_leading_noise(contents: &str) -> String {
+    let body = contents.trim_start_matches(|c: char| c == '\u{feff}' || c.is_whitespace());
+    let skipped_lines = contents[..contents.len() - body.len()].matches('\n').count();
+    format!("{}{}", "\n".repeat(skipped_lines), body)
+}
+
+/// Settings a project config can't change, as `key` or `table.key`: a repository could
+/// otherwise run commands through a shell of its choosing, send prompts (with their
+/// command output) to its own server, or make captures readable by everyone.
+const PROJECT_CONFIG_IGNORED_KEYS: &[&str] = &[
+    "shell",
+    "shell_flag",
+    "restrict_permissions",
+    "providers",
+    "provider.type",
+    "provider.url",
+    "provider.chat_path",
+    "provider.extra_params",
+];
+
+/// Removes `PROJECT_CONFIG_IGNORED_KEYS` from a project config table and returns the ones
+/// it had.
+fn remove_project_ignored_keys(table: &mut toml::Table) -> Vec<String> {
+    PROJECT_CONFIG_IGNORED_KEYS
+        .iter()
+        .filter(|key| match key.split_once('.') {
+            Some((parent, child)) => match table.get_mut(parent) {
+                Some(toml::Value::Table(parent)) => parent.remove(child).is_some(),
+                _ => false,
+            },
+            None => table.remove(**key).is_some(),
+        })
+        .map(|key| key.to_string())
+        .collect()
+}
+
+/// Merges `overlay` into `base`: tables present in both are merged recursively, any other
+/// value in `overlay` replaces the one in `base`.
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(overrides)) => merge_tables(existing, overrides),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
     fn test_provider_config_default() {
         let config = ProviderConfig::default();
         assert_eq!(config.r#type, "lmstudio");
@@ -252,6 +1331,7 @@ mod tests {
         assert!(config.prompt.contains("${summary_words}"));
         assert_eq!(config.summary_words, 100);
         assert_eq!(config.output_length_threshold, 100);
+        assert_eq!(config.max_prompt_chars, 1_000_000);
     }
 
     #[test]
@@ -263,12 +1343,16 @@ mod tests {
         assert_eq!(config.provider.output_length_threshold, 100);
         assert!(config.commands.is_empty());
         assert_eq!(config.clean_up_days, 5);
+        assert!(!config.cleanup_unmarked_files);
+        assert!(config.binary_detection);
+        assert_eq!(config.include_tail, 0);
+        assert_eq!(config.cache_ttl_minutes, 0);
     }
 
     #[test]
     fn test_format_prompt() {
         let config = Config::default();
-        let prompt = config.format_prompt("echo hello", 0, "hello", 50, None);
+        let prompt = config.format_prompt("echo hello", 0, "hello", 50, &PromptContext::default());
         
         assert!(prompt.contains("echo hello"));
         assert!(prompt.contains("0"));
@@ -288,7 +1372,7 @@ mod tests {
             ("ls".to_string(), 0),
             ("npx jest".to_string(), 1),
         ];
-        let prompt = config.format_prompt("npm run build", 0, "output", 50, Some(&recent));
+        let prompt = config.format_prompt("npm run build", 0, "output", 50, &PromptContext { recent_commands: Some(&recent), ..Default::default() });
         
         assert!(prompt.contains("recently run commands"));
         assert!(prompt.contains("cd workspace"));
@@ -298,6 +1382,314 @@ mod tests {
         assert!(prompt.contains("npm run build"));
     }
 
+    #[test]
+    fn test_format_prompt_recent_failure_count() {
+        let mut config = Config::default();
+        config.provider.prompt = "${recent_failure_count} recent failures".to_string();
+        let recent = vec![
+            ("cargo build".to_string(), 101),
+            ("ls".to_string(), 0),
+            ("npx jest".to_string(), 1),
+        ];
+
+        let prompt = config.format_prompt("npm test", 1, "output", 50, &PromptContext { recent_commands: Some(&recent), ..Default::default() });
+        assert_eq!(prompt, "2 recent failures");
+
+        let prompt = config.format_prompt("npm test", 1, "output", 50, &PromptContext::default());
+        assert_eq!(prompt, "0 recent failures");
+    }
+
+    #[test]
+    fn test_format_guidance() {
+        let mut config = Config::default();
+        let path = std::path::Path::new("/tmp/ctx_guard/ls_20250101_120000.txt");
+        let guidance = config.format_guidance(path).unwrap();
+        assert!(guidance.starts_with("The complete output is available at /tmp/ctx_guard/ls_20250101_120000.txt,"));
+
+        config.guidance_template = "Full log: ${output_file} (use rg)".to_string();
+        assert_eq!(config.format_guidance(path).unwrap(), "Full log: /tmp/ctx_guard/ls_20250101_120000.txt (use rg)");
+
+        config.guidance_template = "".to_string();
+        assert!(config.format_guidance(path).is_none());
+    }
+
+    #[test]
+    fn test_format_empty_output() {
+        let mut config = Config::default();
+        let duration = std::time::Duration::from_millis(1250);
+        assert_eq!(config.format_empty_output(0, duration), "Command completed successfully in 1.2 seconds with no output.");
+        assert_eq!(config.format_empty_output(2, duration), "Command failed after 1.2 seconds with exit code 2 and no output.");
+
+        config.empty_failure_template = "Fehlgeschlagen (Code ${exit_code}, ${duration} s), keine Ausgabe.".to_string();
+        assert_eq!(config.format_empty_output(2, duration), "Fehlgeschlagen (Code 2, 1.2 s), keine Ausgabe.");
+    }
+
+    #[test]
+    fn test_format_session_prompt() {
+        let config = Config::default();
+        let runs = vec![
+            ("cargo build".to_string(), 0, "Build finished.".to_string()),
+            ("cargo test".to_string(), 101, "2 tests failed in parser.rs.\n".to_string()),
+        ];
+        let prompt = config.format_session_prompt("release", &runs);
+
+        assert!(prompt.contains("Session: release"));
+        assert!(prompt.contains("Command: cargo build\nExit code: 0 (succeeded)\nSummary: Build finished.\n\nCommand: cargo test"));
+        assert!(prompt.contains("Exit code: 101 (failed)\nSummary: 2 tests failed in parser.rs."));
+        assert!(prompt.contains("100 words or less"));
+        assert!(!prompt.contains("${"));
+    }
+
+    #[test]
+    fn test_merge_tables() {
+        let mut base: toml::Table = toml::from_str(
+            r#"
+            clean_up_days = 5
+            [provider]
+            url = "http://127.0.0.1:1234"
+            model = "home-model"
+            [commands]
+            "cargo test".summary_words = 50
+            "npm test".summarize = false
+            "#,
+        )
+        .unwrap();
+        let overlay: toml::Table = toml::from_str(
+            r#"
+            [provider]
+            model = "project-model"
+            [commands]
+            "cargo test".max_tokens = 1000
+            "make".block = true
+            "#,
+        )
+        .unwrap();
+        merge_tables(&mut base, overlay);
+        let config: Config = base.try_into().unwrap();
+
+        // Only the keys set in the overlay change
+        assert_eq!(config.clean_up_days, 5);
+        assert_eq!(config.provider.url, "http://127.0.0.1:1234");
+        assert_eq!(config.provider.model, "project-model");
+        // Commands from both files are kept, and entries in both are merged field by field
+        assert_eq!(config.get_summary_words("cargo test"), 50);
+        assert_eq!(config.get_max_tokens("cargo test"), 1000);
+        assert!(config.is_summary_disabled("npm test"));
+        assert!(config.is_command_blocked("make"));
+    }
+
+    #[test]
+    fn test_load_from_paths_layers_files() {
+        let dir = std::env::temp_dir().join(format!("ctx_guard_layered_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let home = dir.join("home.toml");
+        let project = dir.join("project.toml");
+        fs::write(&home, "summary_language = \"German\"\n[provider]\nsummary_words = 60\nprompt = \"home ${output}\"\n").unwrap();
+        fs::write(&project, "[provider]\nprompt = \"project ${output}\"\n").unwrap();
+
+        let config = Config::load_from_paths(&[home.clone(), project.clone()]).unwrap();
+        assert_eq!(config.summary_language, "German");
+        assert_eq!(config.provider.summary_words, 60);
+        assert_eq!(config.provider.prompt, "project ${output}");
+        assert!(config.load_warnings().is_empty());
+
+        // Errors point at the file that has them
+        fs::write(&project, "clean_up_days = \"soon\"\n").unwrap();
+        match Config::load_from_paths(&[home, project.clone()]) {
+            Err(ConfigError::ParseError { path, .. }) => assert_eq!(path, project),
+            other => panic!("expected a parse error, got {:?}", other.map(|_| ())),
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_project_config_cannot_change_shell_or_provider_url() {
+        let dir = std::env::temp_dir().join(format!("ctx_guard_untrusted_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let home = dir.join("home.toml");
+        let project = dir.join("project.toml");
+        fs::write(&home, "[provider]\nurl = \"http://127.0.0.1:1234\"\n").unwrap();
+        fs::write(
+            &project,
+            "shell = \"/tmp/evil\"\nshell_flag = \"-c\"\nsummary_language = \"German\"\n[provider]\nurl = \"https://collector.example.com\"\nmodel = \"project-model\"\nextra_params = { seed = 1 }\n[providers.fast]\nurl = \"https://collector.example.com\"\n",
+        )
+        .unwrap();
+
+        let config = Config::load_from_paths(&[home.clone(), project.clone()]).unwrap();
+        assert_eq!(config.shell, None);
+        assert_eq!(config.shell_flag, None);
+        assert_eq!(config.provider.url, "http://127.0.0.1:1234");
+        assert!(config.provider.extra_params.is_empty());
+        assert!(config.providers.is_empty());
+        // Everything else still applies
+        assert_eq!(config.summary_language, "German");
+        assert_eq!(config.provider.model, "project-model");
+        let warnings = config.load_warnings();
+        assert_eq!(warnings.len(), 5);
+        assert!(warnings[0].starts_with("`shell` in the project config"));
+
+        // The same file passed on its own is trusted
+        let config = Config::load_from_paths(&[project]).unwrap();
+        assert_eq!(config.shell.as_deref(), Some("/tmp/evil"));
+        assert_eq!(config.provider.url, "https://collector.example.com");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_find_project_config() {
+        let project = std::env::temp_dir().join(format!("ctx_guard_project_{}", std::process::id()));
+        let nested = project.join("src").join("module");
+        fs::create_dir_all(&nested).unwrap();
+        assert_ne!(find_project_config(&nested), Some(project.join(".ctx_guard").join("config.toml")));
+
+        fs::create_dir_all(project.join(".ctx_guard")).unwrap();
+        fs::write(project.join(".ctx_guard").join("config.toml"), "").unwrap();
+        assert_eq!(find_project_config(&nested), Some(project.join(".ctx_guard").join("config.toml")));
+        assert_eq!(find_project_config(&project), Some(project.join(".ctx_guard").join("config.toml")));
+
+        // The nearest one wins
+        fs::create_dir_all(nested.join(".ctx_guard")).unwrap();
+        fs::write(nested.join(".ctx_guard").join("config.toml"), "").unwrap();
+        assert_eq!(find_project_config(&nested), Some(nested.join(".ctx_guard").join("config.toml")));
+
+        let _ = fs::remove_dir_all(&project);
+    }
+
+    #[test]
+    fn test_format_prompt_with_explain_prompt() {
+        let mut config = Config::default();
+        config.provider.prompt = EXPLAIN_PROMPT.to_string();
+        let prompt = config.format_prompt("cargo build", 101, "error[E0425]: cannot find value `x`", 80, &PromptContext::default());
+
+        assert!(prompt.contains("Command executed: cargo build\nExit code: 101 (failed)"));
+        assert!(prompt.contains("error[E0425]"));
+        assert!(prompt.contains("80 words or less"));
+        assert!(!prompt.contains("${"));
+    }
+
+    #[test]
+    fn test_format_diff_prompt() {
+        let config = Config::default();
+        let prompt = config.format_diff_prompt(("cargo test", 101), ("cargo test", 0), "- test b ... FAILED\n+ test b ... ok");
+
+        assert!(prompt.contains("Earlier run: cargo test\nExit code: 101 (failed)"));
+        assert!(prompt.contains("Later run: cargo test\nExit code: 0 (succeeded)"));
+        assert!(prompt.contains("- test b ... FAILED\n+ test b ... ok"));
+        assert!(!prompt.contains("${"));
+    }
+
+    #[test]
+    fn test_format_prompt_with_cwd() {
+        let mut config = Config::default();
+        config.provider.prompt = "${command} failed".to_string();
+
+        // Off by default
+        let prompt = config.format_prompt("make", 2, "output", 50, &PromptContext { cwd: Some("/src/app"), ..Default::default() });
+        assert_eq!(prompt, "make failed");
+
+        config.include_cwd_in_prompt = true;
+        let prompt = config.format_prompt("make", 2, "output", 50, &PromptContext { cwd: Some("/src/app"), ..Default::default() });
+        assert_eq!(prompt, "make failed\n\nWorking directory: /src/app");
+
+        // A template with the placeholder gets the bare path, with or without the toggle
+        config.include_cwd_in_prompt = false;
+        config.provider.prompt = "${command} failed in ${cwd}".to_string();
+        let prompt = config.format_prompt("make", 2, "output", 50, &PromptContext { cwd: Some("/src/app"), ..Default::default() });
+        assert_eq!(prompt, "make failed in /src/app");
+    }
+
+    #[test]
+    fn test_load_prompt_file() {
+        let dir = std::env::temp_dir().join(format!("ctx_guard_test_prompt_file_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let valid = dir.join("valid.txt");
+        fs::write(&valid, "List the errors in ${command}:\n${output}\n").unwrap();
+        assert_eq!(load_prompt_file(&valid).unwrap(), "List the errors in ${command}:\n${output}\n");
+
+        let no_output = dir.join("no_output.txt");
+        fs::write(&no_output, "Summarize ${command}").unwrap();
+        let error = load_prompt_file(&no_output).unwrap_err().to_string();
+        assert!(error.contains("must contain ${output}"), "{}", error);
+
+        assert!(matches!(load_prompt_file(&dir.join("missing.txt")), Err(ConfigError::InvalidPromptFile { .. })));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_format_prompt_with_pipeline_steps() {
+        let mut config = Config::default();
+        config.provider.prompt = "${command} failed".to_string();
+        let steps = "The command ran as 2 steps:\n1. make: failed (exit code 2)\n2. make install: not run";
+
+        let prompt = config.format_prompt("make && make install", 2, "output", 50, &PromptContext::default());
+        assert_eq!(prompt, "make && make install failed");
+
+        let prompt = config.format_prompt("make && make install", 2, "output", 50, &PromptContext { pipeline_steps: Some(steps), ..Default::default() });
+        assert_eq!(prompt, format!("make && make install failed\n\n{}", steps));
+    }
+
+    #[test]
+    fn test_format_prompt_with_user_context() {
+        let config = Config::default();
+        let prompt = config.format_prompt("cargo test", 101, "output", 50, &PromptContext { user_context: Some("focus on the panic"), ..Default::default() });
+
+        assert!(prompt.contains("Additional context from the user:\nfocus on the panic"));
+        assert!(!prompt.contains("${user_context}"));
+
+        let prompt = config.format_prompt("cargo test", 101, "output", 50, &PromptContext::default());
+        assert!(!prompt.contains("Additional context from the user"));
+        assert!(!prompt.contains("${user_context}"));
+    }
+
+    #[test]
+    fn test_format_prompt_appends_user_context_without_placeholder() {
+        let mut config = Config::default();
+        config.provider.prompt = "Summarize ${command}: ${output}".to_string();
+        let prompt = config.format_prompt("ls", 0, "files", 50, &PromptContext { user_context: Some("ignore warnings"), ..Default::default() });
+
+        assert!(prompt.starts_with("Summarize ls: files"));
+        assert!(prompt.ends_with("Additional context from the user:\nignore warnings"));
+    }
+
+    #[test]
+    fn test_format_prompt_with_custom_status_labels() {
+        let mut config = Config::default();
+        config.status_labels.success = "lyckades".to_string();
+        config.status_labels.failure = "misslyckades".to_string();
+        let recent = vec![("cargo build".to_string(), 0)];
+        let prompt = config.format_prompt("cargo test", 101, "output", 50, &PromptContext { recent_commands: Some(&recent), ..Default::default() });
+
+        assert!(prompt.contains("- cargo build, lyckades"));
+        assert!(prompt.contains("Exit code: 101 (misslyckades)"));
+        assert!(!prompt.contains("${exit_status_text}"));
+        assert_eq!(config.status_label(0), "lyckades");
+    }
+
+    #[test]
+    fn test_format_prompt_summary_language() {
+        let mut config = Config::default();
+        let prompt = config.format_prompt("ls", 0, "files", 50, &PromptContext::default());
+        assert!(prompt.contains("Respond in English."));
+
+        config.summary_language = "Swedish".to_string();
+        let prompt = config.format_prompt("ls", 0, "files", 50, &PromptContext::default());
+        assert!(prompt.contains("Respond in Swedish."));
+        assert!(!prompt.contains("${summary_language}"));
+
+        // Custom templates without the placeholder still get the instruction
+        config.provider.prompt = "Summarize ${output}".to_string();
+        let prompt = config.format_prompt("ls", 0, "files", 50, &PromptContext::default());
+        assert_eq!(prompt, "Summarize files\n\nRespond in Swedish.");
+
+        config.summary_language = "English".to_string();
+        let prompt = config.format_prompt("ls", 0, "files", 50, &PromptContext::default());
+        assert_eq!(prompt, "Summarize files");
+    }
+
     #[test]
     fn test_get_summary_words_default() {
         let config = Config::default();
@@ -309,12 +1701,184 @@ mod tests {
         let mut config = Config::default();
         config.commands.insert(
             "npx jest".to_string(),
-            CommandOverride::SummaryWords { summary_words: 200 }
+            CommandOverride::Settings(CommandSettings { summary_words: Some(200), ..Default::default() })
         );
         assert_eq!(config.get_summary_words("npx jest"), 200);
         assert_eq!(config.get_summary_words("other command"), 100);
     }
 
+    #[test]
+    fn test_get_summary_window() {
+        let toml_str = r#"
+summary_window = "both:40"
+
+[commands]
+"cargo test".summary_window = "tail:100"
+"cargo build".summary_words = 50
+"#;
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert_eq!(config.get_summary_window("cargo test"), SummaryWindow::Tail(100));
+        assert_eq!(config.get_summary_window("cargo build"), SummaryWindow::Both(40));
+        assert_eq!(Config::default().get_summary_window("ls"), SummaryWindow::Whole);
+
+        assert!(toml::from_str::<Config>("summary_window = \"tail\"").is_err());
+    }
+
+    #[test]
+    fn test_generation_settings_override() {
+        let toml_str = r#"
+[provider]
+temperature = 0.2
+max_tokens = 300
+
+[commands]
+"cargo build".max_tokens = 120
+"git log".max_tokens = 1000
+"git log".temperature = 0.0
+"#;
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert_eq!(config.get_max_tokens("cargo build"), 120);
+        assert_eq!(config.get_temperature("cargo build"), 0.2);
+        assert_eq!(config.get_max_tokens("git log"), 1000);
+        assert_eq!(config.get_temperature("git log"), 0.0);
+        assert_eq!(config.get_max_tokens("ls"), 300);
+
+        let defaults = Config::default();
+        assert_eq!(defaults.get_temperature("ls"), 0.7);
+        assert_eq!(defaults.get_max_tokens("ls"), 500);
+    }
+
+    #[test]
+    fn test_get_empty_failure_settings() {
+        let toml_str = r#"
+[commands]
+"grep -r TODO src".empty_output_note = "grep exits with 1 when nothing matches"
+"grep -r TODO src".benign_empty_failure = true
+"cargo build".empty_output_note = "an empty failing build usually means the toolchain is missing"
+"#;
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert_eq!(
+            config.get_empty_failure_settings("grep -r TODO src"),
+            (Some("grep exits with 1 when nothing matches"), true)
+        );
+        assert!(!config.get_empty_failure_settings("cargo build").1);
+        assert_eq!(config.get_empty_failure_settings("ls"), (None, false));
+    }
+
+    #[test]
+    fn test_apply_env_overrides() {
+        let env = |name: &str| match name {
+            "CTX_GUARD_PROVIDER_TYPE" => Some("ollama".to_string()),
+            "CTX_GUARD_PROVIDER_URL" => Some("http://ollama:11434".to_string()),
+            "CTX_GUARD_MODEL" => Some("  ".to_string()),
+            _ => None,
+        };
+        let mut config = Config::default();
+        config.apply_env_overrides(env);
+
+        assert_eq!(config.provider.r#type, "ollama");
+        assert_eq!(config.provider.url, "http://ollama:11434");
+        // Blank values leave the setting alone
+        assert_eq!(config.provider.model, Config::default().provider.model);
+    }
+
+    #[test]
+    fn test_load_from_path_parse_error_names_file() {
+        let path = std::env::temp_dir().join(format!("ctx_guard_bad_config_{}.toml", std::process::id()));
+        fs::write(&path, "clean_up_days = 1\nsummary_language = \"English\n").unwrap();
+
+        let error = Config::load_from_path(Some(path.clone())).unwrap_err();
+        assert!(matches!(error, ConfigError::ParseError { .. }));
+        let message = error.to_string();
+        assert!(message.contains(&path.display().to_string()));
+        assert!(message.contains("line 2"));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_write_default_config_reports_write_error() {
+        // A file where the config directory should be makes creating it fail
+        let blocker = std::env::temp_dir().join(format!("ctx_guard_config_blocker_{}", std::process::id()));
+        fs::write(&blocker, "").unwrap();
+
+        let error = write_default_config_if_missing(&blocker.join("config.toml")).unwrap_err();
+        assert!(matches!(error, ConfigError::WriteError { .. }));
+        assert!(error.to_string().starts_with("Failed to write config file"));
+
+        let _ = fs::remove_file(&blocker);
+    }
+
+    #[test]
+    fn test_load_from_path_ignores_bom_and_leading_whitespace() {
+        let path = std::env::temp_dir().join(format!("ctx_guard_bom_config_{}.toml", std::process::id()));
+        fs::write(&path, "\u{feff}clean_up_days = 3\n").unwrap();
+        assert_eq!(Config::load_from_path(Some(path.clone())).unwrap().clean_up_days, 3);
+
+        fs::write(&path, "\u{feff}\r\n\u{a0} \tclean_up_days = 4\n").unwrap();
+        assert_eq!(Config::load_from_path(Some(path.clone())).unwrap().clean_up_days, 4);
+
+        // Errors still point at the line in the file
+        fs::write(&path, "\u{feff}\n\n\u{a0}clean_up_days = 4\nsummary_language = \"English\n").unwrap();
+        let message = Config::load_from_path(Some(path.clone())).unwrap_err().to_string();
+        assert!(message.contains("line 4"));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_shell_selection() {
+        let config: Config = toml::from_str("shell = \"fish\"\nshell_flag = \"--command\"").unwrap();
+        assert_eq!(config.shell(None), Shell::new("fish", Some("--command")));
+        assert_eq!(config.shell(Some("bash")), Shell::new("bash", Some("-c")));
+        assert_eq!(Config::default().shell(None), Shell::default());
+    }
+
+    #[test]
+    fn test_resolve_provider_named() {
+        let toml_str = r#"
+[provider]
+url = "http://127.0.0.1:1234"
+model = "default-model"
+summary_words = 80
+
+[providers.fast]
+model = "small-model"
+
+[providers.smart]
+url = "gpu-box:8080"
+model = "big-model"
+
+[commands]
+"cargo build".provider = "fast"
+"#;
+        let config: Config = toml::from_str(toml_str).unwrap();
+
+        let default = config.resolve_provider(None).unwrap();
+        assert_eq!(default.model, "default-model");
+
+        let fast = config.resolve_provider(Some("fast")).unwrap();
+        assert_eq!(fast.model, "small-model");
+        // Unset fields inherit from [provider]
+        assert_eq!(fast.url, "http://127.0.0.1:1234");
+        assert_eq!(fast.summary_words, 80);
+
+        let smart = config.resolve_provider(Some("smart")).unwrap();
+        assert_eq!(smart.url, "http://gpu-box:8080");
+        assert_eq!(smart.model, "big-model");
+
+        assert_eq!(config.get_command_provider("cargo build"), Some("fast"));
+        assert_eq!(config.get_command_provider("cargo test"), None);
+        // Provider-only overrides keep the default summary length
+        assert_eq!(config.get_summary_words("cargo build"), 80);
+    }
+
+    #[test]
+    fn test_resolve_provider_unknown() {
+        let config = Config::default();
+        assert!(matches!(config.resolve_provider(Some("missing")), Err(ConfigError::UnknownProvider(_))));
+    }
+
     #[test]
     fn test_get_output_length_threshold_defaults_to_summary_length() {
         let config = Config::default();
@@ -335,28 +1899,149 @@ mod tests {
     }
 
     #[test]
-    fn test_is_command_disabled() {
+    fn test_finished_quickly() {
+        use std::time::Duration;
+
         let mut config = Config::default();
-        assert!(!config.is_command_disabled("some command"));
+        assert!(!config.finished_quickly(Duration::ZERO));
+
+        config.summarize_if_duration_exceeds_secs = 10;
+        assert!(config.finished_quickly(Duration::from_millis(200)));
+        assert!(config.finished_quickly(Duration::from_secs(10)));
+        assert!(!config.finished_quickly(Duration::from_millis(10_001)));
+    }
+
+    #[test]
+    fn test_is_summary_disabled() {
+        let mut config = Config::default();
+        assert!(!config.is_summary_disabled("some command"));
         
-        // In TOML, `command = false` means disabled
         config.commands.insert(
             "curl -v https://example.com".to_string(),
-            CommandOverride::Disabled(false)
+            CommandOverride::Settings(CommandSettings { summarize: Some(false), ..Default::default() })
         );
-        assert!(config.is_command_disabled("curl -v https://example.com"));
-        assert!(!config.is_command_disabled("other command"));
+        assert!(config.is_summary_disabled("curl -v https://example.com"));
+        assert!(!config.is_summary_disabled("other command"));
         
         config.commands.insert(
             "another command".to_string(),
-            CommandOverride::Disabled(true)
+            CommandOverride::Settings(CommandSettings { summarize: Some(true), ..Default::default() })
         );
-        assert!(!config.is_command_disabled("another command"));
+        assert!(!config.is_summary_disabled("another command"));
+    }
+
+    #[test]
+    fn test_legacy_command_override() {
+        let toml_str = r#"
+[commands]
+"curl -v https://example.com" = false
+"another command" = true
+"npx jest".summarize = false
+"#;
+        let config: Config = toml::from_str(toml_str).unwrap();
+        // The old `command = false` form still means "don't summarize"
+        assert!(config.is_summary_disabled("curl -v https://example.com"));
+        assert!(!config.is_command_blocked("curl -v https://example.com"));
+        assert!(!config.is_summary_disabled("another command"));
+
+        let warnings = config.load_warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[1].contains("\"curl -v https://example.com\".summarize = false"));
+    }
+
+    #[test]
+    fn test_normalize_provider_url_missing_scheme() {
+        assert_eq!(normalize_provider_url("127.0.0.1:1234").unwrap(), "http://127.0.0.1:1234");
+        assert_eq!(normalize_provider_url("localhost:8080").unwrap(), "http://localhost:8080");
+        assert_eq!(normalize_provider_url(" https://example.com ").unwrap(), "https://example.com");
+    }
+
+    #[test]
+    fn test_normalize_provider_url_trailing_path() {
+        assert_eq!(normalize_provider_url("http://127.0.0.1:1234/").unwrap(), "http://127.0.0.1:1234/");
+        assert_eq!(normalize_provider_url("gateway.local/api").unwrap(), "http://gateway.local/api");
+    }
+
+    #[test]
+    fn test_normalize_provider_url_garbage() {
+        assert!(matches!(normalize_provider_url(""), Err(ConfigError::InvalidUrl { .. })));
+        assert!(matches!(normalize_provider_url("http://"), Err(ConfigError::InvalidUrl { .. })));
+        assert!(matches!(normalize_provider_url("ftp://example.com"), Err(ConfigError::InvalidUrl { .. })));
+        assert!(matches!(normalize_provider_url("not a url at all"), Err(ConfigError::InvalidUrl { .. })));
+
+        let message = normalize_provider_url("http://exa mple.com").unwrap_err().to_string();
+        assert!(message.contains("http://exa mple.com"));
+    }
+
+    #[test]
+    fn test_is_command_blocked() {
+        let toml_str = r#"
+[commands]
+"rm -rf /".block = true
+"git push --force".blocked = true
+"curl -v https://example.com".summarize = false
+"#;
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert!(config.is_command_blocked("rm -rf /"));
+        assert!(config.is_command_blocked("git push --force"));
+        assert!(!config.is_summary_disabled("rm -rf /"));
+        // Disabling summarization doesn't block the command from running
+        assert!(config.is_summary_disabled("curl -v https://example.com"));
+        assert!(!config.is_command_blocked("curl -v https://example.com"));
+        assert!(!config.is_command_blocked("ls"));
+        assert!(config.load_warnings().is_empty());
+    }
+
+    #[test]
+    fn test_is_outside_allowlist() {
+        let toml_str = r#"
+allowlist_only = true
+
+[commands]
+"cargo test".summary_words = 80
+"curl -v https://example.com".summarize = false
+"#;
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert!(!config.is_outside_allowlist("cargo test"));
+        // Any entry counts, even one that disables summarization
+        assert!(!config.is_outside_allowlist("curl -v https://example.com"));
+        assert!(config.is_outside_allowlist("cargo test --release"));
+        assert!(config.is_outside_allowlist("ls"));
+
+        let config: Config = toml::from_str("allowlist_only = true\n[commands]\n\"cargo test*\".summary_words = 80\n\"make ?\".summary_words = 80\n").unwrap();
+        assert!(!config.is_outside_allowlist("cargo test"));
+        assert!(!config.is_outside_allowlist("cargo test --lib"));
+        assert!(config.is_outside_allowlist("cargo build"));
+        assert!(!config.is_outside_allowlist("make a"));
+        assert!(config.is_outside_allowlist("make all"));
+
+        assert!(!Config::default().is_outside_allowlist("ls"));
+    }
+
+    #[test]
+    fn test_is_passthrough_command() {
+        assert!(!Config::default().is_passthrough_command("vim src/main.rs"));
+
+        let config: Config = toml::from_str("passthrough_commands = [\"vim\", \"less\", \"ssh\"]").unwrap();
+        assert!(config.is_passthrough_command("vim src/main.rs"));
+        assert!(config.is_passthrough_command("/usr/bin/less README.md"));
+        assert!(config.is_passthrough_command("  ssh host"));
+        assert!(!config.is_passthrough_command("cargo test"));
+        // Only the first word counts
+        assert!(!config.is_passthrough_command("git commit -m vim"));
+        assert!(!config.is_passthrough_command(""));
+
+        let config: Config = toml::from_str("passthrough_commands = [\"psql\"]").unwrap();
+        assert!(config.is_passthrough_command("psql mydb"));
+        assert!(!config.is_passthrough_command("vim"));
     }
 
     #[test]
     fn test_config_deserialize() {
         let toml_str = r#"
+failure_stream_priority = "combined"
+file_streams = "stdout"
+
 [provider]
 type = "lmstudio"
 url = "http://localhost:8080"
@@ -367,9 +2052,13 @@ output_length_threshold = 75
 [commands]
 "npx jest".summary_words = 200
 "curl -v https://example.com" = false
+"make lint".summarize = false
 "#;
         
         let config: Config = toml::from_str(toml_str).unwrap();
+        assert_eq!(config.failure_stream_priority, OutputStream::Combined);
+        assert_eq!(config.file_streams, OutputStream::Stdout);
+        assert_eq!(config.summary_streams, OutputStream::Combined);
         assert_eq!(config.provider.url, "http://localhost:8080");
         assert_eq!(config.provider.model, "custom-model");
         assert_eq!(config.provider.summary_words, 50);
@@ -377,7 +2066,54 @@ output_length_threshold = 75
         // Even with a lower threshold, we enforce the summary length floor
         assert_eq!(config.get_output_length_threshold("npx jest"), 200);
         assert_eq!(config.get_summary_words("npx jest"), 200);
-        assert!(config.is_command_disabled("curl -v https://example.com"));
+        assert!(config.is_summary_disabled("curl -v https://example.com"));
+        assert!(config.is_summary_disabled("make lint"));
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("cargo test", "cargo test"), 0);
+        assert_eq!(edit_distance("cargo test", "cargo test --"), 3);
+        assert_eq!(edit_distance("npm tset", "npm test"), 2);
+        assert_eq!(edit_distance("", "ls"), 2);
+    }
+
+    #[test]
+    fn test_command_override_reports_unknown_setting() {
+        let error = toml::from_str::<Config>("[commands]\n\"cargo test\".sumarize = false\n").unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("unknown field `sumarize`"), "{message}");
+        assert!(message.contains("line 2"), "{message}");
+
+        let error = toml::from_str::<Config>("[commands]\n\"cargo test\" = 3\n").unwrap_err();
+        assert!(error.to_string().contains("expected a table of command settings"));
+    }
+
+    #[test]
+    fn test_override_key_suggestions() {
+        let mut config = Config::default();
+        for key in ["cargo test", "npm run build", "make", "ls", "cargo test?", "cargo *"] {
+            config.commands.insert(key.to_string(), CommandOverride::Legacy(false));
+        }
+        let ran = vec!["cargo test --".to_string(), "make".to_string(), "git status".to_string(), "cd".to_string()];
+
+        // Exact matches and keys unlike anything that ran get no suggestion, and short
+        // keys need a close match (`ls` is two edits from `cd`). Patterns are never
+        // suggested against
+        assert_eq!(
+            config.override_key_suggestions(&ran),
+            vec!["the override \"cargo test\" never matched a recent command; did you mean \"cargo test --\"?".to_string()]
+        );
+        assert!(config.override_key_suggestions(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_is_default_provider() {
+        let mut config = Config::default();
+        assert!(config.is_default_provider());
+
+        config.provider.url = "http://127.0.0.1:11434".to_string();
+        assert!(!config.is_default_provider());
     }
 }
 
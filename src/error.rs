@@ -0,0 +1,69 @@
+use crate::config::ConfigError;
+use crate::executor::ExecutorError;
+use chrono::{DateTime, Local};
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors that stop a run, from any of its stages. `cg` reports them and exits with
+/// `exit_code()`; embedders can match on the stage instead.
+#[derive(Debug, Error)]
+pub enum CtxGuardError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Execution(#[from] ExecutorError),
+    #[error("Command '{0}' is blocked in configuration")]
+    CommandBlocked(String),
+    #[error("Failed to read output from '{}'", .0.display())]
+    UnreadableCapture(PathBuf),
+    #[error("'{}' is not a readable ctx_guard capture file", .0.display())]
+    NotACapture(PathBuf),
+    #[error("no run with ID '{run_id}' in {} (see `cg history`)", .dir.display())]
+    RunNotFound { run_id: String, dir: PathBuf },
+    #[error("no runs recorded for session '{0}' (run commands with --session {0})")]
+    EmptySession(String),
+    #[error("the most recent command has no earlier run to compare with")]
+    NoEarlierRun,
+    #[error("--since ({}) is after --until ({})", .since.format("%Y-%m-%d %H:%M:%S"), .until.format("%Y-%m-%d %H:%M:%S"))]
+    InvalidTimeWindow { since: DateTime<Local>, until: DateTime<Local> },
+    #[error("Failed to read stdin: {0}")]
+    Stdin(std::io::Error),
+}
+
+impl CtxGuardError {
+    /// Exit code to report: the executor's for commands that couldn't be run (see
+    /// `ExecutorError::exit_code`), 1 for everything else.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CtxGuardError::Execution(e) => e.exit_code(),
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        let not_found = ExecutorError::ShellNotFound("nosuchshell".to_string());
+        assert_eq!(CtxGuardError::from(not_found).exit_code(), 127);
+        assert_eq!(CtxGuardError::CommandBlocked("rm -rf /".to_string()).exit_code(), 1);
+        assert_eq!(CtxGuardError::from(ConfigError::UnknownProvider("fast".to_string())).exit_code(), 1);
+    }
+
+    #[test]
+    fn test_messages_come_from_the_stage() {
+        let error = CtxGuardError::from(ConfigError::UnknownProvider("fast".to_string()));
+        assert_eq!(error.to_string(), "Unknown provider 'fast' (define it under [providers.fast])");
+        assert_eq!(
+            CtxGuardError::CommandBlocked("rm -rf /".to_string()).to_string(),
+            "Command 'rm -rf /' is blocked in configuration"
+        );
+        assert_eq!(
+            CtxGuardError::EmptySession("deploy".to_string()).to_string(),
+            "no runs recorded for session 'deploy' (run commands with --session deploy)"
+        );
+    }
+}
@@ -1,10 +1,367 @@
-use std::process::{Command, Output};
+use crate::output::collapse_carriage_returns;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum ExecutorError {
     #[error("Failed to execute command: {0}")]
     ExecutionError(#[from] std::io::Error),
+    #[error("Shell '{0}' was not found")]
+    ShellNotFound(String),
+    #[error("Failed to launch shell '{shell}': {source}")]
+    ShellLaunchFailed { shell: String, source: std::io::Error },
+}
+
+impl ExecutorError {
+    /// True if the shell never started, so the command didn't run at all.
+    pub fn is_shell_failure(&self) -> bool {
+        matches!(self, ExecutorError::ShellNotFound(_) | ExecutorError::ShellLaunchFailed { .. })
+    }
+
+    /// Exit code to report for the error, following `env` and POSIX shells: 127 when
+    /// the shell doesn't exist, 126 when it exists but couldn't be started, 1 otherwise.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ExecutorError::ShellNotFound(_) => 127,
+            ExecutorError::ShellLaunchFailed { source, .. } if source.kind() == std::io::ErrorKind::NotFound => 127,
+            ExecutorError::ShellLaunchFailed { .. } => 126,
+            ExecutorError::ExecutionError(_) => 1,
+        }
+    }
+}
+
+/// Interpreter used to run command strings, with the flag that makes it run a command
+/// given as an argument (e.g. `bash -c`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shell {
+    pub program: String,
+    pub flag: String,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        #[cfg(windows)]
+        return Self::new("cmd", None);
+        #[cfg(not(windows))]
+        return Self::new("sh", None);
+    }
+}
+
+impl Shell {
+    /// Creates a shell; without an explicit `flag`, the usual one for the program is used.
+    pub fn new(program: &str, flag: Option<&str>) -> Self {
+        let flag = flag.map(str::to_string).unwrap_or_else(|| {
+            let name = std::path::Path::new(program)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_ascii_lowercase())
+                .unwrap_or_default();
+            match name.as_str() {
+                "cmd" => "/C",
+                "powershell" | "pwsh" => "-Command",
+                _ => "-c",
+            }
+            .to_string()
+        });
+        Self { program: program.to_string(), flag }
+    }
+
+    /// Checks that the program exists, either as a path or on `PATH`.
+    pub fn validate(&self) -> Result<(), ExecutorError> {
+        let program = std::path::Path::new(&self.program);
+        let found = if program.components().count() > 1 {
+            program.is_file()
+        } else {
+            std::env::var_os("PATH").is_some_and(|paths| {
+                std::env::split_paths(&paths).any(|dir| {
+                    dir.join(program).is_file() || (cfg!(windows) && dir.join(program).with_extension("exe").is_file())
+                })
+            })
+        };
+        if found {
+            Ok(())
+        } else {
+            Err(ExecutorError::ShellNotFound(self.program.clone()))
+        }
+    }
+
+    fn command(&self, command_str: &str) -> Command {
+        let mut command = Command::new(&self.program);
+        command.arg(&self.flag).arg(command_str);
+        command
+    }
+
+    /// Wraps an error from starting the shell, so it isn't mistaken for the command failing.
+    fn launch_error(&self, source: std::io::Error) -> ExecutorError {
+        ExecutorError::ShellLaunchFailed { shell: self.program.clone(), source }
+    }
+}
+
+/// Selects one of the command's output streams, or both (`Combined`: all of stdout followed by
+/// all of stderr, not interleaved by time).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    #[default]
+    Combined,
+    Stdout,
+    Stderr,
+}
+
+/// Signal sent to a command that runs past its timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeoutSignal {
+    /// SIGTERM, letting the command clean up; followed by SIGKILL after the grace period
+    #[default]
+    Term,
+    /// SIGKILL right away
+    Kill,
+}
+
+impl std::str::FromStr for TimeoutSignal {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().trim_start_matches("sig") {
+            "term" => Ok(TimeoutSignal::Term),
+            "kill" => Ok(TimeoutSignal::Kill),
+            _ => Err(format!("unknown signal '{}' (expected TERM or KILL)", value)),
+        }
+    }
+}
+
+/// Limit on a command's run time, and how it is stopped once exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandTimeout {
+    pub limit: Duration,
+    pub signal: TimeoutSignal,
+    /// How long to wait after SIGTERM before sending SIGKILL; zero never escalates
+    pub grace: Duration,
+}
+
+/// Exit code reported for a command stopped by its timeout, as GNU `timeout` does.
+pub const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// Tracks a running command's timeout and sends each signal when it is due.
+struct TimeoutEnforcer {
+    timeout: CommandTimeout,
+    /// When the next signal is due, if one is still to be sent
+    next_signal_at: Option<Instant>,
+    timed_out: bool,
+}
+
+impl TimeoutEnforcer {
+    fn new(timeout: CommandTimeout) -> Self {
+        Self { timeout, next_signal_at: Some(Instant::now() + timeout.limit), timed_out: false }
+    }
+
+    /// Time until the next signal is due, or `None` once no more will be sent.
+    fn time_left(&self) -> Option<Duration> {
+        self.next_signal_at.map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Sends the timeout signal, or SIGKILL after it, if it is due.
+    fn enforce(&mut self, child: &mut Child) {
+        if self.next_signal_at.is_none_or(|at| Instant::now() < at) {
+            return;
+        }
+        if self.timed_out {
+            send_signal(child, TimeoutSignal::Kill);
+            self.next_signal_at = None;
+            return;
+        }
+        self.timed_out = true;
+        send_signal(child, self.timeout.signal);
+        let escalate = self.timeout.signal == TimeoutSignal::Term && !self.timeout.grace.is_zero();
+        self.next_signal_at = escalate.then(|| Instant::now() + self.timeout.grace);
+    }
+}
+
+/// Signals the command's whole process group, so processes started by the shell stop too.
+#[cfg(unix)]
+fn send_signal(child: &mut Child, signal: TimeoutSignal) {
+    let signal = match signal {
+        TimeoutSignal::Term => libc::SIGTERM,
+        TimeoutSignal::Kill => libc::SIGKILL,
+    };
+    // The child leads its own process group (see `run_captured`); a negative pid targets it
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), signal);
+    }
+}
+
+/// Windows has no SIGTERM; both signals terminate the process.
+#[cfg(not(unix))]
+fn send_signal(child: &mut Child, _signal: TimeoutSignal) {
+    let _ = child.kill();
+}
+
+/// Process group of the command running under a timeout, for `forward_signal`; 0 if none.
+#[cfg(unix)]
+static FORWARD_TO_GROUP: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(0);
+/// The last signal `forward_signal` passed on, 0 if none.
+#[cfg(unix)]
+static FORWARDED_SIGNAL: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(0);
+
+#[cfg(unix)]
+extern "C" fn forward_signal(signal: libc::c_int) {
+    use std::sync::atomic::Ordering;
+    FORWARDED_SIGNAL.store(signal, Ordering::SeqCst);
+    let group = FORWARD_TO_GROUP.load(Ordering::SeqCst);
+    if group > 0 {
+        unsafe {
+            libc::kill(-group, signal);
+        }
+    }
+}
+
+/// A command in its own process group doesn't get the terminal's Ctrl-C (or a SIGTERM
+/// or SIGHUP sent to `cg`), so while it runs these are passed on to its group. Once the
+/// command has been reaped, `finish` restores the previous handlers and, if one of the
+/// signals came in, stops `cg` with it as it would have without the forwarding.
+#[cfg(unix)]
+struct SignalForwarding {
+    previous: Vec<(libc::c_int, libc::sighandler_t)>,
+}
+
+#[cfg(unix)]
+impl SignalForwarding {
+    const SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+    fn start(child: &Child) -> Self {
+        use std::sync::atomic::Ordering;
+        FORWARDED_SIGNAL.store(0, Ordering::SeqCst);
+        FORWARD_TO_GROUP.store(child.id() as i32, Ordering::SeqCst);
+        let handler = forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        let mut previous = Vec::new();
+        for signal in Self::SIGNALS {
+            let old = unsafe { libc::signal(signal, handler) };
+            // A signal `cg` was started to ignore stays ignored
+            if old == libc::SIG_IGN {
+                unsafe { libc::signal(signal, libc::SIG_IGN) };
+            }
+            previous.push((signal, old));
+        }
+        Self { previous }
+    }
+
+    fn finish(self) {
+        let signal = FORWARDED_SIGNAL.load(std::sync::atomic::Ordering::SeqCst);
+        drop(self);
+        if signal != 0 {
+            unsafe {
+                libc::raise(signal);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SignalForwarding {
+    fn drop(&mut self) {
+        FORWARD_TO_GROUP.store(0, std::sync::atomic::Ordering::SeqCst);
+        for &(signal, handler) in &self.previous {
+            unsafe { libc::signal(signal, handler) };
+        }
+    }
+}
+
+/// Resources used by the executed command and everything it spawned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ResourceUsage {
+    /// Peak resident set size of the largest process, in KiB. This is a maximum over
+    /// every child `cg` has waited for, not only the last command: with retries it covers
+    /// all attempts, and a larger earlier child hides a smaller peak.
+    pub max_rss_kb: u64,
+    pub user_time: Duration,
+    pub system_time: Duration,
+}
+
+impl ResourceUsage {
+    /// Usage accumulated by all terminated, waited-for child processes of `cg`.
+    /// Taking a snapshot before and after running a command and calling `since` on the
+    /// later one gives that command's CPU time. Only available on Unix.
+    #[cfg(unix)]
+    pub fn children() -> Option<Self> {
+        let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+        // SAFETY: getrusage only writes into the provided struct
+        let usage = unsafe {
+            if libc::getrusage(libc::RUSAGE_CHILDREN, usage.as_mut_ptr()) != 0 {
+                return None;
+            }
+            usage.assume_init()
+        };
+
+        let to_duration = |tv: libc::timeval| Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64);
+        // ru_maxrss is reported in bytes on macOS and in KiB elsewhere
+        let max_rss_kb = if cfg!(target_os = "macos") {
+            usage.ru_maxrss as u64 / 1024
+        } else {
+            usage.ru_maxrss as u64
+        };
+
+        Some(Self {
+            max_rss_kb,
+            user_time: to_duration(usage.ru_utime),
+            system_time: to_duration(usage.ru_stime),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn children() -> Option<Self> {
+        None
+    }
+
+    /// CPU time used since `earlier`. The peak RSS is kept as is: the kernel only keeps a
+    /// lifetime maximum over all children, which can't be split per command.
+    pub fn since(&self, earlier: &ResourceUsage) -> Self {
+        Self {
+            max_rss_kb: self.max_rss_kb,
+            user_time: self.user_time.saturating_sub(earlier.user_time),
+            system_time: self.system_time.saturating_sub(earlier.system_time),
+        }
+    }
+
+    /// Encodes the usage for the capture metadata, e.g. `max_rss_kb=2048 user_ms=120 sys_ms=15`.
+    pub fn to_metadata_value(&self) -> String {
+        format!(
+            "max_rss_kb={} user_ms={} sys_ms={}",
+            self.max_rss_kb,
+            self.user_time.as_millis(),
+            self.system_time.as_millis()
+        )
+    }
+
+    pub fn from_metadata_value(value: &str) -> Option<Self> {
+        let mut usage = Self::default();
+        for pair in value.split_whitespace() {
+            let (key, number) = pair.split_once('=')?;
+            let number: u64 = number.parse().ok()?;
+            match key {
+                "max_rss_kb" => usage.max_rss_kb = number,
+                "user_ms" => usage.user_time = Duration::from_millis(number),
+                "sys_ms" => usage.system_time = Duration::from_millis(number),
+                _ => {}
+            }
+        }
+        Some(usage)
+    }
+}
+
+impl std::fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "peak memory {:.1} MB, user CPU {:.2}s, system CPU {:.2}s",
+            self.max_rss_kb as f64 / 1024.0,
+            self.user_time.as_secs_f64(),
+            self.system_time.as_secs_f64()
+        )
+    }
 }
 
 pub struct ExecutionResult {
@@ -12,6 +369,13 @@ pub struct ExecutionResult {
     pub stdout: String,
     pub stderr: String,
     pub combined_output: String,
+    /// Undecoded stdout and stderr, joined the same way as `combined_output`
+    pub raw_output: Vec<u8>,
+    /// The command was stopped by its timeout (`exit_code` is then `TIMEOUT_EXIT_CODE`)
+    pub timed_out: bool,
+    /// Bytes from the start of the output that only went to a sink that keeps everything
+    /// (see `OutputSink::keeps_output`); the fields above then hold the end of the output
+    pub omitted_bytes: u64,
 }
 
 impl ExecutionResult {
@@ -23,18 +387,154 @@ impl ExecutionResult {
         } else {
             format!("{stdout}\n{stderr}")
         };
+        let mut raw_output = output.stdout;
+        if !output.stderr.is_empty() {
+            raw_output.push(b'\n');
+            raw_output.extend_from_slice(&output.stderr);
+        }
 
         Self {
             exit_code: output.status.code().unwrap_or(-1),
             stdout,
             stderr,
             combined_output,
+            raw_output,
+            timed_out: false,
+            omitted_bytes: 0,
+        }
+    }
+
+    /// Builds a result from previously captured output, e.g. to summarize an old capture again.
+    pub fn from_captured(exit_code: i32, output: Vec<u8>) -> Self {
+        let combined_output = String::from_utf8_lossy(&output).to_string();
+
+        Self {
+            exit_code,
+            stdout: combined_output.clone(),
+            stderr: String::new(),
+            combined_output,
+            raw_output: output,
+            timed_out: false,
+            omitted_bytes: 0,
+        }
+    }
+
+    /// Combined output with the configured transformations applied, as shown to the user,
+    /// saved and summarized. The raw fields are left as captured.
+    pub fn display_output(&self, options: ProcessOptions) -> String {
+        self.stream_output(OutputStream::Combined, options)
+    }
+
+    /// One stream (or the combined output) with the configured transformations applied.
+    pub fn stream_output(&self, stream: OutputStream, options: ProcessOptions) -> String {
+        let text = match stream {
+            OutputStream::Combined => &self.combined_output,
+            OutputStream::Stdout => &self.stdout,
+            OutputStream::Stderr => &self.stderr,
+        };
+        process_text(text, options)
+    }
+
+    /// Output to send to the model, with the configured transformations applied. For
+    /// failed commands with both streams non-empty, the stream prioritized by
+    /// `failure_stream_priority` is placed first (labelled) so it gets the model's
+    /// attention and survives any later truncation. Otherwise this is `display_output`.
+    ///
+    /// With `summary_streams` set to one stream, only that stream is sent, unless it is
+    /// empty (or the output was read back from a capture, where the streams are joined).
+    pub fn output_for_prompt(&self, options: ProcessOptions) -> String {
+        if options.summary_streams != OutputStream::Combined {
+            let selected = self.stream_output(options.summary_streams, options);
+            if !selected.trim().is_empty() {
+                return selected;
+            }
+        }
+        if self.is_success() || self.stdout.trim().is_empty() || self.stderr.trim().is_empty() {
+            return self.display_output(options);
+        }
+
+        let stdout = process_text(&self.stdout, options);
+        let stderr = process_text(&self.stderr, options);
+        match options.failure_stream_priority {
+            OutputStream::Combined => self.display_output(options),
+            OutputStream::Stderr => format!("[stderr]\n{}\n\n[stdout]\n{}", stderr.trim_end(), stdout.trim_end()),
+            OutputStream::Stdout => format!("[stdout]\n{}\n\n[stderr]\n{}", stdout.trim_end(), stderr.trim_end()),
         }
     }
 
     pub fn is_success(&self) -> bool {
         self.exit_code == 0
     }
+
+    /// Applies a text transformation to the decoded output. `raw_output` is left as
+    /// captured, since it is only used for binary output.
+    pub fn map_text(mut self, transform: impl Fn(&str) -> String) -> Self {
+        self.stdout = transform(&self.stdout);
+        self.stderr = transform(&self.stderr);
+        self.combined_output = transform(&self.combined_output);
+        self
+    }
+}
+
+/// Settings for how captured output is processed before it is shown, saved and summarized,
+/// taken from the config (see `Config::process_options`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProcessOptions {
+    /// Collapse carriage-return redraws to the line a terminal ends up showing
+    pub collapse_progress: bool,
+    /// Stream placed first in the prompt for a failed command
+    pub failure_stream_priority: OutputStream,
+    /// Stream(s) sent to the model
+    pub summary_streams: OutputStream,
+}
+
+/// The text pipeline behind `ExecutionResult::display_output`. Steps run in a fixed order:
+///
+/// 1. `collapse_progress`: carriage-return redraws are collapsed to the final line a
+///    terminal would show. This runs first because it needs the raw redraw sequences
+///    (including the escape codes that erase lines), and every later step should see a
+///    progress bar as the one line it ends up as.
+///
+/// Steps added later go after it; anything that removes escape codes must run before
+/// steps that compare lines, so colored and plain copies of a line are treated alike.
+fn process_text(text: &str, options: ProcessOptions) -> String {
+    if options.collapse_progress {
+        collapse_carriage_returns(text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Attempts made to start a command when spawning fails with a transient error.
+const SPAWN_ATTEMPTS: u32 = 3;
+/// Delay before the second attempt; doubled for each further one.
+const SPAWN_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Returns true for spawn errors that may go away on their own shortly, such as the
+/// process or file descriptor limits being hit (EAGAIN, EMFILE, ENFILE).
+fn is_transient_spawn_error(error: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    if matches!(error.raw_os_error(), Some(libc::EAGAIN | libc::EMFILE | libc::ENFILE | libc::ENOMEM)) {
+        return true;
+    }
+    matches!(error.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted)
+}
+
+/// Calls `spawn` again with backoff while it fails with a transient error, up to
+/// `SPAWN_ATTEMPTS` times. Only starting the process is retried; a command that ran
+/// and exited nonzero is a result, not an error.
+fn retry_spawn<T>(mut spawn: impl FnMut() -> std::io::Result<T>) -> std::io::Result<T> {
+    let mut delay = SPAWN_RETRY_DELAY;
+    for _ in 1..SPAWN_ATTEMPTS {
+        match spawn() {
+            Err(e) if is_transient_spawn_error(&e) => {
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    spawn()
 }
 
 pub fn execute_command(command: &str, args: &[&str]) -> Result<ExecutionResult, ExecutorError> {
@@ -46,6 +546,11 @@ pub fn execute_command(command: &str, args: &[&str]) -> Result<ExecutionResult,
 }
 
 pub fn execute_command_string(command_str: &str) -> Result<ExecutionResult, ExecutorError> {
+    execute_command_string_in(&Shell::default(), command_str)
+}
+
+/// Runs a command string with the given shell and captures its output.
+pub fn execute_command_string_in(shell: &Shell, command_str: &str) -> Result<ExecutionResult, ExecutorError> {
     if command_str.trim().is_empty() {
         return Err(ExecutorError::ExecutionError(
             std::io::Error::new(std::io::ErrorKind::InvalidInput, "Empty command")
@@ -53,25 +558,465 @@ pub fn execute_command_string(command_str: &str) -> Result<ExecutionResult, Exec
     }
 
     // Use shell to execute the command so it handles things like `npx jest` properly
+    let output = retry_spawn(|| shell.command(command_str).output()).map_err(|e| shell.launch_error(e))?;
+
+    Ok(ExecutionResult::from_output(output))
+}
+
+/// Receives a command's output as it arrives, one chunk of stdout or stderr at a time.
+/// Any `Write` receives both streams as they come in.
+pub trait OutputSink {
+    fn write_chunk(&mut self, is_stderr: bool, chunk: &[u8]) -> std::io::Result<()>;
+
+    /// True if the sink keeps all of the output (e.g. a capture file), so only its end
+    /// needs to be held in memory.
+    fn keeps_output(&self) -> bool {
+        false
+    }
+
+    /// Called after each attempt of a (retried) command, with its exit code.
+    fn finish_attempt(&mut self, _exit_code: i32) {}
+}
+
+impl<W: Write + ?Sized> OutputSink for W {
+    fn write_chunk(&mut self, _is_stderr: bool, chunk: &[u8]) -> std::io::Result<()> {
+        self.write_all(chunk)?;
+        self.flush()
+    }
+}
+
+/// Appends a command's output to a capture file as it arrives. The file keeps all of it,
+/// so in memory only the last `STREAMED_TAIL_BYTES` of each stream are held. `layout`
+/// records where each stream went, so the file can be turned into the finished capture.
+pub struct CaptureFile<'a> {
+    pub file: &'a mut dyn Write,
+    pub layout: StreamLayout,
+}
+
+impl<'a> CaptureFile<'a> {
+    pub fn new(file: &'a mut dyn Write) -> Self {
+        Self { file, layout: StreamLayout::default() }
+    }
+}
+
+impl OutputSink for CaptureFile<'_> {
+    fn write_chunk(&mut self, is_stderr: bool, chunk: &[u8]) -> std::io::Result<()> {
+        self.file.write_chunk(is_stderr, chunk)?;
+        self.layout.record(is_stderr, chunk.len() as u64);
+        Ok(())
+    }
+
+    fn keeps_output(&self) -> bool {
+        true
+    }
+
+    fn finish_attempt(&mut self, exit_code: i32) {
+        self.layout.finish_attempt(exit_code);
+    }
+}
+
+/// A stretch of streamed output from one stream, by its offset from the start of the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamSegment {
+    pub is_stderr: bool,
+    pub start: u64,
+    pub len: u64,
+}
+
+/// The output one attempt wrote to a `CaptureFile`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AttemptLayout {
+    /// In the order written, with adjacent chunks of the same stream merged
+    pub segments: Vec<StreamSegment>,
+    /// Set once the attempt has finished
+    pub exit_code: Option<i32>,
+}
+
+/// Where the streams of a command went in a file written as they arrived, interleaved.
+/// Lets the streams be read back apart, the way `ExecutionResult` keeps them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamLayout {
+    pub attempts: Vec<AttemptLayout>,
+    written: u64,
+}
+
+/// A piece of a capture rebuilt from streamed output (see `StreamLayout::parts`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CapturePart {
+    Text(String),
+    /// `len` bytes of the streamed output, starting `start` bytes into it
+    Output { start: u64, len: u64 },
+}
+
+impl StreamLayout {
+    fn current_attempt(&mut self) -> &mut AttemptLayout {
+        if self.attempts.last().is_none_or(|attempt| attempt.exit_code.is_some()) {
+            self.attempts.push(AttemptLayout::default());
+        }
+        self.attempts.last_mut().expect("an attempt was just added")
+    }
+
+    fn record(&mut self, is_stderr: bool, len: u64) {
+        let start = self.written;
+        self.written += len;
+        let attempt = self.current_attempt();
+        match attempt.segments.last_mut() {
+            Some(last) if last.is_stderr == is_stderr && last.start + last.len == start => last.len += len,
+            _ => attempt.segments.push(StreamSegment { is_stderr, start, len }),
+        }
+    }
+
+    fn finish_attempt(&mut self, exit_code: i32) {
+        self.current_attempt().exit_code = Some(exit_code);
+    }
+
+    /// The pieces making up `stream` of the output, joined the way `execute_with_retries`
+    /// joins an `ExecutionResult`: stdout before stderr, and the last attempt only unless
+    /// `keep_all_attempts`, each under its header.
+    pub fn parts(&self, stream: OutputStream, keep_all_attempts: bool) -> Vec<CapturePart> {
+        let count = self.attempts.len();
+        let with_headers = keep_all_attempts && count > 1;
+        let first = if with_headers { 0 } else { count.saturating_sub(1) };
+        let ranges = |attempt: &AttemptLayout, is_stderr: bool| -> Vec<CapturePart> {
+            attempt
+                .segments
+                .iter()
+                .filter(|segment| segment.is_stderr == is_stderr)
+                .map(|segment| CapturePart::Output { start: segment.start, len: segment.len })
+                .collect()
+        };
+
+        let mut parts = Vec::new();
+        for (i, attempt) in self.attempts.iter().enumerate().skip(first) {
+            let header = with_headers.then(|| CapturePart::Text(attempt_header(i + 1, count, attempt.exit_code.unwrap_or(-1))));
+            let stdout = ranges(attempt, false);
+            let stderr = ranges(attempt, true);
+            match stream {
+                OutputStream::Stdout => parts.extend(header.into_iter().chain(stdout)),
+                OutputStream::Stderr => parts.extend(stderr),
+                OutputStream::Combined => {
+                    parts.extend(header.into_iter().chain(stdout));
+                    if !stderr.is_empty() {
+                        parts.push(CapturePart::Text("\n".to_string()));
+                        parts.extend(stderr);
+                    }
+                    if with_headers {
+                        parts.push(CapturePart::Text("\n".to_string()));
+                    }
+                }
+            }
+        }
+        parts
+    }
+}
+
+/// Forwards a command's stdout and stderr byte for byte to our own stdout and stderr, for
+/// `--raw-stdout`, and copies both to `file` if given.
+pub struct ForwardOutput<'a> {
+    pub file: Option<CaptureFile<'a>>,
+}
+
+impl OutputSink for ForwardOutput<'_> {
+    fn write_chunk(&mut self, is_stderr: bool, chunk: &[u8]) -> std::io::Result<()> {
+        if is_stderr {
+            let mut stderr = std::io::stderr().lock();
+            stderr.write_all(chunk)?;
+            stderr.flush()?;
+        } else {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(chunk)?;
+            stdout.flush()?;
+        }
+        match self.file {
+            Some(ref mut file) => file.write_chunk(is_stderr, chunk),
+            None => Ok(()),
+        }
+    }
+
+    fn keeps_output(&self) -> bool {
+        self.file.is_some()
+    }
+
+    fn finish_attempt(&mut self, exit_code: i32) {
+        if let Some(ref mut file) = self.file {
+            file.finish_attempt(exit_code);
+        }
+    }
+}
+
+/// Bytes of each stream kept in memory when the output also goes to a sink that keeps
+/// all of it: enough for the summary, which only needs the end of a long output.
+pub const STREAMED_TAIL_BYTES: usize = 1024 * 1024;
+
+/// Appends `chunk` to `buffer`. With a `limit`, bytes beyond the last `limit` are dropped
+/// from the front (once the buffer grows to twice the limit, so dropping stays cheap),
+/// and counted in `omitted`. The cut skips to the next UTF-8 character boundary.
+fn push_bounded(buffer: &mut Vec<u8>, chunk: &[u8], limit: Option<usize>, omitted: &mut u64) {
+    buffer.extend_from_slice(chunk);
+    let Some(limit) = limit else {
+        return;
+    };
+    if buffer.len() > 2 * limit {
+        let mut cut = buffer.len() - limit;
+        while cut < buffer.len() && (buffer[cut] & 0b1100_0000) == 0b1000_0000 {
+            cut += 1;
+        }
+        buffer.drain(..cut);
+        *omitted += cut as u64;
+    }
+}
+
+/// Like `execute_command_string_in`, but also copies stdout and stderr to `sink` as they
+/// arrive (interleaved in arrival order), so the output survives the command being
+/// killed. Writing to `sink` stops at the first error; the output is still captured.
+pub fn execute_command_string_streaming(shell: &Shell, command_str: &str, sink: &mut dyn OutputSink) -> Result<ExecutionResult, ExecutorError> {
+    run_captured(shell, command_str, sink, None)
+}
+
+/// Runs a command string like `execute_command_string_streaming`, stopping it once it
+/// runs past `timeout`. A stopped command reports `TIMEOUT_EXIT_CODE` and `timed_out`.
+pub fn execute_command_string_with_timeout(shell: &Shell, command_str: &str, sink: &mut dyn OutputSink, timeout: CommandTimeout) -> Result<ExecutionResult, ExecutorError> {
+    run_captured(shell, command_str, sink, Some(timeout))
+}
+
+fn run_captured(shell: &Shell, command_str: &str, sink: &mut dyn OutputSink, timeout: Option<CommandTimeout>) -> Result<ExecutionResult, ExecutorError> {
+    if command_str.trim().is_empty() {
+        return Err(ExecutorError::ExecutionError(
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Empty command")
+        ));
+    }
+
+    // Same stdio setup as `Command::output`
+    let mut child = retry_spawn(|| {
+        let mut command = shell.command(command_str);
+        command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        // Its own process group lets a timeout signal everything the command started;
+        // it no longer gets the terminal's Ctrl-C, so `SignalForwarding` passes that on
+        #[cfg(unix)]
+        if timeout.is_some() {
+            use std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+        command.spawn()
+    })
+    .map_err(|e| shell.launch_error(e))?;
     #[cfg(unix)]
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(command_str)
-        .output()?;
+    let forwarding = timeout.is_some().then(|| SignalForwarding::start(&child));
 
-    #[cfg(windows)]
-    let output = Command::new("cmd")
-        .arg("/C")
-        .arg(command_str)
-        .output()?;
+    let (sender, receiver) = mpsc::channel();
+    let readers = [
+        spawn_reader(child.stdout.take().expect("stdout is piped"), false, sender.clone()),
+        spawn_reader(child.stderr.take().expect("stderr is piped"), true, sender),
+    ];
 
-    Ok(ExecutionResult::from_output(output))
+    let mut enforcer = timeout.map(TimeoutEnforcer::new);
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let mut omitted_bytes = 0;
+    let mut sink_ok = true;
+    loop {
+        let message = match enforcer.as_ref().and_then(TimeoutEnforcer::time_left) {
+            Some(time_left) => receiver.recv_timeout(time_left),
+            None => receiver.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        let (is_stderr, chunk) = match message {
+            Ok(message) => message,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if let Some(ref mut enforcer) = enforcer {
+                    enforcer.enforce(&mut child);
+                }
+                continue;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        if sink_ok {
+            sink_ok = sink.write_chunk(is_stderr, &chunk).is_ok();
+        }
+        // Once the sink fails, memory holds the only copy, so keep all of it from then on
+        let limit = (sink_ok && sink.keeps_output()).then_some(STREAMED_TAIL_BYTES);
+        push_bounded(if is_stderr { &mut stderr } else { &mut stdout }, &chunk, limit, &mut omitted_bytes);
+    }
+    for reader in readers {
+        let _ = reader.join();
+    }
+
+    // The command may close its output and keep running, so the timeout still applies
+    let status = match enforcer {
+        None => child.wait()?,
+        Some(ref mut enforcer) => loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            enforcer.enforce(&mut child);
+            std::thread::sleep(Duration::from_millis(10));
+        },
+    };
+    #[cfg(unix)]
+    if let Some(forwarding) = forwarding {
+        forwarding.finish();
+    }
+    let mut result = ExecutionResult::from_output(Output { status, stdout, stderr });
+    result.omitted_bytes = omitted_bytes;
+    if enforcer.is_some_and(|enforcer| enforcer.timed_out) {
+        result.exit_code = TIMEOUT_EXIT_CODE;
+        result.timed_out = true;
+    }
+    Ok(result)
+}
+
+/// Forwards everything read from `stream` to `sender` in chunks, tagged with `is_stderr`.
+/// Chunks are raw bytes and may end inside a multibyte character; they are only decoded
+/// once the whole stream is collected, so split characters come out intact.
+fn spawn_reader(mut stream: impl Read + Send + 'static, is_stderr: bool, sender: mpsc::Sender<(bool, Vec<u8>)>) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 8192];
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if sender.send((is_stderr, buffer[..n].to_vec())).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Runs a command string with the terminal's stdin, stdout and stderr, without capturing
+/// anything, and returns its exit code (-1 if it was killed by a signal).
+pub fn execute_passthrough(shell: &Shell, command_str: &str) -> Result<i32, ExecutorError> {
+    if command_str.trim().is_empty() {
+        return Err(ExecutorError::ExecutionError(
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Empty command")
+        ));
+    }
+
+    let status = retry_spawn(|| shell.command(command_str).status()).map_err(|e| shell.launch_error(e))?;
+    Ok(status.code().unwrap_or(-1))
+}
+
+/// Returns true if a command that exited with `exit_code` should be run again.
+/// Successful runs and runs killed by a signal (exit code -1) are never retried. An
+/// empty `retry_on_exit_codes` list means "any other nonzero exit code".
+pub fn should_retry(exit_code: i32, retry_on_exit_codes: &[i32]) -> bool {
+    if exit_code == 0 || exit_code == -1 {
+        return false;
+    }
+    retry_on_exit_codes.is_empty() || retry_on_exit_codes.contains(&exit_code)
+}
+
+/// Runs a command string, re-running it up to `max_retries` times while `should_retry`
+/// holds. Returns the result and the number of attempts made. The result holds the
+/// final attempt's output, or every attempt's output under a header when
+/// `keep_all_attempts` is set. With a `sink`, the output of every attempt is also
+/// streamed to it as it arrives. With a `timeout`, each attempt is stopped once it runs
+/// too long; an attempt that timed out is not retried.
+pub fn execute_with_retries(
+    shell: &Shell,
+    command_str: &str,
+    max_retries: u32,
+    retry_on_exit_codes: &[i32],
+    keep_all_attempts: bool,
+    mut sink: Option<&mut dyn OutputSink>,
+    timeout: Option<CommandTimeout>,
+) -> Result<(ExecutionResult, u32), ExecutorError> {
+    let mut attempts = Vec::new();
+    loop {
+        let result = match (sink.as_mut(), timeout) {
+            (Some(sink), timeout) => {
+                let result = run_captured(shell, command_str, &mut **sink, timeout)?;
+                sink.finish_attempt(result.exit_code);
+                result
+            }
+            (None, Some(timeout)) => execute_command_string_with_timeout(shell, command_str, &mut std::io::sink(), timeout)?,
+            (None, None) => execute_command_string_in(shell, command_str)?,
+        };
+        // A timed-out command would most likely time out again
+        let retry = attempts.len() < max_retries as usize
+            && !result.timed_out
+            && should_retry(result.exit_code, retry_on_exit_codes);
+        attempts.push(result);
+        if !retry {
+            break;
+        }
+    }
+
+    let attempt_count = attempts.len() as u32;
+    let last = attempts.pop().expect("at least one attempt is always made");
+    if !keep_all_attempts || attempts.is_empty() {
+        return Ok((last, attempt_count));
+    }
+
+    attempts.push(last);
+    let mut combined = ExecutionResult::from_captured(0, Vec::new());
+    for (i, attempt) in attempts.into_iter().enumerate() {
+        let header = attempt_header(i + 1, attempt_count as usize, attempt.exit_code);
+        combined.stdout.push_str(&header);
+        combined.stdout.push_str(&attempt.stdout);
+        combined.stderr.push_str(&attempt.stderr);
+        combined.combined_output.push_str(&header);
+        combined.combined_output.push_str(&attempt.combined_output);
+        combined.combined_output.push('\n');
+        combined.raw_output.extend_from_slice(header.as_bytes());
+        combined.raw_output.extend_from_slice(&attempt.raw_output);
+        combined.raw_output.push(b'\n');
+        combined.exit_code = attempt.exit_code;
+        combined.timed_out = attempt.timed_out;
+        combined.omitted_bytes += attempt.omitted_bytes;
+    }
+    Ok((combined, attempt_count))
+}
+
+/// Line put before each attempt's output when all attempts are kept.
+fn attempt_header(attempt: usize, count: usize, exit_code: i32) -> String {
+    format!("=== attempt {} of {} (exit code {}) ===\n", attempt, count, exit_code)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_retry_spawn_on_transient_errors() {
+        let transient = || std::io::Error::from(std::io::ErrorKind::WouldBlock);
+
+        // Recovers once the transient error clears
+        let mut attempts = 0;
+        let result = retry_spawn(|| {
+            attempts += 1;
+            if attempts < SPAWN_ATTEMPTS { Err(transient()) } else { Ok(attempts) }
+        });
+        assert_eq!(result.unwrap(), SPAWN_ATTEMPTS);
+
+        // Gives up after SPAWN_ATTEMPTS
+        let mut attempts = 0;
+        let result: std::io::Result<()> = retry_spawn(|| {
+            attempts += 1;
+            Err(transient())
+        });
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+        assert_eq!(attempts, SPAWN_ATTEMPTS);
+
+        // Other errors, like a missing program, fail right away
+        let mut attempts = 0;
+        let result: std::io::Result<()> = retry_spawn(|| {
+            attempts += 1;
+            Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_is_transient_spawn_error() {
+        assert!(is_transient_spawn_error(&std::io::Error::from_raw_os_error(libc::EAGAIN)));
+        assert!(is_transient_spawn_error(&std::io::Error::from_raw_os_error(libc::EMFILE)));
+        assert!(!is_transient_spawn_error(&std::io::Error::from_raw_os_error(libc::ENOENT)));
+        assert!(!is_transient_spawn_error(&std::io::Error::from_raw_os_error(libc::EACCES)));
+    }
+
     #[test]
     fn test_execution_result_is_success() {
         let output = Command::new("true").output().unwrap();
@@ -104,6 +1049,324 @@ mod tests {
         assert!(!result.combined_output.is_empty());
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn test_execution_result_raw_output() {
+        let output = Command::new("sh").arg("-c").arg("printf 'a\\000b'; printf err >&2").output().unwrap();
+        let result = ExecutionResult::from_output(output);
+        assert_eq!(result.raw_output, b"a\x00b\nerr".to_vec());
+    }
+
+    #[test]
+    fn test_execution_result_from_captured() {
+        let result = ExecutionResult::from_captured(101, b"test failed\n".to_vec());
+        assert!(!result.is_success());
+        assert_eq!(result.combined_output, "test failed\n");
+        assert_eq!(result.raw_output, b"test failed\n".to_vec());
+    }
+
+    #[test]
+    fn test_output_for_prompt_failure_priority() {
+        let result = ExecutionResult {
+            exit_code: 1,
+            stdout: "progress noise\n".to_string(),
+            stderr: "error: boom\n".to_string(),
+            combined_output: "progress noise\n\nerror: boom\n".to_string(),
+            raw_output: Vec::new(),
+            timed_out: false,
+            omitted_bytes: 0,
+        };
+        let mut options = ProcessOptions::default();
+
+        assert_eq!(result.output_for_prompt(options), result.combined_output);
+        options.failure_stream_priority = OutputStream::Stderr;
+        assert_eq!(result.output_for_prompt(options), "[stderr]\nerror: boom\n\n[stdout]\nprogress noise");
+        options.failure_stream_priority = OutputStream::Stdout;
+        assert_eq!(result.output_for_prompt(options), "[stdout]\nprogress noise\n\n[stderr]\nerror: boom");
+
+        // Successful commands always use the combined output
+        let success = ExecutionResult { exit_code: 0, ..result };
+        options.failure_stream_priority = OutputStream::Stderr;
+        assert_eq!(success.output_for_prompt(options), success.combined_output);
+    }
+
+    #[test]
+    fn test_output_for_prompt_summary_streams() {
+        let result = ExecutionResult {
+            exit_code: 1,
+            stdout: "artifact line\n".to_string(),
+            stderr: "error: boom\n".to_string(),
+            combined_output: "artifact line\n\nerror: boom\n".to_string(),
+            raw_output: Vec::new(),
+            timed_out: false,
+            omitted_bytes: 0,
+        };
+        let mut options = ProcessOptions { summary_streams: OutputStream::Stderr, ..Default::default() };
+
+        assert_eq!(result.output_for_prompt(options), "error: boom\n");
+        // The stream choice wins over failure_stream_priority
+        options.failure_stream_priority = OutputStream::Stdout;
+        assert_eq!(result.output_for_prompt(options), "error: boom\n");
+        options.summary_streams = OutputStream::Stdout;
+        assert_eq!(result.output_for_prompt(options), "artifact line\n");
+
+        // An empty stream falls back to everything that was captured
+        let quiet = ExecutionResult { stdout: String::new(), combined_output: "error: boom\n".to_string(), ..result };
+        options.failure_stream_priority = OutputStream::Combined;
+        assert_eq!(quiet.output_for_prompt(options), "error: boom\n");
+        assert_eq!(quiet.stream_output(OutputStream::Stdout, options), "");
+    }
+
+    #[test]
+    fn test_display_output_applies_transformations() {
+        let output = Output {
+            status: Command::new("false").status().unwrap(),
+            stdout: b"Downloading 10%\rDownloading 100%\ndone\n".to_vec(),
+            stderr: b"warn: slow\rwarning: slow mirror\n".to_vec(),
+        };
+        let result = ExecutionResult::from_output(output);
+        let mut options = ProcessOptions::default();
+
+        // Nothing is configured by default
+        assert_eq!(result.display_output(options), result.combined_output);
+
+        options.collapse_progress = true;
+        assert_eq!(result.display_output(options), "Downloading 100%\ndone\n\nwarning: slow mirror\n");
+        options.failure_stream_priority = OutputStream::Stderr;
+        assert_eq!(result.output_for_prompt(options), "[stderr]\nwarning: slow mirror\n\n[stdout]\nDownloading 100%\ndone");
+
+        // The captured fields are untouched
+        assert!(result.combined_output.contains("Downloading 10%\r"));
+        assert!(result.raw_output.starts_with(b"Downloading 10%\r"));
+    }
+
+    #[test]
+    fn test_push_bounded() {
+        let mut buffer = Vec::new();
+        let mut omitted = 0;
+        push_bounded(&mut buffer, b"abcdef", None, &mut omitted);
+        assert_eq!((buffer.as_slice(), omitted), (b"abcdef".as_slice(), 0));
+
+        // Nothing is dropped until the buffer is twice the limit, then it keeps the limit
+        let mut buffer = Vec::new();
+        push_bounded(&mut buffer, b"abcd", Some(2), &mut omitted);
+        assert_eq!((buffer.as_slice(), omitted), (b"abcd".as_slice(), 0));
+        push_bounded(&mut buffer, b"e", Some(2), &mut omitted);
+        assert_eq!((buffer.as_slice(), omitted), (b"de".as_slice(), 3));
+
+        // The cut never splits a character
+        let mut buffer = Vec::new();
+        let mut omitted = 0;
+        push_bounded(&mut buffer, "aéé".as_bytes(), Some(2), &mut omitted);
+        assert_eq!((buffer.as_slice(), omitted), ("é".as_bytes(), 3));
+    }
+
+    #[test]
+    fn test_should_retry() {
+        assert!(!should_retry(0, &[]));
+        assert!(!should_retry(-1, &[]));
+        assert!(should_retry(1, &[]));
+        assert!(should_retry(101, &[1, 101]));
+        assert!(!should_retry(2, &[1, 101]));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_execute_with_retries() {
+        let marker = std::env::temp_dir().join(format!("ctx_guard_retry_test_{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        // Fails with exit code 3 the first time, succeeds the second time
+        let command = format!(
+            "if [ -f {0} ]; then echo passed; else touch {0}; echo flaky; exit 3; fi",
+            marker.display()
+        );
+
+        let (result, attempts) = execute_with_retries(&Shell::default(), &command, 2, &[3], false, None, None).unwrap();
+        assert_eq!(attempts, 2);
+        assert!(result.is_success());
+        assert_eq!(result.combined_output.trim(), "passed");
+
+        let _ = std::fs::remove_file(&marker);
+        let (result, attempts) = execute_with_retries(&Shell::default(), &command, 2, &[3], true, None, None).unwrap();
+        assert_eq!(attempts, 2);
+        assert!(result.is_success());
+        assert!(result.combined_output.contains("=== attempt 1 of 2 (exit code 3) ===\nflaky"));
+        assert!(result.combined_output.contains("=== attempt 2 of 2 (exit code 0) ===\npassed"));
+
+        // Exit codes that aren't listed are not retried
+        let _ = std::fs::remove_file(&marker);
+        let (result, attempts) = execute_with_retries(&Shell::default(), &command, 2, &[1], false, None, None).unwrap();
+        assert_eq!(attempts, 1);
+        assert_eq!(result.exit_code, 3);
+
+        let _ = std::fs::remove_file(&marker);
+    }
+
+    #[test]
+    fn test_timeout_signal_from_str() {
+        assert_eq!("TERM".parse::<TimeoutSignal>(), Ok(TimeoutSignal::Term));
+        assert_eq!("sigkill".parse::<TimeoutSignal>(), Ok(TimeoutSignal::Kill));
+        assert!("HUP".parse::<TimeoutSignal>().is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_execute_with_timeout() {
+        let timeout = |signal, grace_ms| CommandTimeout {
+            limit: Duration::from_millis(300),
+            signal,
+            grace: Duration::from_millis(grace_ms),
+        };
+        let run = |command: &str, timeout: CommandTimeout| {
+            let start = Instant::now();
+            let result = execute_command_string_with_timeout(&Shell::default(), command, &mut std::io::sink(), timeout).unwrap();
+            (result, start.elapsed())
+        };
+
+        // Output from before the timeout is kept, and the shell's children are stopped too
+        let (result, elapsed) = run("echo started; sleep 30; echo finished", timeout(TimeoutSignal::Term, 0));
+        assert!(result.timed_out);
+        assert_eq!(result.exit_code, TIMEOUT_EXIT_CODE);
+        assert_eq!(result.combined_output, "started\n");
+        assert!(elapsed < Duration::from_secs(10));
+
+        // A command ignoring SIGTERM is killed once the grace period is over
+        let (result, elapsed) = run("trap '' TERM; echo ignoring; while :; do sleep 0.1; done", timeout(TimeoutSignal::Term, 300));
+        assert!(result.timed_out);
+        assert!(elapsed >= Duration::from_millis(600));
+        assert!(elapsed < Duration::from_secs(10));
+
+        let (result, _) = run("trap '' TERM; while :; do sleep 0.1; done", timeout(TimeoutSignal::Kill, 0));
+        assert!(result.timed_out);
+
+        // Commands that finish in time are unaffected
+        let (result, _) = run("echo quick; exit 3", timeout(TimeoutSignal::Term, 0));
+        assert!(!result.timed_out);
+        assert_eq!(result.exit_code, 3);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_execute_command_string_streaming() {
+        let mut streamed = Vec::new();
+        let result = execute_command_string_streaming(&Shell::default(), "echo out; echo err >&2; exit 4", &mut streamed).unwrap();
+        assert_eq!(result.exit_code, 4);
+        assert_eq!(result.stdout, "out\n");
+        assert_eq!(result.stderr, "err\n");
+
+        // Both streams reach the sink, in whatever order they arrived
+        let streamed = String::from_utf8(streamed).unwrap();
+        assert!(streamed.contains("out\n") && streamed.contains("err\n"));
+        assert_eq!(streamed.len(), "out\nerr\n".len());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_streaming_keeps_characters_split_across_chunks() {
+        // The euro sign (E2 82 AC) is written in two parts, so it arrives in two reads
+        let command = r"printf 'price: \342\202'; sleep 0.2; printf '\254 5\n'";
+        let mut streamed = Vec::new();
+        let result = execute_command_string_streaming(&Shell::default(), command, &mut streamed).unwrap();
+        let captured = execute_command_string_in(&Shell::default(), command).unwrap();
+
+        assert_eq!(result.stdout, "price: \u{20ac} 5\n");
+        assert_eq!(streamed, "price: \u{20ac} 5\n".as_bytes());
+        assert_eq!(result.raw_output, captured.raw_output);
+        assert_eq!(result.combined_output, captured.combined_output);
+    }
+
+    /// Joins `parts` of the output streamed to `file`, the way a capture is rebuilt from it.
+    fn rebuild(file: &[u8], parts: &[CapturePart]) -> String {
+        let mut rebuilt = Vec::new();
+        for part in parts {
+            match *part {
+                CapturePart::Text(ref text) => rebuilt.extend_from_slice(text.as_bytes()),
+                CapturePart::Output { start, len } => rebuilt.extend_from_slice(&file[start as usize..(start + len) as usize]),
+            }
+        }
+        String::from_utf8(rebuilt).unwrap()
+    }
+
+    #[test]
+    fn test_stream_layout_parts() {
+        let mut file = Vec::new();
+        let mut capture = CaptureFile::new(&mut file);
+        for (is_stderr, chunk) in [(false, "a1\n"), (true, "e1\n"), (false, "a2\n"), (false, "a3\n")] {
+            capture.write_chunk(is_stderr, chunk.as_bytes()).unwrap();
+        }
+        capture.finish_attempt(3);
+        capture.write_chunk(false, b"b1\n").unwrap();
+        capture.finish_attempt(0);
+        let layout = capture.layout;
+
+        // Adjacent chunks of a stream are one segment
+        assert_eq!(layout.attempts.len(), 2);
+        assert_eq!(layout.attempts[0].segments.len(), 3);
+        assert_eq!(layout.attempts[0].exit_code, Some(3));
+
+        assert_eq!(rebuild(&file, &layout.parts(OutputStream::Combined, false)), "b1\n");
+        assert_eq!(
+            rebuild(&file, &layout.parts(OutputStream::Combined, true)),
+            "=== attempt 1 of 2 (exit code 3) ===\na1\na2\na3\n\ne1\n\n=== attempt 2 of 2 (exit code 0) ===\nb1\n\n"
+        );
+        assert_eq!(
+            rebuild(&file, &layout.parts(OutputStream::Stdout, true)),
+            "=== attempt 1 of 2 (exit code 3) ===\na1\na2\na3\n=== attempt 2 of 2 (exit code 0) ===\nb1\n"
+        );
+        assert_eq!(rebuild(&file, &layout.parts(OutputStream::Stderr, true)), "e1\n");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_stream_layout_matches_result() {
+        let marker = std::env::temp_dir().join(format!("ctx_guard_layout_retry_{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        let command = format!(
+            "echo out; echo err >&2; if [ -f {0} ]; then echo passed; else touch {0}; echo flaky >&2; exit 3; fi",
+            marker.display()
+        );
+
+        // The streams read back from the file are the ones the result holds
+        for keep_all_attempts in [false, true] {
+            let _ = std::fs::remove_file(&marker);
+            let mut file = Vec::new();
+            let mut capture = CaptureFile::new(&mut file);
+            let (result, _) = execute_with_retries(&Shell::default(), &command, 1, &[3], keep_all_attempts, Some(&mut capture), None).unwrap();
+            let layout = capture.layout;
+            assert_eq!(rebuild(&file, &layout.parts(OutputStream::Combined, keep_all_attempts)), result.combined_output);
+            assert_eq!(rebuild(&file, &layout.parts(OutputStream::Stdout, keep_all_attempts)), result.stdout);
+            assert_eq!(rebuild(&file, &layout.parts(OutputStream::Stderr, keep_all_attempts)), result.stderr);
+        }
+
+        let _ = std::fs::remove_file(&marker);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_resource_usage_children() {
+        let before = ResourceUsage::children().unwrap();
+        execute_command_string("i=0; while [ $i -lt 20000 ]; do i=$((i+1)); done").unwrap();
+        let after = ResourceUsage::children().unwrap();
+
+        let usage = after.since(&before);
+        assert!(usage.max_rss_kb > 0);
+        assert!(usage.user_time + usage.system_time > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_resource_usage_metadata_round_trip() {
+        let usage = ResourceUsage {
+            max_rss_kb: 2048,
+            user_time: Duration::from_millis(120),
+            system_time: Duration::from_millis(15),
+        };
+        let value = usage.to_metadata_value();
+        assert_eq!(value, "max_rss_kb=2048 user_ms=120 sys_ms=15");
+        assert_eq!(ResourceUsage::from_metadata_value(&value), Some(usage));
+        assert_eq!(ResourceUsage::from_metadata_value("garbage"), None);
+        assert_eq!(usage.to_string(), "peak memory 2.0 MB, user CPU 0.12s, system CPU 0.01s");
+    }
+
     #[test]
     fn test_execute_command_string_empty() {
         let result = execute_command_string("");
@@ -113,6 +1376,70 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn test_execute_passthrough_exit_code() {
+        let shell = Shell::default();
+        assert_eq!(execute_passthrough(&shell, "true").unwrap(), 0);
+        assert_eq!(execute_passthrough(&shell, "exit 3").unwrap(), 3);
+        assert!(execute_passthrough(&shell, " ").is_err());
+    }
+
+    #[test]
+    fn test_shell_flag_inference() {
+        assert_eq!(Shell::new("bash", None).flag, "-c");
+        assert_eq!(Shell::new("/usr/bin/zsh", None).flag, "-c");
+        assert_eq!(Shell::new("cmd", None).flag, "/C");
+        assert_eq!(Shell::new("pwsh", None).flag, "-Command");
+        assert_eq!(Shell::new("fish", Some("--command")).flag, "--command");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_shell_validate() {
+        assert!(Shell::default().validate().is_ok());
+        assert!(Shell::new("/bin/sh", None).validate().is_ok());
+        assert!(matches!(
+            Shell::new("definitely-not-a-shell", None).validate(),
+            Err(ExecutorError::ShellNotFound(_))
+        ));
+        assert!(Shell::new("/nonexistent/bash", None).validate().is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_shell_launch_failure() {
+        let error = execute_command_string_in(&Shell::new("/nonexistent/sh", None), "echo hi").err().unwrap();
+        assert!(error.is_shell_failure());
+        assert_eq!(error.exit_code(), 127);
+        assert!(error.to_string().starts_with("Failed to launch shell '/nonexistent/sh'"));
+
+        // Exists, but isn't executable
+        let not_executable = std::env::temp_dir().join(format!("cg_not_a_shell_{}", std::process::id()));
+        std::fs::write(&not_executable, "").unwrap();
+        let shell = Shell::new(not_executable.to_str().unwrap(), None);
+        for error in [
+            execute_command_string_in(&shell, "echo hi").err().unwrap(),
+            execute_command_string_streaming(&shell, "echo hi", &mut std::io::sink()).err().unwrap(),
+            execute_passthrough(&shell, "echo hi").err().unwrap(),
+        ] {
+            assert!(matches!(error, ExecutorError::ShellLaunchFailed { .. }));
+            assert_eq!(error.exit_code(), 126);
+        }
+        let _ = std::fs::remove_file(&not_executable);
+
+        assert!(!ExecutorError::ExecutionError(std::io::Error::other("read failed")).is_shell_failure());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_execute_with_custom_shell() {
+        // `$0` is the shell's own name, showing which interpreter ran the command
+        let shell = Shell::new("/bin/sh", None);
+        let result = execute_command_string_in(&shell, "echo $0").unwrap();
+        assert_eq!(result.stdout.trim(), "/bin/sh");
+    }
+
     #[test]
     fn test_execute_command_string_success() {
         #[cfg(unix)]
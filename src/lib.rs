@@ -1,5 +1,8 @@
+pub mod cache;
 pub mod config;
+pub mod error;
 pub mod executor;
 pub mod llm;
 pub mod output;
+pub mod pipeline;
 
@@ -0,0 +1,641 @@
+mod ollama;
+mod openai;
+
+pub use ollama::Ollama;
+pub use openai::OpenAiCompatible;
+
+use crate::config::ProviderConfig;
+use reqwest::Client;
+use std::sync::{Arc, Mutex, Once};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+#[derive(Debug, Error)]
+pub enum LlmError {
+    #[error("HTTP request failed: {0}")]
+    RequestError(#[from] reqwest::Error),
+    #[error("Failed to parse JSON response: {0}")]
+    ParseError(#[from] serde_json::Error),
+    #[error("No content in LLM response")]
+    NoContent,
+    #[error("Prompt is {size} characters, exceeding max_prompt_chars ({limit}); not sending it")]
+    PromptTooLarge { size: usize, limit: usize },
+    #[error("Provider returned an error: {0}")]
+    ErrorResponse(String),
+    #[error("Invalid extra_params: {0}")]
+    InvalidExtraParams(String),
+    #[error("max_requests_per_minute would delay the request by more than rate_limit_wait_secs ({0}s); not sending it")]
+    RateLimited(u64),
+}
+
+impl LlmError {
+    /// True if the provider couldn't be reached at all (nothing listening, or no answer).
+    pub fn is_unreachable(&self) -> bool {
+        matches!(self, LlmError::RequestError(e) if e.is_connect() || e.is_timeout())
+    }
+}
+
+/// Sampling settings sent with each summary request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GenerationOptions {
+    pub temperature: f32,
+    pub max_tokens: u32,
+}
+
+impl Default for GenerationOptions {
+    fn default() -> Self {
+        Self { temperature: 0.7, max_tokens: 500 }
+    }
+}
+
+/// A backend API for generating summaries. Each implementation only knows its own
+/// request and response formats; `LlmClient` handles the HTTP transport.
+pub trait Provider: Send + Sync {
+    /// Full URL of the completion endpoint for the given base URL.
+    fn endpoint(&self, base_url: &str) -> String;
+    /// JSON body of a request asking `model` to respond to `prompt`.
+    fn build_request(&self, model: &str, prompt: &str, options: &GenerationOptions) -> serde_json::Value;
+    /// Extracts the generated text of every choice in a response body, in order and
+    /// trimmed. APIs without choices return a single entry.
+    fn parse_choices(&self, body: serde_json::Value) -> Result<Vec<String>, LlmError>;
+    /// Extracts the generated text from a response body: the first non-empty choice.
+    fn parse_response(&self, body: serde_json::Value) -> Result<String, LlmError> {
+        self.parse_choices(body)?
+            .into_iter()
+            .find(|choice| !choice.is_empty())
+            .ok_or(LlmError::NoContent)
+    }
+    /// Joins the generated text of a complete streamed response body.
+    fn parse_stream(&self, body: &str) -> Result<String, LlmError>;
+}
+
+/// Returns the backend for a `type` value from the provider config.
+pub fn provider_for_type(provider_type: &str) -> Option<Arc<dyn Provider>> {
+    match provider_type.to_ascii_lowercase().as_str() {
+        "lmstudio" | "openai" | "openai-compatible" => Some(Arc::new(OpenAiCompatible)),
+        "ollama" => Some(Arc::new(Ollama)),
+        _ => None,
+    }
+}
+
+/// Extracts the message of an error-shaped response body: `{"error": "..."}` or
+/// `{"error": {"message": "..."}}`, as returned by OpenAI-compatible servers and Ollama.
+fn error_message(body: &serde_json::Value) -> Option<String> {
+    let error = body.get("error")?;
+    let message = match error {
+        serde_json::Value::String(message) => message.clone(),
+        serde_json::Value::Object(fields) => match fields.get("message") {
+            Some(serde_json::Value::String(message)) => message.clone(),
+            _ => error.to_string(),
+        },
+        serde_json::Value::Null => return None,
+        other => other.to_string(),
+    };
+    Some(message)
+}
+
+/// Prompt for the check pass of `summarize_and_verify`.
+const VERIFY_PROMPT: &str = "You wrote the summary below for the command output that follows it. \
+Check whether the summary omits or misstates any critical error, failure or warning in the output. \
+If it does, reply with a corrected summary of similar length. If it doesn't, reply with the summary unchanged. \
+Reply with the summary only.
+
+Summary:
+${summary}
+
+Output:
+${output}";
+
+/// Request fields `extra_params` may not set, since the request depends on them.
+const RESERVED_REQUEST_FIELDS: &[&str] = &["model", "messages", "stream"];
+
+/// Converts a provider's `extra_params` to the JSON object merged into each request.
+fn extra_params_object(params: &toml::Table) -> Result<serde_json::Map<String, serde_json::Value>, LlmError> {
+    let object = match serde_json::to_value(params).map_err(|e| LlmError::InvalidExtraParams(e.to_string()))? {
+        serde_json::Value::Object(object) => object,
+        other => return Err(LlmError::InvalidExtraParams(format!("expected a table, got {}", other))),
+    };
+    if let Some(field) = RESERVED_REQUEST_FIELDS.iter().find(|field| object.contains_key(**field)) {
+        return Err(LlmError::InvalidExtraParams(format!("'{}' is set by ctx_guard and can't be overridden", field)));
+    }
+    Ok(object)
+}
+
+/// Merges `extra` into `request`. Objects present in both (like Ollama's `options`) are
+/// merged key by key, so extra sampling options don't drop the configured ones.
+fn merge_extra_params(request: &mut serde_json::Value, extra: &serde_json::Map<String, serde_json::Value>) {
+    let Some(fields) = request.as_object_mut() else {
+        return;
+    };
+    for (key, value) in extra {
+        match (fields.get_mut(key), value) {
+            (Some(serde_json::Value::Object(existing)), serde_json::Value::Object(additions)) => {
+                existing.extend(additions.iter().map(|(k, v)| (k.clone(), v.clone())));
+            }
+            _ => {
+                fields.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Appended to the prompt when asking again after an empty summary.
+const EMPTY_SUMMARY_NUDGE: &str = "Provide a concise summary of the above output.";
+
+/// Drops a reasoning model's thinking: everything up to and including the first `</think>`.
+pub fn strip_think_blocks(s: &str) -> String {
+    if let Some(end) = s.find("</think>") {
+        // Drop everything up to and including the first closing </think> tag.
+        s[end + "</think>".len()..].trim().to_string()
+    } else {
+        s.to_string()
+    }
+}
+
+/// True if an error message says the server only accepts streaming requests: it has
+/// "stream" or "streaming" as a word (so "upstream connect error" doesn't count).
+fn mentions_streaming(message: &str) -> bool {
+    message
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .any(|word| word.eq_ignore_ascii_case("stream") || word.eq_ignore_ascii_case("streaming"))
+}
+
+/// True if the response is a stream (server-sent events or newline-delimited JSON).
+fn is_stream_response(response: &reqwest::Response) -> bool {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/event-stream") || content_type.starts_with("application/x-ndjson"))
+}
+
+/// Shortened response body for error messages.
+pub(crate) fn body_excerpt(body: &serde_json::Value) -> String {
+    const MAX_CHARS: usize = 200;
+    let text = body.to_string();
+    match text.char_indices().nth(MAX_CHARS) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+/// Token bucket for `max_requests_per_minute`: holds up to a minute's worth of requests,
+/// refilled continuously, and each request takes one token. Requests that find it empty
+/// reserve a later token, so waiting requests go out evenly spaced.
+#[derive(Debug)]
+struct RateLimiter {
+    capacity: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    fn per_minute(limit: u32) -> Self {
+        Self {
+            capacity: limit as f64,
+            tokens: limit as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes a token for a request at `now` and returns how long the request must wait
+    /// for it. Takes nothing and returns None if the wait would be longer than `max_wait`.
+    fn reserve(&mut self, now: Instant, max_wait: Duration) -> Option<Duration> {
+        let per_second = self.capacity / 60.0;
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * per_second).min(self.capacity);
+        self.refilled_at = self.refilled_at.max(now);
+
+        let wait = if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / per_second)
+        };
+        if wait > max_wait {
+            return None;
+        }
+        self.tokens -= 1.0;
+        Some(wait)
+    }
+}
+
+/// Client for one provider. Clones share the HTTP connection pool and the limits on
+/// concurrent requests and request rate, so a clone can be handed to each task of a batch.
+#[derive(Clone)]
+pub struct LlmClient {
+    client: Client,
+    provider: Arc<dyn Provider>,
+    base_url: String,
+    /// Replaces the provider's default endpoint path when set
+    chat_path: Option<String>,
+    options: GenerationOptions,
+    /// Hard cap on prompt size; 0 disables the check
+    max_prompt_chars: usize,
+    /// Permits for in-flight requests, shared between clones
+    request_permits: Arc<Semaphore>,
+    /// Limits summary requests per minute when set, shared between clones
+    rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    /// Longest a request waits for the rate limit before giving up
+    rate_limit_wait: Duration,
+    /// Retry with `stream: true` when the server rejects non-streaming requests
+    stream_fallback: bool,
+    /// Ask once more, with `EMPTY_SUMMARY_NUDGE`, when the model returns nothing
+    retry_empty_summary: bool,
+    /// Provider-specific fields merged into every request body
+    extra_params: serde_json::Map<String, serde_json::Value>,
+    /// Report fallbacks on stderr
+    verbose: bool,
+}
+
+impl LlmClient {
+    /// Creates a client for an OpenAI-compatible API.
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            client: Client::new(),
+            provider: Arc::new(OpenAiCompatible),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            chat_path: None,
+            options: GenerationOptions::default(),
+            max_prompt_chars: 0,
+            request_permits: Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+            rate_limiter: None,
+            rate_limit_wait: Duration::ZERO,
+            stream_fallback: true,
+            retry_empty_summary: false,
+            extra_params: serde_json::Map::new(),
+            verbose: false,
+        }
+    }
+
+    /// Creates a client using the connection and safety settings of a provider.
+    pub fn from_provider(provider: &ProviderConfig) -> Result<Self, LlmError> {
+        // Any type used to mean the OpenAI-compatible API, so unknown types keep working
+        let backend = provider_for_type(&provider.r#type).unwrap_or_else(|| {
+            static WARN_UNKNOWN_TYPE: Once = Once::new();
+            WARN_UNKNOWN_TYPE.call_once(|| {
+                eprintln!(
+                    "Warning: Unknown provider type '{}' (expected lmstudio, openai, openai-compatible or ollama); using the OpenAI chat completions API",
+                    provider.r#type
+                );
+            });
+            Arc::new(OpenAiCompatible)
+        });
+        Ok(Self {
+            provider: backend,
+            chat_path: provider.chat_path.clone(),
+            options: GenerationOptions {
+                temperature: provider.temperature,
+                max_tokens: provider.max_tokens,
+            },
+            max_prompt_chars: provider.max_prompt_chars as usize,
+            request_permits: Arc::new(Semaphore::new(match provider.max_concurrent_requests {
+                0 => Semaphore::MAX_PERMITS,
+                limit => limit as usize,
+            })),
+            rate_limiter: (provider.max_requests_per_minute > 0)
+                .then(|| Arc::new(Mutex::new(RateLimiter::per_minute(provider.max_requests_per_minute)))),
+            rate_limit_wait: Duration::from_secs(provider.rate_limit_wait_secs),
+            stream_fallback: provider.stream_fallback,
+            retry_empty_summary: provider.retry_empty_summary,
+            extra_params: extra_params_object(&provider.extra_params)?,
+            ..Self::new(&provider.url)
+        })
+    }
+
+    /// Reports on stderr when a request falls back to streaming.
+    pub fn with_verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Sends `prompt` to `model` and returns the generated text. With
+    /// `retry_empty_summary`, an empty answer (or one with only thinking) is retried
+    /// once with a nudge appended to the prompt. HTTP errors are never retried here.
+    pub async fn summarize(&self, model: &str, prompt: &str) -> Result<String, LlmError> {
+        let result = self.request_summary(model, prompt).await;
+        let empty = match result {
+            Err(LlmError::NoContent) => true,
+            Ok(ref text) => strip_think_blocks(text).is_empty(),
+            Err(_) => false,
+        };
+        if !(empty && self.retry_empty_summary) {
+            return result;
+        }
+
+        if self.verbose {
+            eprintln!("The model returned an empty summary; asking once more");
+        }
+        let nudged_prompt = format!("{}\n\n{}", prompt.trim_end(), EMPTY_SUMMARY_NUDGE);
+        self.request_summary(model, &nudged_prompt).await
+    }
+
+    /// Sends a single summary request, falling back to streaming if the server needs it.
+    async fn request_summary(&self, model: &str, prompt: &str) -> Result<String, LlmError> {
+        self.check_prompt_size(prompt)?;
+        self.wait_for_rate_limit().await?;
+
+        // The semaphore is never closed, so acquiring can't fail
+        let _permit = self.request_permits.acquire().await.expect("request semaphore is never closed");
+
+        let url = self.endpoint();
+        let request = self.build_request(model, prompt, &self.options);
+
+        let response = self
+            .client
+            .post(&url)
+            .json(&request)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error = response.error_for_status_ref().unwrap_err();
+            // Streaming-only servers reject `stream: false` with an error saying so
+            if self.stream_fallback && mentions_streaming(&response.text().await.unwrap_or_default()) {
+                return self.summarize_streaming(&url, request).await;
+            }
+            return Err(LlmError::RequestError(error));
+        }
+
+        // Others ignore `stream: false` and answer with a stream anyway
+        if is_stream_response(&response) {
+            return self.provider.parse_stream(&response.text().await?);
+        }
+
+        // Some servers report failures (e.g. no model loaded) with a 200 and an error body
+        let body: serde_json::Value = response.json().await?;
+        if let Some(message) = error_message(&body) {
+            if self.stream_fallback && mentions_streaming(&message) {
+                return self.summarize_streaming(&url, request).await;
+            }
+            return Err(LlmError::ErrorResponse(message));
+        }
+        self.provider.parse_response(body)
+    }
+
+    /// Waits until `max_requests_per_minute` allows another request, or fails right away
+    /// if that would take longer than `rate_limit_wait_secs`.
+    async fn wait_for_rate_limit(&self) -> Result<(), LlmError> {
+        let Some(ref limiter) = self.rate_limiter else {
+            return Ok(());
+        };
+        let wait = limiter
+            .lock()
+            .expect("rate limiter lock is never poisoned")
+            .reserve(Instant::now(), self.rate_limit_wait)
+            .ok_or(LlmError::RateLimited(self.rate_limit_wait.as_secs()))?;
+        if !wait.is_zero() {
+            if self.verbose {
+                eprintln!("Waiting {:.1} seconds for max_requests_per_minute", wait.as_secs_f64());
+            }
+            tokio::time::sleep(wait).await;
+        }
+        Ok(())
+    }
+
+    /// Summarizes like `summarize`, then sends the summary back with `output` asking the
+    /// model to correct it if it omits a critical error. Costs a second request; if that
+    /// request fails, the first summary is returned.
+    pub async fn summarize_and_verify(&self, model: &str, prompt: &str, output: &str) -> Result<String, LlmError> {
+        let summary = strip_think_blocks(&self.summarize(model, prompt).await?);
+        let check_prompt = VERIFY_PROMPT.replace("${summary}", &summary).replace("${output}", output);
+
+        match self.summarize(model, &check_prompt).await {
+            Ok(checked) if !strip_think_blocks(&checked).is_empty() => Ok(checked),
+            Ok(_) => Ok(summary),
+            Err(e) => {
+                if self.verbose {
+                    eprintln!("Summary check failed, keeping the first summary: {}", e);
+                }
+                Ok(summary)
+            }
+        }
+    }
+
+    /// Sends `request` again with streaming enabled and joins the streamed text.
+    async fn summarize_streaming(&self, url: &str, mut request: serde_json::Value) -> Result<String, LlmError> {
+        if self.verbose {
+            eprintln!("Provider rejected a non-streaming request; retrying with stream: true");
+        }
+        request["stream"] = serde_json::Value::Bool(true);
+
+        let response = self.client.post(url).json(&request).send().await?.error_for_status()?;
+        self.provider.parse_stream(&response.text().await?)
+    }
+
+    /// Sends a minimal request so the server loads the model, letting a later
+    /// `summarize` skip the cold start. The generated text is discarded.
+    pub async fn warm_up(&self, model: &str) -> Result<(), LlmError> {
+        let url = self.endpoint();
+        let options = GenerationOptions { max_tokens: 1, ..self.options };
+        let request = self.build_request(model, "Reply with OK.", &options);
+
+        self.client.post(&url).json(&request).send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Checks that something answers at the provider's base URL. Any HTTP response
+    /// counts, since servers differ in what they serve there.
+    pub async fn check_reachable(&self) -> Result<(), LlmError> {
+        self.client.get(&self.base_url).send().await?;
+        Ok(())
+    }
+
+    /// The provider's request body with the configured `extra_params` merged in.
+    fn build_request(&self, model: &str, prompt: &str, options: &GenerationOptions) -> serde_json::Value {
+        let mut request = self.provider.build_request(model, prompt, options);
+        merge_extra_params(&mut request, &self.extra_params);
+        request
+    }
+
+    /// URL requests are sent to: the configured `chat_path` under the base URL, or the
+    /// provider's default endpoint.
+    fn endpoint(&self) -> String {
+        match self.chat_path {
+            Some(ref path) => format!("{}/{}", self.base_url, path.trim_start_matches('/')),
+            None => self.provider.endpoint(&self.base_url),
+        }
+    }
+
+    fn check_prompt_size(&self, prompt: &str) -> Result<(), LlmError> {
+        if self.max_prompt_chars == 0 {
+            return Ok(());
+        }
+
+        let size = prompt.chars().count();
+        if size > self.max_prompt_chars {
+            return Err(LlmError::PromptTooLarge { size, limit: self.max_prompt_chars });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mentions_streaming() {
+        assert!(mentions_streaming("Only stream=true is supported"));
+        assert!(mentions_streaming("Streaming is not supported for this model"));
+        assert!(mentions_streaming("\"stream\" must be true"));
+        assert!(!mentions_streaming("upstream connect error or disconnect/reset before headers"));
+        assert!(!mentions_streaming("downstream service unavailable"));
+    }
+
+    #[test]
+    fn test_rate_limiter_reserve() {
+        let mut limiter = RateLimiter::per_minute(60);
+        let start = Instant::now();
+        let max_wait = Duration::from_secs(60);
+
+        // A full bucket lets a minute's worth through at once
+        for _ in 0..60 {
+            assert_eq!(limiter.reserve(start, max_wait), Some(Duration::ZERO));
+        }
+        // Then one token comes back every second, and waiting requests queue up
+        assert_eq!(limiter.reserve(start, max_wait), Some(Duration::from_secs(1)));
+        assert_eq!(limiter.reserve(start, max_wait), Some(Duration::from_secs(2)));
+        // A request that would wait too long takes nothing
+        assert_eq!(limiter.reserve(start, Duration::from_millis(2500)), None);
+        let second_later = start + Duration::from_secs(1);
+        assert_eq!(limiter.reserve(second_later, Duration::from_millis(2500)), Some(Duration::from_secs(2)));
+
+        // An idle bucket refills, but only up to its capacity
+        let mut limiter = RateLimiter::per_minute(60);
+        let later = start + Duration::from_secs(600);
+        for _ in 0..60 {
+            assert_eq!(limiter.reserve(later, Duration::ZERO), Some(Duration::ZERO));
+        }
+        assert_eq!(limiter.reserve(later, Duration::ZERO), None);
+    }
+
+    #[test]
+    fn test_llm_client_new() {
+        let client = LlmClient::new("http://127.0.0.1:1234");
+        assert_eq!(client.base_url, "http://127.0.0.1:1234");
+    }
+
+    #[test]
+    fn test_llm_client_new_with_trailing_slash() {
+        let client = LlmClient::new("http://127.0.0.1:1234/");
+        assert_eq!(client.base_url, "http://127.0.0.1:1234");
+    }
+
+    #[test]
+    fn test_llm_client_from_provider() {
+        let provider = ProviderConfig {
+            url: "http://127.0.0.1:1234/".to_string(),
+            max_prompt_chars: 10,
+            ..Default::default()
+        };
+        let client = LlmClient::from_provider(&provider).unwrap();
+        assert_eq!(client.base_url, "http://127.0.0.1:1234");
+        assert_eq!(client.max_prompt_chars, 10);
+        assert_eq!(client.options, GenerationOptions::default());
+    }
+
+    #[tokio::test]
+    async fn test_summarize_rejects_oversized_prompt() {
+        // Nothing listens here; the size check must fail before any request is made
+        let provider = ProviderConfig {
+            url: "http://127.0.0.1:9".to_string(),
+            max_prompt_chars: 10,
+            ..Default::default()
+        };
+        let client = LlmClient::from_provider(&provider).unwrap();
+
+        let result = client.summarize("model", "this prompt is longer than ten characters").await;
+        assert!(matches!(result, Err(LlmError::PromptTooLarge { size: 41, limit: 10 })));
+    }
+
+    #[test]
+    fn test_chat_path_overrides_endpoint() {
+        let provider = ProviderConfig {
+            url: "http://gateway.local/".to_string(),
+            chat_path: Some("/api/v1/chat/completions".to_string()),
+            ..Default::default()
+        };
+        let client = LlmClient::from_provider(&provider).unwrap();
+        assert_eq!(client.endpoint(), "http://gateway.local/api/v1/chat/completions");
+    }
+
+    #[test]
+    fn test_strip_think_blocks() {
+        assert_eq!(strip_think_blocks("<think>reasoning</think>\n\nBuild passed."), "Build passed.");
+        assert_eq!(strip_think_blocks("Build passed."), "Build passed.");
+    }
+
+    #[test]
+    fn test_from_provider_selects_backend() {
+        let provider = ProviderConfig {
+            r#type: "ollama".to_string(),
+            url: "http://127.0.0.1:11434".to_string(),
+            ..Default::default()
+        };
+        let client = LlmClient::from_provider(&provider).unwrap();
+        assert_eq!(client.endpoint(), "http://127.0.0.1:11434/api/chat");
+
+        // Unknown types fall back to the OpenAI-compatible API, with a warning
+        let provider = ProviderConfig { r#type: "carrier-pigeon".to_string(), ..Default::default() };
+        let client = LlmClient::from_provider(&provider).unwrap();
+        assert_eq!(client.endpoint(), "http://127.0.0.1:1234/v1/chat/completions");
+    }
+
+    #[test]
+    fn test_extra_params_validation_and_merge() {
+        use serde_json::json;
+
+        let mut provider = ProviderConfig { r#type: "ollama".to_string(), ..Default::default() };
+        provider.extra_params = toml::toml! {
+            keep_alive = "10m"
+            options = { top_p = 0.5 }
+        };
+        let client = LlmClient::from_provider(&provider).unwrap();
+        let request = client.build_request("model", "prompt", &GenerationOptions::default());
+        assert_eq!(request["keep_alive"], json!("10m"));
+        assert_eq!(request["options"]["top_p"], json!(0.5));
+        assert_eq!(request["options"]["num_predict"], json!(500));
+        assert_eq!(request["model"], json!("model"));
+
+        provider.extra_params = toml::toml! { model = "other" };
+        assert!(matches!(
+            LlmClient::from_provider(&provider),
+            Err(LlmError::InvalidExtraParams(ref reason)) if reason.contains("'model'")
+        ));
+    }
+
+    #[test]
+    fn test_error_message() {
+        use serde_json::json;
+
+        assert_eq!(
+            error_message(&json!({ "error": { "message": "No models loaded", "type": "invalid_request_error" } })),
+            Some("No models loaded".to_string())
+        );
+        assert_eq!(error_message(&json!({ "error": "model 'x' not found" })), Some("model 'x' not found".to_string()));
+        assert_eq!(error_message(&json!({ "error": null, "choices": [] })), None);
+        assert_eq!(error_message(&json!({ "choices": [] })), None);
+    }
+
+    #[test]
+    fn test_provider_for_type() {
+        assert!(provider_for_type("lmstudio").is_some());
+        assert!(provider_for_type("OpenAI").is_some());
+        assert!(provider_for_type("ollama").is_some());
+        assert!(provider_for_type("unknown").is_none());
+    }
+
+    #[test]
+    fn test_check_prompt_size_disabled() {
+        let client = LlmClient::new("http://127.0.0.1:1234");
+        assert!(client.check_prompt_size(&"x".repeat(10_000)).is_ok());
+    }
+
+    #[test]
+    fn test_llm_client_new_with_path() {
+        let client = LlmClient::new("http://127.0.0.1:1234/v1");
+        assert_eq!(client.base_url, "http://127.0.0.1:1234/v1");
+    }
+}
+
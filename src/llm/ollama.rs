@@ -0,0 +1,122 @@
+use super::{error_message, GenerationOptions, LlmError, Provider};
+use serde::{Deserialize, Serialize};
+
+/// Ollama's native chat API (`/api/chat`), with streaming disabled.
+pub struct Ollama;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatMessage {
+    role: String,
+    content: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatOptions {
+    temperature: f32,
+    num_predict: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    stream: bool,
+    options: ChatOptions,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatResponse {
+    message: Option<ChatMessage>,
+}
+
+impl Provider for Ollama {
+    fn endpoint(&self, base_url: &str) -> String {
+        format!("{}/api/chat", base_url)
+    }
+
+    fn build_request(&self, model: &str, prompt: &str, options: &GenerationOptions) -> serde_json::Value {
+        let request = ChatRequest {
+            model: model.to_string(),
+            messages: vec![ChatMessage {
+                role: "user".to_string(),
+                content: prompt.to_string(),
+            }],
+            stream: false,
+            options: ChatOptions {
+                temperature: options.temperature,
+                num_predict: options.max_tokens,
+            },
+        };
+        serde_json::to_value(request).expect("chat request is always serializable")
+    }
+
+    fn parse_choices(&self, body: serde_json::Value) -> Result<Vec<String>, LlmError> {
+        let chat_response: ChatResponse = serde_json::from_value(body)?;
+        Ok(chat_response.message.map(|message| message.content.trim().to_string()).into_iter().collect())
+    }
+
+    /// Newline-delimited JSON, one partial `message` per line.
+    fn parse_stream(&self, body: &str) -> Result<String, LlmError> {
+        let mut content = String::new();
+        for line in body.lines().filter(|line| !line.trim().is_empty()) {
+            let chunk: serde_json::Value = serde_json::from_str(line)?;
+            if let Some(message) = error_message(&chunk) {
+                return Err(LlmError::ErrorResponse(message));
+            }
+            let chunk: ChatResponse = serde_json::from_value(chunk)?;
+            if let Some(message) = chunk.message {
+                content.push_str(&message.content);
+            }
+        }
+
+        match content.trim() {
+            "" => Err(LlmError::NoContent),
+            text => Ok(text.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_endpoint() {
+        assert_eq!(Ollama.endpoint("http://127.0.0.1:11434"), "http://127.0.0.1:11434/api/chat");
+    }
+
+    #[test]
+    fn test_build_request() {
+        let request = Ollama.build_request("llama3", "summarize this", &GenerationOptions { temperature: 0.0, max_tokens: 120 });
+        assert_eq!(request["model"], "llama3");
+        assert_eq!(request["stream"], false);
+        assert_eq!(request["messages"][0]["content"], "summarize this");
+        assert_eq!(request["options"]["num_predict"], 120);
+        assert_eq!(request["options"]["temperature"], 0.0);
+    }
+
+    #[test]
+    fn test_parse_stream() {
+        let body = concat!(
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"All \"},\"done\":false}\n",
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"green.\"},\"done\":false}\n",
+            "{\"done\":true}\n"
+        );
+        assert_eq!(Ollama.parse_stream(body).unwrap(), "All green.");
+        assert!(matches!(Ollama.parse_stream("{\"done\":true}\n"), Err(LlmError::NoContent)));
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let body = json!({
+            "model": "llama3",
+            "message": { "role": "assistant", "content": "Tests passed.\n" },
+            "done": true
+        });
+        assert_eq!(Ollama.parse_response(body).unwrap(), "Tests passed.");
+
+        let empty = json!({ "model": "llama3", "done": true });
+        assert!(matches!(Ollama.parse_response(empty), Err(LlmError::NoContent)));
+    }
+}
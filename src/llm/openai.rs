@@ -0,0 +1,174 @@
+use super::{GenerationOptions, body_excerpt, error_message, LlmError, Provider};
+use serde::{Deserialize, Serialize};
+
+/// OpenAI chat completions API, also served by LM Studio and most local servers.
+pub struct OpenAiCompatible;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatMessage {
+    role: String,
+    content: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    temperature: f32,
+    max_tokens: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatChoice {
+    message: ChatMessage,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatResponse {
+    choices: Vec<ChatChoice>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ChatDelta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChoice {
+    #[serde(default)]
+    delta: ChatDelta,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+}
+
+impl Provider for OpenAiCompatible {
+    fn endpoint(&self, base_url: &str) -> String {
+        format!("{}/v1/chat/completions", base_url)
+    }
+
+    fn build_request(&self, model: &str, prompt: &str, options: &GenerationOptions) -> serde_json::Value {
+        let request = ChatRequest {
+            model: model.to_string(),
+            messages: vec![ChatMessage {
+                role: "user".to_string(),
+                content: prompt.to_string(),
+            }],
+            temperature: options.temperature,
+            max_tokens: options.max_tokens,
+        };
+        serde_json::to_value(request).expect("chat request is always serializable")
+    }
+
+    fn parse_choices(&self, body: serde_json::Value) -> Result<Vec<String>, LlmError> {
+        if body.get("choices").is_none() {
+            return Err(LlmError::ErrorResponse(format!("response has no choices: {}", body_excerpt(&body))));
+        }
+        let chat_response: ChatResponse = serde_json::from_value(body)?;
+        Ok(chat_response.choices.into_iter().map(|choice| choice.message.content.trim().to_string()).collect())
+    }
+
+    /// Server-sent events: `data: {chunk}` lines ending with `data: [DONE]`.
+    fn parse_stream(&self, body: &str) -> Result<String, LlmError> {
+        let mut content = String::new();
+        for line in body.lines() {
+            let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+                continue;
+            };
+            if data == "[DONE]" {
+                break;
+            }
+            let chunk: serde_json::Value = serde_json::from_str(data)?;
+            if let Some(message) = error_message(&chunk) {
+                return Err(LlmError::ErrorResponse(message));
+            }
+            let chunk: StreamChunk = serde_json::from_value(chunk)?;
+            if let Some(text) = chunk.choices.into_iter().next().and_then(|choice| choice.delta.content) {
+                content.push_str(&text);
+            }
+        }
+
+        match content.trim() {
+            "" => Err(LlmError::NoContent),
+            text => Ok(text.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_endpoint() {
+        assert_eq!(OpenAiCompatible.endpoint("http://127.0.0.1:1234"), "http://127.0.0.1:1234/v1/chat/completions");
+    }
+
+    #[test]
+    fn test_build_request() {
+        let request = OpenAiCompatible.build_request("qwen", "summarize this", &GenerationOptions { temperature: 0.0, max_tokens: 120 });
+        assert_eq!(request["model"], "qwen");
+        assert_eq!(request["messages"][0]["role"], "user");
+        assert_eq!(request["messages"][0]["content"], "summarize this");
+        assert_eq!(request["max_tokens"], 120);
+        assert_eq!(request["temperature"], 0.0);
+    }
+
+    #[test]
+    fn test_parse_stream() {
+        let body = concat!(
+            "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Build \"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"passed.\"}}]}\n\n",
+            "data: [DONE]\n\n"
+        );
+        assert_eq!(OpenAiCompatible.parse_stream(body).unwrap(), "Build passed.");
+        assert!(matches!(OpenAiCompatible.parse_stream("data: [DONE]\n"), Err(LlmError::NoContent)));
+        assert!(matches!(
+            OpenAiCompatible.parse_stream("data: {\"error\":{\"message\":\"overloaded\"}}\n"),
+            Err(LlmError::ErrorResponse(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let body = json!({
+            "choices": [{ "message": { "role": "assistant", "content": "  Build succeeded.\n" } }]
+        });
+        assert_eq!(OpenAiCompatible.parse_response(body).unwrap(), "Build succeeded.");
+
+        let empty = json!({ "choices": [] });
+        assert!(matches!(OpenAiCompatible.parse_response(empty), Err(LlmError::NoContent)));
+
+        let malformed = json!({ "object": "list", "data": [] });
+        match OpenAiCompatible.parse_response(malformed) {
+            Err(LlmError::ErrorResponse(message)) => assert!(message.contains("no choices")),
+            other => panic!("expected an error response, got {:?}", other),
+        }
+
+        let bad_choice = json!({ "choices": [{ "text": "legacy completion" }] });
+        assert!(matches!(OpenAiCompatible.parse_response(bad_choice), Err(LlmError::ParseError(_))));
+    }
+
+    #[test]
+    fn test_parse_response_multiple_choices() {
+        let body = json!({
+            "choices": [
+                { "index": 0, "message": { "role": "assistant", "content": "  \n" } },
+                { "index": 1, "message": { "role": "assistant", "content": "Second choice." } },
+                { "index": 2, "message": { "role": "assistant", "content": "Third choice." } }
+            ]
+        });
+        assert_eq!(OpenAiCompatible.parse_choices(body.clone()).unwrap(), ["", "Second choice.", "Third choice."]);
+        // The first non-empty choice is used
+        assert_eq!(OpenAiCompatible.parse_response(body).unwrap(), "Second choice.");
+
+        let all_empty = json!({ "choices": [{ "message": { "role": "assistant", "content": "" } }] });
+        assert!(matches!(OpenAiCompatible.parse_response(all_empty), Err(LlmError::NoContent)));
+    }
+}
@@ -1,98 +1,1135 @@
-use clap::Parser;
-use ctx_guard::config::Config;
-use ctx_guard::executor::execute_command_string;
-use ctx_guard::llm::LlmClient;
-use ctx_guard::output::{cleanup_old_files, format_fallback_output, generate_output_filename, write_output_file, get_recent_commands, update_output_file_summary, CommandMetadata};
-use chrono::Local;
-use std::path::PathBuf;
-use std::time::Instant;
-
-fn strip_think_blocks(s: &str) -> String {
-    if let Some(end) = s.find("</think>") {
-        // Drop everything up to and including the first closing </think> tag.
-        s[end + "</think>".len()..].trim().to_string()
-    } else {
-        s.to_string()
-    }
-}
+use clap::{Parser, Subcommand};
+use ctx_guard::error::CtxGuardError;
+use ctx_guard::config::{config_sources, load_prompt_file, setup_hint_marker_path, Config, PromptContext, EXPLAIN_PROMPT};
+use ctx_guard::executor::{execute_passthrough, execute_with_retries, CaptureFile, ExecutionResult, ForwardOutput, OutputSink, OutputStream, ResourceUsage, StreamLayout, TimeoutSignal};
+use ctx_guard::llm::{strip_think_blocks, LlmClient, LlmError};
+use ctx_guard::cache::{evict_expired_entries, PromptCache};
+use ctx_guard::pipeline::{join_argv, supports_tracing, Pipeline};
+use ctx_guard::output::{apply_summary_window, capture_dir, ensure_capture_dir, cap_output_lines, check_output_dir_writable, output_dir, cleanup_old_files, compute_stats, current_time, detect_output_extension, diff_lines, find_run, generate_run_id, format_fallback_output, format_output_size, generate_output_filename_in, is_likely_binary, start_output_file, parse_metadata_from_file, read_output_from_file, tail_bytes, tail_lines, truncate_words, write_output_file, write_output_file_in, write_streamed_output_file, get_last_commands, get_recent_commands, list_runs, RunRecord, parse_tag, parse_time_bound, update_output_file_metadata, write_summary_sidecar, CommandMetadata, StreamedText};
+use chrono::{DateTime, FixedOffset, Local};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Most output `--full-on-failure` prints; beyond this only the end is shown.
+const FULL_OUTPUT_MAX_BYTES: usize = 1024 * 1024;
+
+/// Exit code reserved for `--exit-on-summary-failure`: the command succeeded but
+/// the LLM summary failed and the truncated fallback output was printed instead.
+const SUMMARY_FAILURE_EXIT_CODE: i32 = 250;
 
 #[derive(Parser)]
 #[command(name = "cg")]
 #[command(about = "Context guard - wrap commands and summarize output for AI agents")]
+#[command(subcommand_negates_reqs = true, disable_help_subcommand = true)]
+#[command(after_help = "cg's own options go before the command; everything from the command's first word on is passed to it \
+unchanged, so `cg --debug-llm cargo test --release` gives --debug-llm to cg and --release to cargo. Use `--` to mark where the \
+command starts, e.g. `cg --debug-llm -- cargo test` or `cg -- rerun` for a command named like a subcommand.")]
 struct Args {
-    /// Force summarization even if output is short
+    #[command(subcommand)]
+    action: Option<Action>,
+
+    /// Force summarization even if output is short or summarization is disabled for the command
     #[arg(long = "force-summary", default_value_t = false)]
     force_summary: bool,
 
-    /// Path to configuration file (default: ~/.ctx_guard/config.toml)
+    /// Summarize even if the output is short, unless summarization is disabled for the
+    /// command or --no-summarize is given
+    #[arg(long = "summarize-anyway", default_value_t = false)]
+    summarize_anyway: bool,
+
+    /// Never summarize this run; return the raw output however long it is
+    #[arg(long = "no-summarize", default_value_t = false, conflicts_with = "force_summary")]
+    no_summarize: bool,
+
+    /// Ask the model for concrete steps to fix the problem instead of a summary (implies
+    /// --force-summary)
+    #[arg(long = "explain", default_value_t = false, conflicts_with = "no_summarize")]
+    explain: bool,
+
+    /// Extra guidance for the model for this run only (fills `${user_context}`)
+    #[arg(long = "append-context", value_name = "TEXT")]
+    append_context: Option<String>,
+
+    /// Use the prompt template in this file for this run instead of the configured one.
+    /// It must contain ${output}; the other placeholders work as in `prompt`.
+    #[arg(long = "prompt-file", value_name = "PATH", conflicts_with = "explain")]
+    prompt_file: Option<PathBuf>,
+
+    /// Put the N most recent commands in the prompt, instead of those run within
+    /// command_context_minutes
+    #[arg(long = "context-count", value_name = "N", conflicts_with = "no_context")]
+    context_count: Option<usize>,
+
+    /// Skip the summary check pass enabled by verify_summary for this run
+    #[arg(long = "no-verify", default_value_t = false)]
+    no_verify: bool,
+
+    /// Look back N minutes for recent commands in this run, instead of command_context_minutes
+    #[arg(long = "recent-minutes", value_name = "N", conflicts_with = "no_context")]
+    recent_minutes: Option<u32>,
+
+    /// Leave recent commands out of the prompt for this run
+    #[arg(long = "no-context", visible_alias = "no-recent-context", default_value_t = false)]
+    no_context: bool,
+
+    /// Exit with code 250 if the command succeeded but summarization failed
+    #[arg(long = "exit-on-summary-failure", default_value_t = false)]
+    exit_on_summary_failure: bool,
+
+    /// Named provider to use for this run (defined under [providers.<name>])
+    #[arg(long = "provider", value_name = "NAME")]
+    provider: Option<String>,
+
+    /// Re-run the command up to N times if it fails with a retryable exit code
+    #[arg(long = "retry", value_name = "N")]
+    retry: Option<u32>,
+
+    /// Stop the command if it runs longer than SECS seconds, instead of command_timeout_secs
+    #[arg(long = "timeout", value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// Signal sent when the command times out: TERM (then KILL after timeout_grace_secs) or KILL
+    #[arg(long = "timeout-signal", value_name = "SIGNAL")]
+    timeout_signal: Option<TimeoutSignal>,
+
+    /// Tag the capture so it can be found later with `cg history --tag` (repeatable)
+    #[arg(long = "tag", value_name = "NAME", value_parser = parse_tag)]
+    tag: Vec<String>,
+
+    /// If the command fails, print its whole output after the summary (up to 1 MB)
+    #[arg(long = "full-on-failure", default_value_t = false)]
+    full_on_failure: bool,
+
+    /// Group this run into a session, for `cg summarize-session NAME` (a tag under the hood)
+    #[arg(long = "session", value_name = "NAME", value_parser = parse_tag)]
+    session: Option<String>,
+
+    /// Keep at most N lines of output (the first and last half) in the file and the summary
+    #[arg(long = "max-output-lines", value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_output_lines: Option<u32>,
+
+    /// Write the absolute path of the output file to this file
+    #[arg(long = "path-file", value_name = "PATH")]
+    path_file: Option<PathBuf>,
+
+    /// Save the capture at this path instead of the output directory. If the path is a
+    /// directory, the generated filename is used inside it.
+    #[arg(long = "wrap-file", value_name = "PATH")]
+    wrap_file: Option<PathBuf>,
+
+    /// Pass the command's stdout through to ours byte for byte as it runs, and print the
+    /// summary and file path to stderr instead, so cg can sit in a pipeline. The command
+    /// runs once: output already passed on can't be taken back for a retry
+    #[arg(long = "raw-stdout", default_value_t = false, conflicts_with_all = ["summarize_stdin", "retry"])]
+    raw_stdout: bool,
+
+    /// Print only the absolute path of the output file (the summary is still saved in it)
+    #[arg(long = "print-path-only", default_value_t = false)]
+    print_path_only: bool,
+
+    /// Language to write the summary in for this run (overrides summary_language)
+    #[arg(long = "lang", value_name = "LANGUAGE")]
+    lang: Option<String>,
+
+    /// Don't reuse or store summaries in the prompt cache for this run
+    #[arg(long = "no-cache", default_value_t = false)]
+    no_cache: bool,
+
+    /// Shell to run the command with (e.g. bash, zsh); overrides the `shell` setting
+    #[arg(long = "shell", value_name = "PROGRAM")]
+    shell: Option<String>,
+
+    /// Show which settings would apply and where the output would go, without running
+    /// the command or calling the LLM
+    #[arg(long = "plan", default_value_t = false)]
+    plan: bool,
+
+    /// Print the full prompt and the raw model response to stderr
+    #[arg(long = "debug-llm", default_value_t = false)]
+    debug_llm: bool,
+
+    /// Measure peak memory and CPU time of the command (Unix only)
+    #[arg(long = "resources", default_value_t = false)]
+    resources: bool,
+
+    /// Path to configuration file, used on its own (default: ~/.ctx_guard/config.toml with the
+    /// nearest .ctx_guard/config.toml merged over it)
     #[arg(short = 'c', long = "config")]
     config: Option<PathBuf>,
+
+    /// Run with the default settings if the config file can't be loaded, instead of failing
+    #[arg(long = "config-fallback", default_value_t = false)]
+    config_fallback: bool,
     
-    /// Command to execute (all remaining arguments)
-    #[arg(trailing_var_arg = true, required = true)]
+    /// Summarize output piped on stdin instead of running a command. Any command
+    /// given is only used as a label for the input.
+    #[arg(long = "summarize-stdin", default_value_t = false)]
+    summarize_stdin: bool,
+
+    /// Exit code to report for input read with --summarize-stdin
+    #[arg(long = "exit-code", value_name = "CODE", default_value_t = 0, requires = "summarize_stdin")]
+    exit_code: i32,
+
+    /// Command to execute: everything from its first word (or after `--`) on, including
+    /// arguments that look like cg options
+    #[arg(trailing_var_arg = true, required_unless_present = "summarize_stdin")]
     command: Vec<String>,
 }
 
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
-    let command_str = args.command.join(" ");
+#[derive(Subcommand)]
+enum Action {
+    /// Re-execute the command recorded in a previous capture file
+    Rerun {
+        /// Capture file to read the command from
+        file: PathBuf,
 
-    // Load configuration
-    let config = match Config::load_from_path(args.config.clone()) {
-        Ok(cfg) => cfg,
+        /// Summarize the captured output again instead of re-running the command
+        #[arg(long = "resummarize-instead", default_value_t = false)]
+        resummarize_instead: bool,
+    },
+    /// List previous runs recorded in the output directory, oldest first
+    History {
+        #[command(flatten)]
+        window: TimeWindow,
+
+        /// Only show runs with this tag (repeatable; runs must have all of them)
+        #[arg(long = "tag", value_name = "NAME", value_parser = parse_tag)]
+        tag: Vec<String>,
+    },
+    /// Show the command, exit code, capture file and summary of a run by its ID
+    Show {
+        /// Run ID printed with the summary and listed by `cg history`
+        run_id: String,
+    },
+    /// Summarize the captures in the output directory
+    Stats {
+        #[command(flatten)]
+        window: TimeWindow,
+
+        /// Print the statistics as JSON
+        #[arg(long = "json", default_value_t = false)]
+        json: bool,
+    },
+    /// Summarize all runs recorded with `--session NAME` in one combined summary
+    SummarizeSession {
+        /// Session name given to `--session`
+        #[arg(value_parser = parse_tag)]
+        name: String,
+
+        #[command(flatten)]
+        window: TimeWindow,
+    },
+    /// Summarize what changed between the outputs of two captures
+    Diff {
+        /// Capture of the earlier run
+        #[arg(required_unless_present = "last", conflicts_with = "last")]
+        old: Option<PathBuf>,
+
+        /// Capture of the later run
+        #[arg(required_unless_present = "last", conflicts_with = "last")]
+        new: Option<PathBuf>,
+
+        /// Compare the most recent run with the previous run of the same command
+        #[arg(long = "last", default_value_t = false)]
+        last: bool,
+    },
+    /// Check the config, output directory, shell and provider, with hints for fixing problems
+    Doctor,
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+/// `--since`/`--until` bounds shared by the subcommands that list recorded runs.
+#[derive(clap::Args)]
+struct TimeWindow {
+    /// Only include runs at or after this time (e.g. 2024-01-01T09:00)
+    #[arg(long = "since", value_name = "TIME", value_parser = parse_time_bound)]
+    since: Option<DateTime<Local>>,
+
+    /// Only include runs at or before this time
+    #[arg(long = "until", value_name = "TIME", value_parser = parse_time_bound)]
+    until: Option<DateTime<Local>>,
+}
+
+impl TimeWindow {
+    /// Recorded runs within the window, oldest first. Fails if `--since` is after `--until`.
+    fn runs(&self) -> Result<Vec<RunRecord>, CtxGuardError> {
+        if let (Some(since), Some(until)) = (self.since, self.until) {
+            if since > until {
+                return Err(CtxGuardError::InvalidTimeWindow { since, until });
+            }
+        }
+        Ok(list_runs(self.since, self.until))
+    }
+}
+
+impl Args {
+    /// Tags to record in the capture: `--tag` values plus the `--session` name.
+    fn tags(&self) -> Vec<String> {
+        self.tag.iter().chain(&self.session).cloned().collect()
+    }
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Check the config file and report deprecated settings and override keys that look
+    /// like typos of commands that were run
+    Validate,
+}
+
+/// Describes the previous run of `command` within the context window if it also produced
+/// no output, so a repeated silent failure stands out.
+fn previous_empty_run_note(command: &str, current_capture: Option<&Path>, minutes: u32) -> Option<String> {
+    let since = Local::now() - chrono::Duration::minutes(minutes as i64);
+    let previous = list_runs(Some(since), None)
+        .into_iter()
+        .rev()
+        .find(|run| run.metadata.command == command && Some(run.path.as_path()) != current_capture)?;
+
+    let output = read_output_from_file(&previous.path)?;
+    if !String::from_utf8_lossy(&output).trim().is_empty() {
+        return None;
+    }
+    Some(format!(
+        "The previous run of this command at {} (exit code {}) also produced no output.",
+        previous.metadata.timestamp.format("%H:%M:%S"),
+        previous.metadata.exit_code
+    ))
+}
+
+/// Reports an error that stops the run and exits with its exit code. Shell failures are
+/// worded so they read as an environment problem, since the command never ran.
+fn exit_on_error(error: &CtxGuardError) -> ! {
+    match error {
+        CtxGuardError::Execution(e) if e.is_shell_failure() => {
+            eprintln!("Error: {}. The command was not run; check that the shell exists or set `shell` in the config.", e);
+        }
+        CtxGuardError::Execution(e) => eprintln!("Error executing command: {}", e),
+        CtxGuardError::Config(e) => {
+            eprintln!("Error: {}", e);
+            eprintln!("Fix the config file, or pass --config-fallback to run commands with the default settings.");
+        }
+        CtxGuardError::CommandBlocked(_) => eprintln!("{}", error),
+        CtxGuardError::Stdin(e) => eprintln!("Error reading stdin: {}", e),
+        _ => eprintln!("Error: {}", error),
+    }
+    std::process::exit(error.exit_code());
+}
+
+/// What running the command produced, besides the output in `result`.
+struct CommandRun {
+    result: ExecutionResult,
+    attempts: u32,
+    /// Capture written while the command ran, with `stream_to_file`
+    partial_path: Option<PathBuf>,
+    /// Where each stream went in the partial capture
+    stream_layout: Option<StreamLayout>,
+    /// How each step went, with `trace_pipeline`
+    pipeline_steps: Option<String>,
+    resources_before: Option<ResourceUsage>,
+}
+
+impl From<ExecutionResult> for CommandRun {
+    fn from(result: ExecutionResult) -> Self {
+        Self { result, attempts: 1, partial_path: None, stream_layout: None, pipeline_steps: None, resources_before: None }
+    }
+}
+
+/// Runs the command described by `metadata` with the run's settings: retries, timeout,
+/// streaming to a partial capture and pipeline tracing.
+fn run_command(args: &Args, config: &Config, metadata: &CommandMetadata) -> Result<CommandRun, CtxGuardError> {
+    let command_str = metadata.command.as_str();
+    if config.is_command_blocked(command_str) {
+        return Err(CtxGuardError::CommandBlocked(command_str.to_string()));
+    }
+
+    let resources_before = if args.resources { ResourceUsage::children() } else { None };
+    // Passed-through output can't be taken back, so retry_count doesn't apply to it
+    let max_retries = if args.raw_stdout { 0 } else { args.retry.unwrap_or(config.retry_count) };
+    let shell = config.shell(args.shell.as_deref());
+    shell.validate()?;
+    // Stream into a partial capture so a crash or kill doesn't lose the output
+    let mut partial_file = None;
+    let mut partial_path = None;
+    if config.stream_to_file {
+        let (dir, filename) = capture_location(args, config, command_str, "txt");
+        if args.wrap_file.is_none() {
+            // Created with the output directory's permissions; start_output_file would too, without them
+            let _ = ensure_capture_dir(&filename, config.capture_options());
+        }
+        let path = dir.join(filename);
+        let metadata = CommandMetadata {
+            exit_code: -1,
+            timestamp: current_time(config.use_utc),
+            ..metadata.clone()
+        };
+        match start_output_file(&path, &metadata, config.capture_options()) {
+            Ok(file) => {
+                partial_file = Some(file);
+                partial_path = Some(path);
+            }
+            Err(e) => eprintln!("Warning: Failed to create output file, capturing in memory only: {}", e),
+        }
+    }
+    // With trace_pipeline, each step of a compound command records how it went
+    let pipeline = Some(command_str)
+        .filter(|_| config.trace_pipeline && supports_tracing(&shell))
+        .and_then(Pipeline::parse);
+    let trace_file = std::env::temp_dir().join(format!("ctx_guard_trace_{}", metadata.run_id.as_deref().unwrap_or_default()));
+    let command_to_run = match pipeline {
+        Some(ref pipeline) => pipeline.instrument(&trace_file),
+        None => command_str.to_string(),
+    };
+    let file = partial_file.as_mut().map(|file| CaptureFile::new(file));
+    let mut forward = None;
+    let mut capture_file = None;
+    let sink = if args.raw_stdout {
+        Some(forward.insert(ForwardOutput { file }) as &mut dyn OutputSink)
+    } else {
+        file.map(|file| capture_file.insert(file) as &mut dyn OutputSink)
+    };
+    let (result, attempts) = execute_with_retries(&shell, &command_to_run, max_retries, &config.retry_on_exit_codes, config.retry_capture_all, sink, config.command_timeout())?;
+    let stream_layout = capture_file.or(forward.and_then(|forward| forward.file)).map(|file| file.layout);
+    let pipeline_steps = pipeline.and_then(|pipeline| {
+        let steps = pipeline.describe(&pipeline.read_trace(&trace_file), config);
+        let _ = std::fs::remove_file(&trace_file);
+        steps
+    });
+    Ok(CommandRun { result, attempts, partial_path, stream_layout, pipeline_steps, resources_before })
+}
+
+/// The directory given to `--wrap-file`, if it names one: an existing directory, or a
+/// path ending in a separator. The capture then gets the usual generated name inside it.
+fn wrap_dir(args: &Args) -> Option<&Path> {
+    args.wrap_file.as_deref().filter(|target| {
+        target.is_dir() || target.as_os_str().to_string_lossy().ends_with(std::path::MAIN_SEPARATOR)
+    })
+}
+
+/// Directory and file name for a run's capture: the `--wrap-file` path, a generated name
+/// in the `--wrap-file` directory, or a generated name in the output directory (its day
+/// directory with `subdir_by_date`). Nothing is created.
+fn capture_location(args: &Args, config: &Config, command_str: &str, extension: &str) -> (PathBuf, String) {
+    let generate = |dir: &Path| generate_output_filename_in(dir, command_str, config.filename_hash, extension, config.use_utc);
+    match (wrap_dir(args), args.wrap_file.as_deref()) {
+        (Some(dir), _) => (dir.to_path_buf(), generate(dir)),
+        (None, Some(target)) => {
+            let dir = target.parent().unwrap_or(Path::new("")).to_path_buf();
+            let name = target.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_else(|| generate(&dir));
+            (dir, name)
+        }
+        (None, None) => {
+            let filename = generate(&output_dir());
+            (capture_dir(&filename, config.capture_options()), filename)
+        }
+    }
+}
+
+/// Environment variable that turns `cg` into a transparent passthrough when set to a
+/// truthy value (e.g. `CTX_GUARD_DISABLE=1` for a CI stage).
+const DISABLE_ENV_VAR: &str = "CTX_GUARD_DISABLE";
+
+fn is_disabled_by_env() -> bool {
+    std::env::var(DISABLE_ENV_VAR)
+        .map(|value| matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false)
+}
+
+/// Prints a delimited block for `--debug-llm` to stderr, keeping stdout for the summary.
+fn print_debug_block(label: &str, content: &str) {
+    eprintln!("--- ctx_guard debug: {} ---\n{}\n--- end of {} ---", label, content, label);
+}
+
+/// Describes the command timeout and how it is enforced, e.g. "30 seconds (SIGKILL)".
+fn describe_timeout(config: &Config) -> String {
+    let stop = match config.timeout_signal {
+        TimeoutSignal::Kill => "SIGKILL".to_string(),
+        TimeoutSignal::Term if config.timeout_grace_secs == 0 => "SIGTERM".to_string(),
+        TimeoutSignal::Term => format!("SIGTERM, then SIGKILL after {} more seconds", config.timeout_grace_secs),
+    };
+    format!("{} seconds ({})", config.command_timeout_secs, stop)
+}
+
+/// Prints what a run would do for `--plan`: the resolved command and the settings that
+/// apply to it. Nothing is executed, written or sent.
+fn print_plan(args: &Args, config: &Config, command_str: &str) {
+    let (output_dir, filename) = capture_location(args, config, command_str, "txt");
+    let output_path = output_dir.join(filename);
+    let yes_no = |value: bool| if value { "yes" } else { "no" };
+
+    println!("Command: {}", command_str);
+    println!("Blocked: {}", yes_no(config.is_command_blocked(command_str)));
+    println!("Summarization disabled: {}", yes_no(config.is_summary_disabled(command_str)));
+    if config.allowlist_only {
+        println!("Allowlisted: {}", yes_no(!config.is_outside_allowlist(command_str)));
+    }
+    println!("Passthrough: {}", yes_no(config.is_passthrough_command(command_str)));
+    println!("Provider: {} at {} (type {})", config.provider.model, config.provider.url, config.provider.r#type);
+    println!("Summary words: {}", config.get_summary_words(command_str));
+    println!("Output length threshold: {} words", config.get_output_length_threshold(command_str));
+    if config.summarize_if_duration_exceeds_secs > 0 {
+        println!("Summarize successes after: {} seconds", config.summarize_if_duration_exceeds_secs);
+    }
+    println!("Summary window: {}", config.get_summary_window(command_str));
+    println!("Temperature: {}, max tokens: {}", config.provider.temperature, config.provider.max_tokens);
+    let shell = config.shell(args.shell.as_deref());
+    println!("Shell: {} {}{}", shell.program, shell.flag, if shell.validate().is_ok() { "" } else { " (not found)" });
+    println!("Retries: {}", if args.raw_stdout { 0 } else { args.retry.unwrap_or(config.retry_count) });
+    if config.command_timeout().is_some() {
+        println!("Timeout: {}", describe_timeout(config));
+    }
+    if config.smart_extensions && args.wrap_file.is_none() {
+        println!("Output file: {} (extension depends on the output)", output_path.display());
+    } else {
+        println!("Output file: {}", output_path.display());
+    }
+    // Preview the prompt being tried out, with placeholders for what only a run produces
+    if let Some(ref path) = args.prompt_file {
+        let context = PromptContext {
+            user_context: args.append_context.as_deref(),
+            ..Default::default()
+        };
+        let prompt = config.format_prompt(command_str, 0, "[command output]", config.get_summary_words(command_str), &context);
+        println!("Prompt from {} (exit code and output are placeholders):\n{}", path.display(), prompt);
+    }
+}
+
+/// Explains how to set up a provider, the first time the default one turns out to be
+/// unreachable. Later runs stay quiet.
+fn print_setup_hint_once(url: &str) {
+    let marker = setup_hint_marker_path();
+    if marker.exists() {
+        return;
+    }
+    eprintln!("No LLM server is answering at {} (the default LM Studio address), so the output is shown without a summary.", url);
+    eprintln!("To get summaries, start LM Studio with a model loaded, or point ctx_guard at your provider with type, url and model under [provider] in {}.", marker.with_file_name("config.toml").display());
+    eprintln!("This hint is shown once.");
+    let _ = std::fs::write(&marker, "");
+}
+
+/// Loads the config and prints any problems found. Fails if it can't be loaded.
+fn validate_config(config_path: Option<PathBuf>) -> Result<(), CtxGuardError> {
+    let config = Config::load_from_path(config_path)?;
+
+    // Captures are kept for clean_up_days, so this covers every run still on disk
+    let recent_minutes = config.clean_up_days.saturating_mul(24 * 60);
+    let ran_commands: Vec<String> = get_recent_commands(recent_minutes, config.use_utc)
+        .into_iter()
+        .map(|(command, _, _)| command)
+        .collect();
+
+    let warnings: Vec<String> = config
+        .load_warnings()
+        .into_iter()
+        .chain(config.override_key_suggestions(&ran_commands))
+        .collect();
+    if warnings.is_empty() {
+        println!("Config OK");
+    }
+    for warning in warnings {
+        println!("Warning: {}", warning);
+    }
+    Ok(())
+}
+
+/// Prints aggregate information about the captures in the output directory within `window`.
+fn print_stats(window: &TimeWindow, json: bool) -> Result<(), CtxGuardError> {
+    const TOP_COMMANDS: usize = 5;
+    let stats = compute_stats(&window.runs()?, TOP_COMMANDS);
+    let format_time = |time: Option<DateTime<FixedOffset>>| time.map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string());
+
+    if json {
+        let report = serde_json::json!({
+            "total_files": stats.total_files,
+            "total_bytes": stats.total_bytes,
+            "succeeded": stats.succeeded,
+            "failed": stats.failed,
+            "oldest": stats.oldest.map(|t| t.to_rfc3339()),
+            "newest": stats.newest.map(|t| t.to_rfc3339()),
+            "top_commands": stats.top_commands.iter()
+                .map(|(command, runs)| serde_json::json!({ "command": command, "runs": runs }))
+                .collect::<Vec<_>>(),
+        });
+        println!("{}", serde_json::to_string_pretty(&report).expect("stats are always serializable"));
+        return Ok(());
+    }
+
+    println!("Captures: {} ({:.1} KB)", stats.total_files, stats.total_bytes as f64 / 1024.0);
+    println!("Succeeded: {}, failed: {}", stats.succeeded, stats.failed);
+    if let (Some(oldest), Some(newest)) = (format_time(stats.oldest), format_time(stats.newest)) {
+        println!("Oldest: {}", oldest);
+        println!("Newest: {}", newest);
+    }
+    if !stats.top_commands.is_empty() {
+        println!("Most frequent commands:");
+        for (command, runs) in &stats.top_commands {
+            println!("  {:>4}  {}", runs, command);
+        }
+    }
+    Ok(())
+}
+
+/// Prints one line per recorded run within `window` that has all of `tags`.
+fn print_history(window: &TimeWindow, tags: &[String]) -> Result<(), CtxGuardError> {
+    let runs = window
+        .runs()?
+        .into_iter()
+        .filter(|run| tags.iter().all(|tag| run.metadata.tags.contains(tag)));
+    for run in runs {
+        let source = run.metadata.summary_source.map(|source| format!("  [{}]", source)).unwrap_or_default();
+        println!(
+            "{}  {:<12}  exit {:<3}  {}  {}{}",
+            run.metadata.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            run.metadata.run_id.as_deref().unwrap_or("-"),
+            run.metadata.exit_code,
+            run.metadata.command,
+            run.path.display(),
+            source
+        );
+    }
+    Ok(())
+}
+
+/// Implements `cg show`: prints the recorded details and summary of a run.
+fn show_run(run_id: &str) -> Result<(), CtxGuardError> {
+    let run = find_run(run_id).ok_or_else(|| CtxGuardError::RunNotFound { run_id: run_id.to_string(), dir: output_dir() })?;
+    let metadata = run.metadata;
+    println!("Command: {}", metadata.command);
+    println!("Exit code: {}", metadata.exit_code);
+    println!("Time: {}", metadata.timestamp.format("%Y-%m-%d %H:%M:%S %:z"));
+    if let Some(cwd) = metadata.cwd {
+        println!("Working directory: {}", cwd);
+    }
+    println!("Capture: {}", run.path.display());
+    if let Some(summary) = metadata.summary {
+        println!("\n{}", summary);
+    }
+    Ok(())
+}
+
+/// Prints one line of the `cg doctor` report, followed by an indented fix hint if given.
+fn print_check(status: &str, check: &str, detail: &str, hint: Option<&str>) {
+    println!("[{:<4}] {}: {}", status, check, detail);
+    if let Some(hint) = hint {
+        println!("       {}", hint);
+    }
+}
+
+/// Checks everything `cg` needs and prints a report. Exits with 1 if a check that keeps
+/// commands from running or being saved fails; provider problems only cost the summary,
+/// so they are reported as warnings.
+async fn run_doctor(config_path: Option<PathBuf>) {
+    const NETWORK_TIMEOUT: Duration = Duration::from_secs(30);
+    let mut failures = 0;
+
+    let config_files = config_sources(config_path.clone())
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(" + ");
+    let config = match Config::load_from_path(config_path) {
+        Ok(config) => {
+            print_check("ok", "Config", &format!("{} loaded", config_files), None);
+            config
+        }
         Err(e) => {
-            eprintln!("Warning: Failed to load config: {}. Using defaults.", e);
+            failures += 1;
+            print_check("FAIL", "Config", &e.to_string(), Some("Fix the file, or delete it to have the defaults written again. The checks below use the defaults."));
             Config::default()
         }
     };
+    for warning in config.load_warnings() {
+        print_check("warn", "Config", &warning, None);
+    }
 
-    // Clean up old temporary files
-    cleanup_old_files(config.clean_up_days);
+    match check_output_dir_writable(config.restrict_permissions) {
+        Ok(dir) => print_check("ok", "Output directory", &format!("{} is writable", dir.display()), None),
+        Err(e) => {
+            failures += 1;
+            print_check("FAIL", "Output directory", &format!("{}: {}", output_dir().display(), e), Some("Captures are saved there; make sure this user can create and write to it."));
+        }
+    }
 
-    // Check if command is disabled
-    if config.is_command_disabled(&command_str) {
-        eprintln!("Command '{}' is disabled in configuration", command_str);
+    let shell = config.shell(None);
+    match shell.validate() {
+        Ok(()) => print_check("ok", "Shell", &format!("{} {}", shell.program, shell.flag), None),
+        Err(e) => {
+            failures += 1;
+            print_check("FAIL", "Shell", &e.to_string(), Some("Install it, or set `shell` in the config to one that exists."));
+        }
+    }
+
+    let provider = &config.provider;
+    match LlmClient::from_provider(provider) {
+        Err(e) => print_check("warn", "Provider", &e.to_string(), Some("Set `type` under [provider]. Commands still run, but without summaries.")),
+        Ok(client) => match tokio::time::timeout(NETWORK_TIMEOUT, client.check_reachable()).await {
+            Ok(Ok(())) => {
+                print_check("ok", "Provider", &format!("{} answers at {}", provider.r#type, provider.url), None);
+                match tokio::time::timeout(NETWORK_TIMEOUT, client.summarize(&provider.model, "Reply with OK.")).await {
+                    Ok(Ok(_)) => print_check("ok", "Model", &format!("{} responded", provider.model), None),
+                    Ok(Err(e)) => print_check("warn", "Model", &format!("{} didn't respond: {}", provider.model, e), Some("Load the model on the server, or set `model` under [provider] to one it serves.")),
+                    Err(_) => print_check("warn", "Model", &format!("{} didn't respond within {} seconds", provider.model, NETWORK_TIMEOUT.as_secs()), Some("A large model may still be loading; try again in a minute.")),
+                }
+            }
+            Ok(Err(e)) => print_check("warn", "Provider", &format!("nothing answers at {}: {}", provider.url, e), Some("Start the server (e.g. LM Studio with a model loaded), or set `url` under [provider]. Commands still run, but without summaries.")),
+            Err(_) => print_check("warn", "Provider", &format!("no answer from {} within {} seconds", provider.url, NETWORK_TIMEOUT.as_secs()), Some("Check that `url` under [provider] points at the LLM server.")),
+        },
+    }
+
+    if failures > 0 {
+        println!("\n{} critical check(s) failed", failures);
         std::process::exit(1);
     }
+    println!("\nAll critical checks passed");
+}
 
-    // Execute the command
-    let cmd_exec_start_time = Instant::now();
-    let result = match execute_command_string(&command_str) {
-        Ok(res) => res,
+/// Runs the command (or copies piped input for `--summarize-stdin`) straight through to
+/// the terminal without capturing or summarizing anything, and exits with its exit code.
+fn run_passthrough(args: &Args, config: &Config, command_str: &str) -> ! {
+    if args.summarize_stdin {
+        if let Err(e) = std::io::copy(&mut std::io::stdin(), &mut std::io::stdout()) {
+            exit_on_error(&CtxGuardError::Stdin(e));
+        }
+        std::process::exit(args.exit_code);
+    }
+    match execute_passthrough(&config.shell(args.shell.as_deref()), command_str) {
+        Ok(exit_code) => std::process::exit(exit_code),
+        Err(e) => exit_on_error(&e.into()),
+    }
+}
+
+/// Summarizes all runs of session `name` within `window` in one LLM request built from
+/// their summaries. Without a reachable provider, the runs are listed instead.
+async fn summarize_session(config_path: Option<PathBuf>, name: &str, window: &TimeWindow) -> Result<(), CtxGuardError> {
+    // Lines of output used for runs whose output was short enough to skip summarizing
+    const UNSUMMARIZED_TAIL_LINES: usize = 20;
+
+    let config = Config::load_from_path(config_path)?;
+
+    let runs: Vec<(String, i32, String)> = window
+        .runs()?
+        .into_iter()
+        .filter(|run| run.metadata.tags.iter().any(|tag| tag == name))
+        .map(|run| {
+            let summary = run.metadata.summary.unwrap_or_else(|| {
+                read_output_from_file(&run.path)
+                    .map(|output| tail_lines(&String::from_utf8_lossy(&output), UNSUMMARIZED_TAIL_LINES))
+                    .unwrap_or_default()
+            });
+            (run.metadata.command, run.metadata.exit_code, summary)
+        })
+        .collect();
+    if runs.is_empty() {
+        return Err(CtxGuardError::EmptySession(name.to_string()));
+    }
+
+    let prompt = config.format_session_prompt(name, &runs);
+    let result = match LlmClient::from_provider(&config.provider) {
+        Ok(client) => client.summarize(&config.provider.model, &prompt).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(summary) => println!("{}", strip_think_blocks(&summary)),
         Err(e) => {
-            eprintln!("Error executing command: {}", e);
-            std::process::exit(1);
+            eprintln!("Warning: could not summarize the session: {}", e);
+            for (command, exit_code, _) in &runs {
+                println!("exit {:<3}  {}", exit_code, command);
+            }
         }
+    }
+    Ok(())
+}
+
+/// Returns the captures of the most recent run and the previous run of the same command.
+fn last_two_runs() -> Option<(PathBuf, PathBuf)> {
+    let runs = list_runs(None, None);
+    let (newest, earlier) = runs.split_last()?;
+    let previous = earlier.iter().rev().find(|run| run.metadata.command == newest.metadata.command)?;
+    Some((previous.path.clone(), newest.path.clone()))
+}
+
+/// Implements `cg diff`: diffs the outputs of two captures and asks the model what changed.
+/// Without a provider, the diff itself is printed.
+async fn diff_captures(config_path: Option<PathBuf>, old: Option<PathBuf>, new: Option<PathBuf>) -> Result<(), CtxGuardError> {
+    let config = Config::load_from_path(config_path)?;
+
+    let (old, new) = match (old, new) {
+        (Some(old), Some(new)) => (old, new),
+        _ => last_two_runs().ok_or(CtxGuardError::NoEarlierRun)?,
+    };
+    let load = |path: &Path| match (parse_metadata_from_file(path), read_output_from_file(path)) {
+        (Some(metadata), Some(output)) => Ok((metadata, String::from_utf8_lossy(&output).into_owned())),
+        _ => Err(CtxGuardError::NotACapture(path.to_path_buf())),
     };
+    let (old_metadata, old_output) = load(&old)?;
+    let (new_metadata, new_output) = load(&new)?;
+
+    if old_metadata.command != new_metadata.command {
+        eprintln!(
+            "Warning: the captures are for different commands (`{}` and `{}`); the diff may not be meaningful",
+            old_metadata.command, new_metadata.command
+        );
+    }
+
+    let diff = diff_lines(&old_output, &new_output);
+    if diff.is_empty() {
+        println!(
+            "The output is unchanged (exit code {} before, {} now).",
+            old_metadata.exit_code, new_metadata.exit_code
+        );
+        return Ok(());
+    }
+
+    let prompt = config.format_diff_prompt(
+        (&old_metadata.command, old_metadata.exit_code),
+        (&new_metadata.command, new_metadata.exit_code),
+        &diff,
+    );
+    let result = match LlmClient::from_provider(&config.provider) {
+        Ok(client) => client.summarize(&config.provider.model, &prompt).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(summary) => println!("{}", strip_think_blocks(&summary)),
+        Err(e) => {
+            eprintln!("Warning: could not summarize the difference: {}", e);
+            println!("{}", format_fallback_output(&diff, 20));
+        }
+    }
+    Ok(())
+}
+
+/// Loads the configuration for a run. A broken config is an error unless falling back to
+/// defaults was asked for, so a typo doesn't silently change behavior.
+fn load_config(args: &Args) -> Result<Config, CtxGuardError> {
+    match Config::load_from_path(args.config.clone()) {
+        Ok(cfg) => Ok(cfg),
+        Err(e) if args.config_fallback => {
+            eprintln!("Warning: {}. Using defaults.", e);
+            Ok(Config::default())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = Args::parse();
+    // Remediation steps are most useful for short error output too
+    args.force_summary |= args.explain;
+
+    // Passthrough mode: no capture file, no summary, just the command and its exit code
+    if args.action.is_none() && !args.plan && is_disabled_by_env() {
+        let config = if args.summarize_stdin {
+            Config::default()
+        } else {
+            load_config(&args).unwrap_or_else(|e| exit_on_error(&e))
+        };
+        run_passthrough(&args, &config, &args.command.join(" "));
+    }
+
+    let subcommand = match args.action {
+        Some(Action::History { ref window, ref tag }) => Some(print_history(window, tag)),
+        Some(Action::Show { ref run_id }) => Some(show_run(run_id)),
+        Some(Action::Stats { ref window, json }) => Some(print_stats(window, json)),
+        Some(Action::Config { action: ConfigAction::Validate }) => Some(validate_config(args.config.clone())),
+        Some(Action::Diff { ref old, ref new, .. }) => Some(diff_captures(args.config.clone(), old.clone(), new.clone()).await),
+        Some(Action::Doctor) => {
+            run_doctor(args.config.clone()).await;
+            return;
+        }
+        Some(Action::SummarizeSession { ref name, ref window }) => Some(summarize_session(args.config.clone(), name, window).await),
+        _ => None,
+    };
+    if let Some(result) = subcommand {
+        if let Err(e) = result {
+            exit_on_error(&e);
+        }
+        return;
+    }
+
+    // Captures reused by `rerun --resummarize-instead` are summarized again in place
+    let mut reused_capture: Option<(PathBuf, i32)> = None;
+    // The arguments the command was given as, kept so `rerun` can rebuild it exactly
+    let mut argv: Option<Vec<String>> = None;
+    let command_str = match &args.action {
+        Some(Action::Rerun { file, resummarize_instead }) => {
+            let metadata = parse_metadata_from_file(file)
+                .unwrap_or_else(|| exit_on_error(&CtxGuardError::NotACapture(file.clone())));
+            if *resummarize_instead {
+                reused_capture = Some((file.clone(), metadata.exit_code));
+            }
+            // Captures from older versions only have the joined command
+            argv = metadata.argv;
+            match argv {
+                Some(ref args) => join_argv(args),
+                None => metadata.command,
+            }
+        }
+        Some(Action::History { .. } | Action::Stats { .. } | Action::Config { .. } | Action::SummarizeSession { .. } | Action::Diff { .. } | Action::Show { .. } | Action::Doctor) => unreachable!("handled before running a command"),
+        // Without a command, piped input is labelled as coming from stdin
+        None if args.summarize_stdin && args.command.is_empty() => "<stdin>".to_string(),
+        None => {
+            argv = Some(args.command.clone());
+            args.command.join(" ")
+        }
+    };
+
+    let mut config = load_config(&args).unwrap_or_else(|e| exit_on_error(&e));
+
+    for warning in config.load_warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+
+    if let Some(ref lang) = args.lang {
+        config.summary_language = lang.clone();
+    }
+    if let Some(secs) = args.timeout {
+        config.command_timeout_secs = secs;
+    }
+    if let Some(signal) = args.timeout_signal {
+        config.timeout_signal = signal;
+    }
+    if let Some(minutes) = args.recent_minutes {
+        config.command_context_minutes = minutes;
+    }
+    if args.no_context {
+        config.command_context_minutes = 0;
+    }
+
+    // Resolve the active provider: --provider wins over the per-command override
+    let provider_name = args.provider.as_deref().or(config.get_command_provider(&command_str));
+    config.provider = match config.resolve_provider(provider_name) {
+        Ok(provider) => provider,
+        Err(e) => exit_on_error(&e.into()),
+    };
+
+    // Per-command generation settings take precedence over the provider's
+    config.provider.temperature = config.get_temperature(&command_str);
+    config.provider.max_tokens = config.get_max_tokens(&command_str);
+    if args.explain {
+        config.provider.prompt = EXPLAIN_PROMPT.to_string();
+    }
+    if let Some(ref path) = args.prompt_file {
+        config.provider.prompt = match load_prompt_file(path) {
+            Ok(prompt) => prompt,
+            Err(e) => exit_on_error(&e.into()),
+        };
+    }
+
+    if args.plan {
+        print_plan(&args, &config, &command_str);
+        return;
+    }
+
+    // Commands missing from the allowlist are neither saved nor sent to the LLM
+    if config.is_outside_allowlist(&command_str) && reused_capture.is_none() {
+        if args.debug_llm {
+            eprintln!("'{}' has no [commands] entry and allowlist_only is set; running it without capturing", command_str);
+        }
+        run_passthrough(&args, &config, &command_str);
+    }
+    // Interactive programs need the terminal; a block in [commands] still applies
+    let listed_passthrough = config.is_passthrough_command(&command_str) && !config.is_command_blocked(&command_str);
+    if listed_passthrough && reused_capture.is_none() && !args.summarize_stdin {
+        if args.debug_llm {
+            eprintln!("'{}' is in passthrough_commands; running it without capturing", command_str);
+        }
+        run_passthrough(&args, &config, &command_str);
+    }
+
+    // Clean up old temporary files
+    cleanup_old_files(config.clean_up_days, config.cleanup_unmarked_files, config.use_utc);
+    if config.cache_ttl_minutes > 0 {
+        evict_expired_entries(config.cache_ttl_minutes, config.restrict_permissions);
+    }
+
+    // Warm up the model while the command runs, so a large local model isn't cold by
+    // the time the output is ready
+    let summary_wanted = args.force_summary || (!args.no_summarize && !config.is_summary_disabled(&command_str));
+    let preflight = (config.preflight_model && summary_wanted).then(|| {
+        let provider = config.provider.clone();
+        tokio::spawn(async move {
+            if let Ok(client) = LlmClient::from_provider(&provider) {
+                let _ = client.warm_up(&provider.model).await;
+            }
+        })
+    });
+
+    // Recorded in the capture, and given to the model when include_cwd_in_prompt is set
+    let cwd = std::env::current_dir().ok().map(|dir| dir.display().to_string());
+    // A capture summarized again keeps its ID
+    let run_id = reused_capture
+        .as_ref()
+        .and_then(|(path, _)| parse_metadata_from_file(path))
+        .and_then(|metadata| metadata.run_id)
+        .unwrap_or_else(generate_run_id);
+
+    // Execute the command, or load the output of the capture being summarized again
+    let cmd_exec_start_time = Instant::now();
+    let run = if let Some((ref capture_path, exit_code)) = reused_capture {
+        read_output_from_file(capture_path)
+            .map(|output| CommandRun::from(ExecutionResult::from_captured(exit_code, output)))
+            .ok_or_else(|| CtxGuardError::UnreadableCapture(capture_path.clone()))
+    } else if args.summarize_stdin {
+        let mut input = Vec::new();
+        std::io::stdin()
+            .read_to_end(&mut input)
+            .map(|_| CommandRun::from(ExecutionResult::from_captured(args.exit_code, input)))
+            .map_err(CtxGuardError::Stdin)
+    } else {
+        let metadata = CommandMetadata {
+            command: command_str.clone(),
+            argv: argv.clone(),
+            tags: args.tags(),
+            cwd: cwd.clone(),
+            run_id: Some(run_id.clone()),
+            ..Default::default()
+        };
+        run_command(&args, &config, &metadata)
+    };
+    let CommandRun { mut result, attempts, partial_path, stream_layout, pipeline_steps, resources_before } = run.unwrap_or_else(|e| exit_on_error(&e));
+    // Long streamed output is only kept in memory from its end on: the capture is then
+    // written from the partial one, which holds all of it
+    let streamed_capture = partial_path.clone().zip(stream_layout).filter(|_| result.omitted_bytes > 0);
+    if result.omitted_bytes > 0 {
+        let note = format!("... (first {} bytes omitted; the capture file has the complete output) ...\n", result.omitted_bytes);
+        result.combined_output.insert_str(0, &note);
+    }
     let cmd_exec_duration = cmd_exec_start_time.elapsed();
+    let resources = resources_before.and_then(|before| ResourceUsage::children().map(|after| after.since(&before)));
+    if args.resources && resources.is_none() && reused_capture.is_none() && !args.summarize_stdin {
+        eprintln!("Warning: Resource usage is not available on this platform");
+    }
+
+    // Binary output is saved byte-for-byte but never sent to the LLM
+    let binary_output = config.binary_detection && is_likely_binary(&result.raw_output);
+    // The line budget applies to everything downstream: the file, the threshold and the prompt
+    let result = match args.max_output_lines {
+        Some(max_lines) if !binary_output => result.map_text(|text| cap_output_lines(text, max_lines as usize)),
+        _ => result,
+    };
+    // The configured transformations (e.g. collapse_progress) apply to everything except binary output
+    let display_output = if binary_output {
+        result.combined_output.clone()
+    } else {
+        result.display_output(config.process_options())
+    };
+
+    let summary_words = config.get_summary_words(&command_str);
+    let output_length_threshold = config.get_output_length_threshold(&command_str);
+    // Short output is summarized too when asked for, or when it explains a failure
+    let ignore_threshold = args.force_summary || args.summarize_anyway || (config.summarize_all_failures && !result.is_success());
+
+    // A success without output has nothing worth reading or saving
+    let trivial_success = result.is_success() && display_output.trim().is_empty();
+
+    // Output that is printed in full doesn't need a file when the command was quick
+    let capture_optional = reused_capture.is_none()
+        && args.wrap_file.is_none()
+        && args.path_file.is_none()
+        && !args.print_path_only
+        && !binary_output;
+    let small_capture = config.skip_small_captures
+        && !ignore_threshold
+        && cmd_exec_duration.as_millis() <= u128::from(config.small_capture_max_ms)
+        && display_output.split_whitespace().count() as u32 <= output_length_threshold;
+    let skip_capture = capture_optional && (small_capture || (config.skip_trivial_captures && trivial_success));
+
+    // The file can hold a single stream, independently of what the model sees
+    let file_output = (config.file_streams != OutputStream::Combined && !binary_output)
+        .then(|| result.stream_output(config.file_streams, config.process_options()));
+    let file_text = file_output.as_deref().unwrap_or(&display_output);
 
     // Write output to temp file with metadata (initially without summary)
     let output_file_start_time = Instant::now();
-    let filename = generate_output_filename(&command_str);
+    let extension = if config.smart_extensions && !binary_output {
+        detect_output_extension(&command_str, file_text)
+    } else {
+        "txt"
+    };
+    let (capture_parent, filename) = capture_location(&args, &config, &command_str, extension);
     let metadata = CommandMetadata {
         command: command_str.clone(),
         exit_code: result.exit_code,
-        timestamp: Local::now(),
+        timestamp: current_time(config.use_utc),
         summary: None,
+        resources,
+        argv: argv.clone(),
+        tags: args.tags(),
+        summary_source: None,
+        cwd: cwd.clone(),
+        run_id: Some(run_id.clone()),
     };
-    let output_path = match write_output_file(&filename, &result.combined_output, Some(&metadata)) {
-        Ok(path) => path,
-        Err(e) => {
-            eprintln!("Error writing output file: {}", e);
-            std::process::exit(1);
+    let file_content = if binary_output {
+        result.raw_output.as_slice()
+    } else {
+        file_text.as_bytes()
+    };
+    // A failed write must not fail the run: the summary doesn't depend on the file
+    let resummarizing = reused_capture.is_some();
+    let output_path = if let Some((capture_path, _)) = reused_capture {
+        Some(capture_path)
+    } else if skip_capture && streamed_capture.is_none() {
+        None
+    } else {
+        let write_result = match streamed_capture {
+            Some((partial, layout)) => {
+                let stream = if binary_output { OutputStream::Combined } else { config.file_streams };
+                let text = (!binary_output).then(|| StreamedText {
+                    collapse_progress: config.collapse_progress,
+                    max_lines: args.max_output_lines.map(|max_lines| max_lines as usize),
+                });
+                let dir = if args.wrap_file.is_some() {
+                    Ok(capture_parent.clone())
+                } else {
+                    ensure_capture_dir(&filename, config.capture_options())
+                };
+                dir.and_then(|dir| write_streamed_output_file(&partial, &layout.parts(stream, config.retry_capture_all), &dir, &filename, &metadata, text, config.capture_options()))
+            }
+            None if args.wrap_file.is_some() => write_output_file_in(&capture_parent, &filename, file_content, Some(&metadata), config.capture_options()),
+            None => write_output_file(&filename, file_content, Some(&metadata), config.capture_options()),
+        };
+        match write_result {
+            Ok(path) => Some(path),
+            Err(e) => {
+                eprintln!("Warning: Failed to write output file, continuing without it: {}", e);
+                None
+            }
         }
     };
+    // The finished capture replaces the partial one; keep it only if nothing replaced it
+    if let Some(ref partial_path) = partial_path {
+        if (output_path.is_some() || skip_capture) && output_path.as_ref() != Some(partial_path) {
+            let _ = std::fs::remove_file(partial_path);
+        }
+    }
     let output_file_duration = output_file_start_time.elapsed();
 
-    // Get summary words for this command   
-    let summary_words = config.get_summary_words(&command_str);
-    let output_length_threshold = config.get_output_length_threshold(&command_str);
-
-    // Get recent commands if command_context_minutes is enabled
-    let recent_commands: Option<Vec<(String, i32)>> = if config.command_context_minutes > 0 {
-        let recent = get_recent_commands(config.command_context_minutes);
+    // Get recent commands if requested for this run or command_context_minutes is enabled
+    let recent = if args.no_context {
+        None
+    } else if let Some(count) = args.context_count {
+        Some(get_last_commands(count, config.use_utc))
+    } else if config.command_context_minutes > 0 {
+        Some(get_recent_commands(config.command_context_minutes, config.use_utc))
+    } else {
+        None
+    };
+    let recent_commands: Option<Vec<(String, i32)>> = if let Some(recent) = recent {
         if recent.is_empty() {
+            // Explain why the prompt has no history even though context was asked for
+            if args.debug_llm {
+                let window = match args.context_count {
+                    Some(_) => "in the output directory".to_string(),
+                    None => format!("from the last {} minutes", config.command_context_minutes),
+                };
+                eprintln!("Note: recent-command context is enabled, but no commands {} were found; ${{recent_commands}} will be empty.", window);
+            }
             None
         } else {
             Some(recent.iter().map(|(cmd, code, _)| (cmd.clone(), *code)).collect())
@@ -103,51 +1140,178 @@ async fn main() {
 
     // Generate summary
     let summary_start_time = Instant::now();
-    let summary = if result.combined_output.trim().is_empty() {
+    let mut summary_failed = false;
+    let mut llm_summarized = false;
+    let mut summary_source = None;
+    let output_size = format_output_size(&display_output);
+    let summary = if display_output.trim().is_empty() {
         if result.is_success() {
-            format!("Command completed successfully in {:.1} seconds with no output.", cmd_exec_duration.as_secs_f64())
+            config.format_empty_output(result.exit_code, cmd_exec_duration)
         } else {
-            format!("Command failed after {:.1} seconds with exit code {} and no output.", cmd_exec_duration.as_secs_f64(), result.exit_code)
+            let (note, benign) = config.get_empty_failure_settings(&command_str);
+            let mut message = if benign {
+                format!("Command exited with code {} after {:.1} seconds and no output (expected for this command).", result.exit_code, cmd_exec_duration.as_secs_f64())
+            } else {
+                config.format_empty_output(result.exit_code, cmd_exec_duration)
+            };
+            if let Some(note) = note {
+                message.push_str(&format!("\n\nNote: {}", note));
+            }
+            if config.command_context_minutes > 0 {
+                if let Some(previous) = previous_empty_run_note(&command_str, output_path.as_deref(), config.command_context_minutes) {
+                    message.push_str(&format!("\n\n{}", previous));
+                }
+            }
+            message
         }
+    } else if binary_output {
+        format!(
+            "{} {} after {:.1} seconds (binary output, not summarized; {} bytes saved).",
+            command_str,
+            config.status_label(result.exit_code),
+            cmd_exec_duration.as_secs_f64(),
+            result.raw_output.len()
+        )
+    } else if config.is_summary_disabled(&command_str) && !args.force_summary && !args.no_summarize {
+        format!(
+            "{} {} after {:.1} seconds and produced {} (summarization disabled for this command). Output:\n\n{}",
+            command_str,
+            config.status_label(result.exit_code),
+            cmd_exec_duration.as_secs_f64(),
+            output_size,
+            format_fallback_output(&display_output, 20)
+        )
     } else {
-        let output_text = result.combined_output.trim();
+        let output_text = display_output.trim();
         let output_word_count = output_text.split_whitespace().count() as u32;
 
-        if !args.force_summary && output_word_count <= output_length_threshold {
-            let status = if result.is_success() {
-                "succeeded"
+        // Per-run flags take precedence over the command's settings and the threshold
+        // Either a short output or a quick success is enough to skip the summary
+        let quick_success = result.is_success() && config.finished_quickly(cmd_exec_duration);
+        if args.no_summarize || (!ignore_threshold && (output_word_count <= output_length_threshold || quick_success)) {
+            let status = config.status_label(result.exit_code);
+            // Output too long to summarize is only shown in full when asked for
+            let (reason, shown_output) = if args.no_summarize {
+                ("summarization skipped with --no-summarize".to_string(), output_text.to_string())
+            } else if output_word_count <= output_length_threshold {
+                (format!("output shorter than {} words", output_length_threshold), output_text.to_string())
             } else {
-                "failed"
+                (
+                    format!("finished within {} seconds", config.summarize_if_duration_exceeds_secs),
+                    format_fallback_output(&display_output, 20),
+                )
             };
             format!(
-                "{} {} after {:.1} seconds (output shorter than {} words; returning raw output):\n\n{}",
+                "{} {} after {:.1} seconds and produced {} ({}; returning raw output):\n\n{}",
                 command_str,
                 status,
                 cmd_exec_duration.as_secs_f64(),
-                output_length_threshold,
-                output_text
+                output_size,
+                reason,
+                shown_output
             )
         } else {
-            let recent_commands_ref = recent_commands.as_ref().map(|v| v.as_slice());
-            let prompt = config.format_prompt(&command_str, result.exit_code, &result.combined_output, summary_words, recent_commands_ref);
+            let recent_commands_ref = recent_commands.as_deref();
+            let prompt_output = apply_summary_window(
+                &result.output_for_prompt(config.process_options()),
+                config.get_summary_window(&command_str),
+            );
+            let prompt_context = PromptContext {
+                recent_commands: recent_commands_ref,
+                user_context: args.append_context.as_deref(),
+                cwd: cwd.as_deref(),
+                pipeline_steps: pipeline_steps.as_deref(),
+            };
+            let prompt = config.format_prompt(&command_str, result.exit_code, &prompt_output, summary_words, &prompt_context);
             
-            let llm_client = LlmClient::new(&config.provider.url);
-            match llm_client.summarize(&config.provider.model, &prompt).await {
-                Ok(raw_summary) => {
-                    strip_think_blocks(&raw_summary)
+            // Resummarizing asks for a fresh summary, so it bypasses the cache
+            let mut prompt_cache = (config.cache_ttl_minutes > 0 && !args.no_cache && !resummarizing)
+                .then(|| PromptCache::open(config.restrict_permissions))
+                .and_then(Result::ok);
+            let cached_summary = prompt_cache
+                .as_ref()
+                .and_then(|cache| cache.get(&prompt, config.cache_ttl_minutes))
+                .map(str::to_string);
+
+            // Let the warmup finish first so the server isn't loading the model twice
+            if let (Some(preflight), None) = (preflight, &cached_summary) {
+                let _ = preflight.await;
+            }
+
+            if args.debug_llm {
+                print_debug_block(&format!("prompt for {} at {}", config.provider.model, config.provider.url), &prompt);
+            }
+
+            let describe_source = |kind: &str| format!("{} ({}, {})", kind, config.provider.r#type, config.provider.model);
+            let source_kind = if cached_summary.is_some() { "cache" } else { "llm" };
+            let summary_result = match cached_summary {
+                Some(summary) => {
+                    if args.debug_llm {
+                        print_debug_block("cached summary (no request sent)", &summary);
+                    }
+                    Ok(summary)
                 }
-                Err(_) => {
-                    // Fallback to truncated output
-                    let truncated = format_fallback_output(&result.combined_output, 20);
-                    let status = if result.is_success() {
-                        "succeeded"
+                None => match LlmClient::from_provider(&config.provider) {
+                    Ok(llm_client) => {
+                        let llm_client = llm_client.with_verbose(args.debug_llm);
+                        if config.verify_summary && !args.no_verify {
+                            llm_client.summarize_and_verify(&config.provider.model, &prompt, &prompt_output).await
+                        } else {
+                            llm_client.summarize(&config.provider.model, &prompt).await
+                        }
+                    }
+                    Err(e) => Err(e),
+                }
+                .inspect(|raw_summary| {
+                    if args.debug_llm {
+                        print_debug_block("raw response", raw_summary);
+                    }
+                })
+                .inspect_err(|e| {
+                    if args.debug_llm {
+                        print_debug_block("request failed", &e.to_string());
+                    }
+                })
+                .map(|raw_summary| {
+                    let summary = strip_think_blocks(&raw_summary);
+                    if let Some(ref mut cache) = prompt_cache {
+                        cache.insert(&prompt, &summary);
+                        if let Err(e) = cache.save() {
+                            eprintln!("Warning: {}", e);
+                        }
+                    }
+                    summary
+                }),
+            };
+            match summary_result {
+                Ok(summary) => {
+                    // Models don't always keep to ${summary_words}; cap it before it is saved or printed
+                    let summary = if config.enforce_summary_length {
+                        truncate_words(&summary, summary_words as usize)
                     } else {
-                        "failed"
+                        summary
                     };
-                    format!("{} {} after {:.1} seconds. Output:\n\n{}", 
+                    llm_summarized = true;
+                    summary_source = Some(describe_source(source_kind));
+                    format!("{}\n\nThe command produced {} of output.", summary, output_size)
+                }
+                Err(e) => {
+                    if let LlmError::PromptTooLarge { .. } | LlmError::ErrorResponse(_) | LlmError::RateLimited(_) = e {
+                        eprintln!("Warning: {}", e);
+                    }
+                    if e.is_unreachable() && config.show_setup_hint && config.is_default_provider() {
+                        print_setup_hint_once(&config.provider.url);
+                    }
+                    summary_failed = true;
+                    summary_source = Some(describe_source("fallback"));
+                    // Fallback to truncated output
+                    let truncated = format_fallback_output(&display_output, 20);
+                    let status = config.status_label(result.exit_code);
+                    format!("{} {} after {:.1} seconds and produced {}. Output:\n\n{}", 
                         command_str, 
                         status, 
                         cmd_exec_duration.as_secs_f64(),
+                        output_size,
                         truncated
                     )
                 }
@@ -155,15 +1319,93 @@ async fn main() {
         }
     };
     let summary_duration = summary_start_time.elapsed();
+    let summary = match resources {
+        Some(usage) => format!("{}\n\nResource usage: {}.", summary, usage),
+        None => summary,
+    };
+    let summary = if result.timed_out {
+        format!("{}\n\nThe command was stopped after its timeout of {}.", summary, describe_timeout(&config))
+    } else {
+        summary
+    };
+    let summary = if attempts > 1 {
+        let captured = if config.retry_capture_all { "all attempts" } else { "the final attempt" };
+        format!("{}\n\nThe command was run {} times; the output is from {}.", summary, attempts, captured)
+    } else {
+        summary
+    };
 
     // Update output file with summary in metadata
-    if let Err(e) = update_output_file_summary(&output_path, &summary) {
-        eprintln!("Warning: Failed to update output file with summary: {}", e);
+    if let Some(ref output_path) = output_path {
+        let update = update_output_file_metadata(output_path, |metadata| {
+            metadata.summary = Some(summary.clone());
+            metadata.summary_source = summary_source.clone();
+            metadata.run_id = Some(run_id.clone());
+        });
+        if let Err(e) = update {
+            eprintln!("Warning: Failed to update output file with summary: {}", e);
+        }
+        if config.write_summary_sidecar {
+            if let Err(e) = write_summary_sidecar(output_path, &summary) {
+                eprintln!("Warning: Failed to write summary file: {}", e);
+            }
+        }
     }
 
-    // Print summary and file path
-    println!("{}", summary);
-    println!("\nThe complete output is available at {}, prefer reading parts of the output from the file (grep, tail, etc.) instead of the whole thing", output_path.display());
+    // Expose the capture path for scripts
+    let absolute_output_path = output_path
+        .as_ref()
+        .map(|path| std::fs::canonicalize(path).unwrap_or_else(|_| path.clone()));
+    if let (Some(path_file), Some(absolute_path)) = (&args.path_file, &absolute_output_path) {
+        if let Err(e) = std::fs::write(path_file, format!("{}\n", absolute_path.display())) {
+            eprintln!("Warning: Failed to write output path to {}: {}", path_file.display(), e);
+        }
+    }
+
+    // Print summary and file path; with --raw-stdout, stdout carries only the command's output
+    macro_rules! report {
+        ($($arg:tt)*) => {
+            if args.raw_stdout {
+                eprintln!($($arg)*)
+            } else {
+                println!($($arg)*)
+            }
+        };
+    }
+    if args.print_path_only {
+        if let Some(ref absolute_path) = absolute_output_path {
+            report!("{}", absolute_path.display());
+        }
+    } else if config.quiet_trivial_success && trivial_success {
+        // Nothing to report: the exit code says it all
+    } else {
+        report!("{}", summary);
+        if let (true, Some(source)) = (config.annotate_summary, &summary_source) {
+            report!("\nSummary source: {}", source);
+        }
+        if config.show_run_id {
+            report!("\nRun ID: {}", run_id);
+        }
+        // Only summaries and the truncated fallback leave out part of the output
+        let output_abridged = (llm_summarized || summary_failed) && !binary_output;
+        if args.full_on_failure && !result.is_success() && output_abridged {
+            let (output, truncated) = tail_bytes(display_output.trim_end(), FULL_OUTPUT_MAX_BYTES);
+            if truncated {
+                report!("\nFull output (last {} KB):\n\n{}", FULL_OUTPUT_MAX_BYTES / 1024, output);
+            } else {
+                report!("\nFull output:\n\n{}", output);
+            }
+        } else if llm_summarized && config.include_tail > 0 {
+            report!("\nLast {} lines of output:\n\n{}", config.include_tail, tail_lines(&display_output, config.include_tail as usize));
+        }
+        if let Some(ref output_path) = output_path {
+            if let Some(guidance) = config.format_guidance(output_path) {
+                report!("\n{}", guidance);
+            }
+        } else if !skip_capture {
+            report!("\nThe complete output could not be saved to a file.");
+        }
+    }
 
     const DEBUG: bool = false;
     if DEBUG {
@@ -172,6 +1414,11 @@ async fn main() {
         println!("Output file writing took {:.1} seconds", output_file_duration.as_secs_f64());
     }
 
+    // Only signal a degraded summary when explicitly requested and the command itself succeeded
+    if args.exit_on_summary_failure && summary_failed && result.is_success() {
+        std::process::exit(SUMMARY_FAILURE_EXIT_CODE);
+    }
+
     // Exit with the same code as the original command
     std::process::exit(result.exit_code);
 }
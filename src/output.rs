@@ -1,5 +1,8 @@
-use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use crate::executor::{CapturePart, ResourceUsage};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
 use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 
@@ -7,116 +10,644 @@ use thiserror::Error;
 pub enum OutputError {
     #[error("Failed to create output directory: {0}")]
     DirectoryError(#[from] std::io::Error),
+    #[error("Invalid time '{0}': expected YYYY-MM-DD, YYYY-MM-DDTHH:MM[:SS] or an RFC 3339 timestamp")]
+    InvalidTime(String),
+    #[error("Invalid tag '{0}': tags may only contain letters, digits, '-' and '_'")]
+    InvalidTag(String),
+    #[error("Output path is too long for the filesystem ({} bytes): {}", .0.as_os_str().len(), .0.display())]
+    PathTooLong(PathBuf),
 }
 
 const OUTPUT_DIR: &str = "/tmp/ctx_guard";
 const METADATA_START: &str = "---CTX_GUARD_METADATA---";
 const METADATA_END: &str = "---END_METADATA---";
+/// Longest filename (in bytes) accepted by common filesystems
+const MAX_FILENAME_BYTES: usize = 255;
+/// Longest path (in bytes) the OS accepts
+#[cfg(windows)]
+const MAX_PATH_BYTES: usize = 260;
+#[cfg(not(windows))]
+const MAX_PATH_BYTES: usize = 4096;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct CommandMetadata {
     pub command: String,
     pub exit_code: i32,
-    pub timestamp: DateTime<Local>,
+    /// When the command ran, in local time or UTC depending on `use_utc`
+    pub timestamp: DateTime<FixedOffset>,
     pub summary: Option<String>,
+    /// Resources used by the command, when measured with `--resources`
+    pub resources: Option<ResourceUsage>,
+    /// The arguments `command` was joined from, when it came from the command line
+    pub argv: Option<Vec<String>>,
+    /// Labels given with `--tag`, for finding the run later
+    pub tags: Vec<String>,
+    /// How the summary was produced, e.g. `llm (lmstudio, qwen/qwen3-vl-4b)`, when an
+    /// LLM summary was requested
+    pub summary_source: Option<String>,
+    /// Working directory the command ran in
+    pub cwd: Option<String>,
+    /// Short random ID printed with the summary, for finding the run with `cg show`
+    pub run_id: Option<String>,
+}
+
+/// Directory captures are written to, without creating it.
+pub fn output_dir() -> PathBuf {
+    PathBuf::from(OUTPUT_DIR)
+}
+
+/// How captures are written, taken from the config (see `Config::capture_options`) and
+/// passed to the functions that create captures and their directories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureOptions {
+    /// Make the output directory and capture files accessible to their owner only (Unix)
+    pub restrict_permissions: bool,
+    /// Start the output with a `$ command` / `exit: N` transcript header
+    pub transcript_header: bool,
+    /// Write captures to a `YYYY-MM-DD` subdirectory of the output directory, named after
+    /// the date in their filename
+    pub subdir_by_date: bool,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        Self { restrict_permissions: true, transcript_header: false, subdir_by_date: false }
+    }
+}
+
+/// Format of day directory names
+const DAY_DIR_FORMAT: &str = "%Y-%m-%d";
+
+/// Returns the date a day directory is named after, or None for any other path.
+fn day_dir_date(path: &Path) -> Option<NaiveDate> {
+    let name = path.file_name()?.to_str()?;
+    // Parsing alone would also accept e.g. `2024-1-5`
+    if name.len() != 10 {
+        return None;
+    }
+    NaiveDate::parse_from_str(name, DAY_DIR_FORMAT).ok()
 }
 
-pub fn ensure_output_dir() -> Result<PathBuf, OutputError> {
+/// Directory a capture with this filename goes in: the output directory, or with
+/// `subdir_by_date` its subdirectory for the date in the filename. Doesn't create it.
+pub fn capture_dir(filename: &str, options: CaptureOptions) -> PathBuf {
+    match filename_timestamp(filename).filter(|_| options.subdir_by_date) {
+        Some(time) => output_dir().join(time.format(DAY_DIR_FORMAT).to_string()),
+        None => output_dir(),
+    }
+}
+
+/// Creates `capture_dir(filename)` if needed, with the same permissions as the output
+/// directory.
+pub fn ensure_capture_dir(filename: &str, options: CaptureOptions) -> Result<PathBuf, OutputError> {
+    let root = ensure_output_dir(options.restrict_permissions)?;
+    let dir = capture_dir(filename, options);
+    if dir != root {
+        #[cfg(unix)]
+        if options.restrict_permissions {
+            use std::os::unix::fs::DirBuilderExt;
+            fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+            return Ok(dir);
+        }
+        fs::create_dir_all(&dir)?;
+    }
+    Ok(dir)
+}
+
+/// Creates (or truncates) a file for captured output, as `0600` on Unix when `restrict`
+/// is set. Existing files keep their mode.
+fn create_capture_file(path: &Path, restrict: bool) -> std::io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if restrict {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// Creates the output directory if needed. With `restrict_permissions`, it is created as
+/// `0700` on Unix, and an existing one readable by others (e.g. from an older version) is
+/// tightened if we own it.
+pub fn ensure_output_dir(restrict_permissions: bool) -> Result<PathBuf, OutputError> {
     let dir = Path::new(OUTPUT_DIR);
+    #[cfg(unix)]
+    if restrict_permissions {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+        if !dir.exists() {
+            fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        } else if fs::metadata(dir)?.permissions().mode() & 0o077 != 0 {
+            // Fails harmlessly if the directory belongs to another user
+            let _ = fs::set_permissions(dir, fs::Permissions::from_mode(0o700));
+        }
+        return Ok(dir.to_path_buf());
+    }
     if !dir.exists() {
         fs::create_dir_all(dir)?;
     }
     Ok(dir.to_path_buf())
 }
 
-pub fn generate_output_filename(command: &str) -> String {
-    let timestamp = Local::now().format("%Y%m%d_%H%M%S");
-    let command_slug = command
-        .replace(' ', "_")
-        .replace('/', "_")
-        .replace('\\', "_")
-        .replace('|', "_")
-        .replace('&', "_")
-        .replace(';', "_")
-        .replace('>', "_")
-        .replace('<', "_")
-        .replace('*', "_")
-        .replace('?', "_")
-        .replace('"', "_")
-        .replace('\'', "_")
+/// Creates the output directory if needed and checks that a file can be written to it.
+pub fn check_output_dir_writable(restrict_permissions: bool) -> Result<PathBuf, OutputError> {
+    let dir = ensure_output_dir(restrict_permissions)?;
+    let probe = dir.join(format!(".write_check_{}", std::process::id()));
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)?;
+    Ok(dir)
+}
+
+/// Short, stable hash of a command string (FNV-1a, 32 bits as 8 hex chars).
+/// Stable across runs and Rust versions, so it can be used to look up all captures
+/// of the same command by filename prefix.
+pub fn command_hash(command: &str) -> String {
+    let hash = fnv1a_64(command.as_bytes());
+    // Fold the 64-bit hash down to 32 bits to keep filenames short
+    format!("{:08x}", (hash ^ (hash >> 32)) as u32)
+}
+
+/// Generates a short random run ID: 12 hex digits (48 bits), so IDs stay unique among
+/// the captures kept over any realistic retention window.
+pub fn generate_run_id() -> String {
+    use std::hash::{BuildHasher, Hasher};
+
+    // RandomState is seeded from the OS once per process and varies per instance after that
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+    hasher.write_u128(nanos);
+    hasher.write_u32(std::process::id());
+    format!("{:012x}", hasher.finish() & 0xffff_ffff_ffff)
+}
+
+/// 64-bit FNV-1a hash, used where a stable (non-randomized) hash is needed on disk.
+pub(crate) fn fnv1a_64(bytes: &[u8]) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Extensions used for capture files. `txt` is the default; the others are only
+/// produced when `smart_extensions` is enabled (see `detect_output_extension`).
+pub const CAPTURE_EXTENSIONS: &[&str] = &["txt", "json", "diff", "log"];
+
+/// Returns the capture filename without its extension, if the file has one of the
+/// capture extensions.
+fn capture_file_stem(filename: &str) -> Option<&str> {
+    let (stem, extension) = filename.rsplit_once('.')?;
+    CAPTURE_EXTENSIONS.contains(&extension).then_some(stem)
+}
+
+/// Picks a file extension from the command and the shape of its output, so editors
+/// open captures in a suitable mode: `json` for JSON documents, `diff` for patches,
+/// `log` for logs and stack traces, and `txt` otherwise.
+pub fn detect_output_extension(command: &str, output: &str) -> &'static str {
+    let trimmed = output.trim();
+    if (trimmed.starts_with('{') || trimmed.starts_with('['))
+        && serde_json::from_str::<serde_json::Value>(trimmed).is_ok()
+    {
+        return "json";
+    }
+
+    let first_word = command.split_whitespace().next().unwrap_or("");
+    let is_diff_command = first_word == "diff" || command.starts_with("git diff") || command.starts_with("git show");
+    let looks_like_diff = trimmed.starts_with("diff --git")
+        || (trimmed.starts_with("--- ") && trimmed.contains("\n+++ ") && trimmed.contains("\n@@"));
+    if looks_like_diff || (is_diff_command && trimmed.contains("\n@@")) {
+        return "diff";
+    }
+
+    const LOG_MARKERS: &[&str] = &["Traceback (most recent call last)", "panicked at", "Exception", "\n    at ", "ERROR", "WARN"];
+    if LOG_MARKERS.iter().any(|marker| trimmed.contains(marker)) {
+        return "log";
+    }
+
+    "txt"
+}
+
+/// The current time in UTC when `use_utc` is set, otherwise in local time.
+pub fn current_time(use_utc: bool) -> DateTime<FixedOffset> {
+    if use_utc {
+        Utc::now().fixed_offset()
+    } else {
+        Local::now().fixed_offset()
+    }
+}
+
+/// Builds the capture filename: `{command_slug}_{YYYYMMDD_HHMMSS}.{extension}`, or
+/// `{command_slug}_{command_hash}_{YYYYMMDD_HHMMSS}.{extension}` when `include_hash` is set.
+/// The timestamp is in UTC when `use_utc` is set, otherwise in local time.
+pub fn generate_output_filename(command: &str, include_hash: bool, extension: &str, use_utc: bool) -> String {
+    generate_output_filename_in(&output_dir(), command, include_hash, extension, use_utc)
+}
+
+/// Like `generate_output_filename`, but shortens the command slug further when needed so
+/// the file name and its full path in `dir` stay within filesystem limits.
+pub fn generate_output_filename_in(dir: &Path, command: &str, include_hash: bool, extension: &str, use_utc: bool) -> String {
+    let timestamp = current_time(use_utc).format("%Y%m%d_%H%M%S");
+    let suffix = if include_hash {
+        format!("_{}_{timestamp}.{extension}", command_hash(command))
+    } else {
+        format!("_{timestamp}.{extension}")
+    };
+
+    // Room left for the slug, counting the separator between the directory and the name
+    let path_budget = MAX_PATH_BYTES.saturating_sub(dir.as_os_str().len() + 1);
+    let slug_budget = MAX_FILENAME_BYTES.min(path_budget).saturating_sub(suffix.len());
+
+    let mut command_slug = String::new();
+    for c in command
+        .replace([' ', '/', '\\', '|', '&', ';', '>', '<', '*', '?', '"', '\'', '\n', '\r', '\t'], "_")
         .chars()
         .take(50)
-        .collect::<String>();
-    
-    format!("{command_slug}_{timestamp}.txt")
+    {
+        if command_slug.len() + c.len_utf8() > slug_budget {
+            break;
+        }
+        command_slug.push(c);
+    }
+
+    format!("{command_slug}{suffix}")
+}
+
+/// Encodes a command for the single-line `command:` field. Commands spanning several lines
+/// (or starting with a quote) are written as a JSON string; others are written as is, so
+/// the common case stays readable.
+fn encode_command(command: &str) -> String {
+    if command.contains(['\n', '\r']) || command.starts_with('"') {
+        serde_json::to_string(command).expect("strings are always serializable")
+    } else {
+        command.to_string()
+    }
+}
+
+/// Reverses `encode_command`. Values that aren't a valid JSON string are taken literally.
+fn decode_command(value: &str) -> String {
+    if value.starts_with('"') {
+        if let Ok(command) = serde_json::from_str::<String>(value) {
+            return command;
+        }
+    }
+    value.to_string()
 }
 
 fn format_metadata(metadata: &CommandMetadata) -> String {
     let summary_line = if let Some(ref summary) = metadata.summary {
-        format!("summary: {}\n", summary.replace('\n', " ").replace('\r', " "))
+        format!("summary: {}\n", summary.replace(['\n', '\r'], " "))
     } else {
         "summary: \n".to_string()
     };
     
+    let mut optional_lines = String::new();
+    if let Some(ref resources) = metadata.resources {
+        optional_lines.push_str(&format!("resources: {}\n", resources.to_metadata_value()));
+    }
+    if let Some(ref argv) = metadata.argv {
+        optional_lines.push_str(&format!("argv: {}\n", serde_json::to_string(argv).expect("strings are always serializable")));
+    }
+    if !metadata.tags.is_empty() {
+        optional_lines.push_str(&format!("tags: {}\n", metadata.tags.join(",")));
+    }
+    if let Some(ref source) = metadata.summary_source {
+        optional_lines.push_str(&format!("summary_source: {}\n", source));
+    }
+    if let Some(ref cwd) = metadata.cwd {
+        // Encoded like the command, in case the path contains a newline
+        optional_lines.push_str(&format!("cwd: {}\n", encode_command(cwd)));
+    }
+    if let Some(ref run_id) = metadata.run_id {
+        optional_lines.push_str(&format!("run_id: {}\n", run_id));
+    }
+    
     format!(
-        "{}\ncommand: {}\nexit_code: {}\ntimestamp: {}\n{}\n{}\n",
+        "{}\ncommand: {}\nexit_code: {}\ntimestamp: {}\n{}{}\n{}\n",
         METADATA_START,
-        metadata.command,
+        encode_command(&metadata.command),
         metadata.exit_code,
         metadata.timestamp.to_rfc3339(),
+        optional_lines,
         summary_line,
         METADATA_END
     )
 }
 
-pub fn write_output_file(filename: &str, content: &str, metadata: Option<&CommandMetadata>) -> Result<PathBuf, OutputError> {
-    let dir = ensure_output_dir()?;
+/// The lines put before the output when `transcript_header` is on, so the capture reads
+/// like a terminal session.
+fn format_transcript_header(metadata: &CommandMetadata) -> String {
+    format!("$ {}\nexit: {}\n\n", metadata.command, metadata.exit_code)
+}
+
+/// Removes the transcript header from the start of a capture's output, if it has one.
+fn strip_transcript_header<'a>(metadata: &CommandMetadata, output: &'a [u8]) -> &'a [u8] {
+    output
+        .strip_prefix(format_transcript_header(metadata).as_bytes())
+        .unwrap_or(output)
+}
+
+pub fn write_output_file(filename: &str, content: impl AsRef<[u8]>, metadata: Option<&CommandMetadata>, options: CaptureOptions) -> Result<PathBuf, OutputError> {
+    let dir = ensure_capture_dir(filename, options)?;
+    write_output_file_in(&dir, filename, content, metadata, options)
+}
+
+/// Like `write_output_file`, but writes into `dir` (created if missing) instead of the
+/// default output directory.
+pub fn write_output_file_in(dir: &Path, filename: &str, content: impl AsRef<[u8]>, metadata: Option<&CommandMetadata>, options: CaptureOptions) -> Result<PathBuf, OutputError> {
+    fs::create_dir_all(dir)?;
     let file_path = dir.join(filename);
     
-    let file_content = if let Some(meta) = metadata {
-        format!("{}\n\n{}", format_metadata(meta), content)
+    let content = content.as_ref();
+    let mut file_content = if let Some(meta) = metadata {
+        format!("{}\n\n", format_metadata(meta)).into_bytes()
     } else {
-        content.to_string()
+        Vec::new()
     };
+    // Binary captures are kept byte for byte
+    if let Some(meta) = metadata.filter(|_| options.transcript_header && !is_likely_binary(content)) {
+        file_content.extend_from_slice(format_transcript_header(meta).as_bytes());
+    }
+    file_content.extend_from_slice(content);
     
-    fs::write(&file_path, file_content)?;
-    Ok(file_path)
+    match create_capture_file(&file_path, options.restrict_permissions).and_then(|mut file| file.write_all(&file_content)) {
+        Ok(()) => Ok(file_path),
+        Err(e) if is_name_too_long(&e) => Err(OutputError::PathTooLong(file_path)),
+        Err(e) => Err(e.into()),
+    }
 }
 
-pub fn parse_metadata_from_file(file_path: &Path) -> Option<CommandMetadata> {
-    let content = match fs::read_to_string(file_path) {
-        Ok(c) => c,
-        Err(_) => return None,
+/// How output read back from a streamed capture is processed, the way output held in
+/// memory is (see `write_streamed_output_file`).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamedText {
+    /// Collapse carriage-return redraws, as `collapse_carriage_returns` does
+    pub collapse_progress: bool,
+    /// Keep only the first and last lines past this many, as `cap_output_lines` does
+    pub max_lines: Option<usize>,
+}
+
+/// Writes the finished capture `dir/filename` of a command whose output was streamed to
+/// `partial` (see `start_output_file`), made of `parts` of that output. The output is
+/// copied over piece by piece, so it never has to fit in memory. With `text`, it is
+/// processed line by line like a capture written from memory; without, it is copied
+/// byte for byte (binary output). `partial` may be the finished capture's own path.
+pub fn write_streamed_output_file(partial: &Path, parts: &[CapturePart], dir: &Path, filename: &str, metadata: &CommandMetadata, text: Option<StreamedText>, options: CaptureOptions) -> Result<PathBuf, OutputError> {
+    fs::create_dir_all(dir)?;
+    let file_path = dir.join(filename);
+    let mut source = fs::File::open(partial)?;
+    // The output starts after the end marker's line and the blank line start_output_file
+    // puts after the header
+    let head = read_head(&mut source)?;
+    let output_start = head
+        .windows(METADATA_END.len())
+        .position(|window| window == METADATA_END.as_bytes())
+        .map_or(0, |pos| (pos + METADATA_END.len() + "\n\n\n".len()) as u64);
+
+    let tmp_path = file_path.with_file_name(format!(".{}.tmp{}", filename, std::process::id()));
+    let written = create_capture_file(&tmp_path, options.restrict_permissions)
+        .and_then(|file| {
+            let mut out = std::io::BufWriter::new(file);
+            write!(out, "{}\n\n", format_metadata(metadata))?;
+            match text {
+                Some(text) => {
+                    if options.transcript_header {
+                        out.write_all(format_transcript_header(metadata).as_bytes())?;
+                    }
+                    copy_streamed_text(&mut source, output_start, parts, text, &mut out)?;
+                }
+                None => copy_parts(&mut source, output_start, parts, |chunk| out.write_all(chunk))?,
+            }
+            out.flush()
+        })
+        .and_then(|_| fs::rename(&tmp_path, &file_path));
+    match written {
+        Ok(()) => Ok(file_path),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp_path);
+            if is_name_too_long(&e) {
+                Err(OutputError::PathTooLong(file_path))
+            } else {
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// Passes `parts` to `write` in order, reading the ranges of output from `source`, where
+/// the output starts `output_start` bytes in.
+fn copy_parts(source: &mut fs::File, output_start: u64, parts: &[CapturePart], mut write: impl FnMut(&[u8]) -> std::io::Result<()>) -> std::io::Result<()> {
+    let mut buffer = vec![0u8; 64 * 1024];
+    for part in parts {
+        match *part {
+            CapturePart::Text(ref text) => write(text.as_bytes())?,
+            CapturePart::Output { start, len } => {
+                source.seek(SeekFrom::Start(output_start + start))?;
+                let mut remaining = len;
+                while remaining > 0 {
+                    let size = remaining.min(buffer.len() as u64) as usize;
+                    source.read_exact(&mut buffer[..size])?;
+                    write(&buffer[..size])?;
+                    remaining -= size as u64;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copies `parts` to `out` as text, one line at a time. With `max_lines`, the lines are
+/// counted in a first pass.
+fn copy_streamed_text(source: &mut fs::File, output_start: u64, parts: &[CapturePart], text: StreamedText, out: &mut impl Write) -> std::io::Result<()> {
+    let cap = match text.max_lines {
+        Some(max_lines) => {
+            // Counted like `str::lines`: a last line without a line break counts too
+            let (mut breaks, mut last_byte) = (0, None);
+            copy_parts(source, output_start, parts, |chunk| {
+                breaks += chunk.iter().filter(|&&b| b == b'\n').count();
+                last_byte = chunk.last().copied().or(last_byte);
+                Ok(())
+            })?;
+            let total_lines = breaks + usize::from(last_byte.is_some_and(|b| b != b'\n'));
+            (total_lines > max_lines).then(|| LineCap {
+                head: max_lines / 2,
+                tail_start: total_lines - (max_lines - max_lines / 2),
+                marker: capped_lines_marker(total_lines - max_lines, max_lines),
+            })
+        }
+        None => None,
     };
-    
-    if !content.starts_with(METADATA_START) {
+
+    let mut lines = TextLines { out, collapse_progress: text.collapse_progress, cap, line: Vec::new(), index: 0 };
+    copy_parts(source, output_start, parts, |chunk| lines.push(chunk))?;
+    if !lines.line.is_empty() {
+        lines.end_line(false)?;
+    }
+    Ok(())
+}
+
+/// Lines kept from output capped like `cap_output_lines`.
+struct LineCap {
+    /// Lines kept before the marker
+    head: usize,
+    /// Index of the first line kept after the marker
+    tail_start: usize,
+    marker: String,
+}
+
+/// Splits streamed output into lines and writes each as `copy_streamed_text` describes.
+struct TextLines<'a, W: Write> {
+    out: &'a mut W,
+    collapse_progress: bool,
+    cap: Option<LineCap>,
+    /// The line read so far
+    line: Vec<u8>,
+    /// Index of the current line
+    index: usize,
+}
+
+impl<W: Write> TextLines<'_, W> {
+    fn push(&mut self, mut chunk: &[u8]) -> std::io::Result<()> {
+        while let Some(end) = chunk.iter().position(|&b| b == b'\n') {
+            self.line.extend_from_slice(&chunk[..end]);
+            self.end_line(true)?;
+            chunk = &chunk[end + 1..];
+        }
+        self.line.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    fn end_line(&mut self, line_break: bool) -> std::io::Result<()> {
+        let line = std::mem::take(&mut self.line);
+        let index = self.index;
+        self.index += 1;
+        let Some(ref cap) = self.cap else {
+            self.write_line(&line)?;
+            return if line_break { self.out.write_all(b"\n") } else { Ok(()) };
+        };
+
+        // Capped output joins the lines it keeps, as `head_lines` and `tail_lines` do
+        let separator = if index == cap.tail_start { cap.marker.as_str() } else { "\n" };
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+        if index < cap.head {
+            if index > 0 {
+                self.out.write_all(b"\n")?;
+            }
+            self.write_line(line)?;
+        } else if index >= cap.tail_start {
+            self.out.write_all(separator.as_bytes())?;
+            self.write_line(line)?;
+        }
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        let line = String::from_utf8_lossy(line);
+        if self.collapse_progress {
+            self.out.write_all(collapse_carriage_returns(&line).as_bytes())
+        } else {
+            self.out.write_all(line.as_bytes())
+        }
+    }
+}
+
+/// Summary stored in a capture while its command is still running. A capture that still
+/// has it after `cg` exits was interrupted.
+pub const PARTIAL_CAPTURE_SUMMARY: &str = "Partial capture: the command was still running or was interrupted.";
+
+/// Creates the capture file at `path` (and its directory) with a metadata header marking
+/// it as partial, and returns it for appending output as it arrives. Writing the finished
+/// capture replaces it.
+pub fn start_output_file(path: &Path, metadata: &CommandMetadata, options: CaptureOptions) -> Result<fs::File, OutputError> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let partial_metadata = CommandMetadata {
+        summary: Some(PARTIAL_CAPTURE_SUMMARY.to_string()),
+        ..metadata.clone()
+    };
+
+    let mut file = create_capture_file(path, options.restrict_permissions).map_err(|e| match e {
+        e if is_name_too_long(&e) => OutputError::PathTooLong(path.to_path_buf()),
+        e => e.into(),
+    })?;
+    write!(file, "{}\n\n", format_metadata(&partial_metadata))?;
+    Ok(file)
+}
+
+/// Returns true if the error means a path or file name exceeded the OS limits.
+fn is_name_too_long(error: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    if error.raw_os_error() == Some(libc::ENAMETOOLONG) {
+        return true;
+    }
+    error.kind() == std::io::ErrorKind::InvalidFilename
+}
+
+/// Returns true if the file starts with the ctx_guard metadata header, i.e. it
+/// was written by us. Only the first few bytes are read.
+pub fn has_metadata_marker(file_path: &Path) -> bool {
+    let mut file = match fs::File::open(file_path) {
+        Ok(f) => f,
+        Err(_) => return false,
+    };
+
+    let mut header = [0u8; METADATA_START.len()];
+    match file.read_exact(&mut header) {
+        Ok(()) => header == METADATA_START.as_bytes(),
+        Err(_) => false,
+    }
+}
+
+/// Splits a capture into its metadata section (up to and including the end marker)
+/// and the raw output that follows. Returns None if the file has no metadata header.
+fn split_metadata(content: &[u8]) -> Option<(&str, &[u8])> {
+    if !content.starts_with(METADATA_START.as_bytes()) {
         return None;
     }
-    
-    let metadata_end_pos = content.find(METADATA_END)?;
-    let metadata_section = &content[..metadata_end_pos + METADATA_END.len()];
-    
+
+    let metadata_end_pos = content
+        .windows(METADATA_END.len())
+        .position(|window| window == METADATA_END.as_bytes())?;
+    let section_end = metadata_end_pos + METADATA_END.len();
+    let metadata_section = std::str::from_utf8(&content[..section_end]).ok()?;
+    // Skip the blank line after the header, written as LF or CRLF
+    let output_section = &content[section_end..];
+    let output_start = output_section.iter().position(|&b| b != b'\n' && b != b'\r').unwrap_or(output_section.len());
+    Some((metadata_section, &output_section[output_start..]))
+}
+
+fn parse_metadata_section(metadata_section: &str) -> Option<CommandMetadata> {
     let mut command = None;
     let mut exit_code = None;
     let mut timestamp = None;
     let mut summary = None;
+    let mut resources = None;
+    let mut tags = Vec::new();
+    let mut argv = None;
+    let mut summary_source = None;
+    let mut cwd = None;
+    let mut run_id = None;
     
-    for line in metadata_section.lines() {
-        if line.starts_with("command: ") {
-            command = Some(line[9..].trim().to_string());
-        } else if line.starts_with("exit_code: ") {
-            exit_code = Some(line[11..].trim().parse().ok()?);
-        } else if line.starts_with("timestamp: ") {
-            timestamp = DateTime::parse_from_rfc3339(line[11..].trim())
-                .ok()
-                .map(|dt| dt.with_timezone(&Local));
-        } else if line.starts_with("summary: ") {
-            let summary_text = line[9..].trim();
-            summary = if summary_text.is_empty() {
-                None
-            } else {
-                Some(summary_text.to_string())
-            };
+    // Each line is `key: value`; the key ends at the first colon, so values may contain colons
+    for (key, value) in metadata_section.lines().filter_map(|line| line.split_once(':')) {
+        let value = value.trim();
+        match key.trim() {
+            "command" => command = Some(decode_command(value)),
+            "exit_code" => exit_code = Some(value.parse().ok()?),
+            "timestamp" => timestamp = DateTime::parse_from_rfc3339(value).ok(),
+            "resources" => resources = ResourceUsage::from_metadata_value(value),
+            "summary_source" => summary_source = Some(value.to_string()).filter(|source| !source.is_empty()),
+            "argv" => argv = serde_json::from_str(value).ok(),
+            "cwd" => cwd = Some(decode_command(value)).filter(|cwd| !cwd.is_empty()),
+            "run_id" => run_id = Some(value.to_string()).filter(|run_id| !run_id.is_empty()),
+            "tags" => tags = value.split(',').map(str::trim).filter(|tag| !tag.is_empty()).map(str::to_string).collect(),
+            "summary" => summary = Some(value.to_string()).filter(|summary| !summary.is_empty()),
+            // Unknown keys come from newer versions; ignore them
+            _ => {}
         }
     }
     
@@ -125,149 +656,737 @@ pub fn parse_metadata_from_file(file_path: &Path) -> Option<CommandMetadata> {
         exit_code: exit_code?,
         timestamp: timestamp?,
         summary,
+        resources,
+        argv,
+        tags,
+        summary_source,
+        cwd,
+        run_id,
     })
 }
 
-pub fn get_recent_commands(minutes: u32) -> Vec<(String, i32, DateTime<Local>)> {
-    let output_dir = match ensure_output_dir() {
-        Ok(dir) => dir,
-        Err(_) => {
-            eprintln!("DEBUG: Failed to get output directory");
-            return Vec::new();
-        },
+pub fn parse_metadata_from_file(file_path: &Path) -> Option<CommandMetadata> {
+    // Read as bytes: the output section may not be valid UTF-8 (e.g. binary captures)
+    let content = fs::read(file_path).ok()?;
+    let (metadata_section, _) = split_metadata(&content)?;
+    parse_metadata_section(metadata_section)
+}
+
+/// Reads the captured command output from a file, without its metadata header (or
+/// transcript header). Returns None if the file can't be read or wasn't written by ctx_guard.
+pub fn read_output_from_file(file_path: &Path) -> Option<Vec<u8>> {
+    let content = fs::read(file_path).ok()?;
+    let (metadata_section, output_section) = split_metadata(&content)?;
+    let output = match parse_metadata_section(metadata_section) {
+        Some(metadata) => strip_transcript_header(&metadata, output_section),
+        None => output_section,
     };
-    
-    let now = Local::now();
-    let cutoff_time = now - chrono::Duration::minutes(minutes as i64);
-    
-    let entries = match fs::read_dir(&output_dir) {
+    Some(output.to_vec())
+}
+
+/// Finds the capture of the run with this `run_id` in the output directory.
+pub fn find_run(run_id: &str) -> Option<RunRecord> {
+    list_runs(None, None)
+        .into_iter()
+        .find(|run| run.metadata.run_id.as_deref() == Some(run_id))
+}
+
+/// A capture file and the metadata recorded in it.
+#[derive(Debug, Clone)]
+pub struct RunRecord {
+    pub path: PathBuf,
+    pub metadata: CommandMetadata,
+}
+
+/// Lists the captures in the output directory that were run between `since` and `until`
+/// (both inclusive, either may be open), oldest first. Files without ctx_guard metadata
+/// are skipped.
+pub fn list_runs(since: Option<DateTime<Local>>, until: Option<DateTime<Local>>) -> Vec<RunRecord> {
+    list_runs_in(&output_dir(), since, until)
+}
+
+/// Like `list_runs`, but for captures in `dir` and its day directories (see
+/// `subdir_by_date`), whichever layout they were written with.
+pub fn list_runs_in(dir: &Path, since: Option<DateTime<Local>>, until: Option<DateTime<Local>>) -> Vec<RunRecord> {
+    let entries = match fs::read_dir(dir) {
         Ok(entries) => entries,
-        Err(e) => {
-            eprintln!("DEBUG: Failed to read directory: {}", e);
-            return Vec::new();
-        },
+        Err(_) => return Vec::new(),
     };
-    
-    let mut recent_commands = Vec::new();
-    
-    for entry in entries {
-        let entry = match entry {
-            Ok(e) => e,
-            Err(_) => continue,
-        };
-        
+
+    let mut paths = Vec::new();
+    for entry in entries.flatten() {
         let path = entry.path();
-        if !path.is_file() || !path.to_string_lossy().ends_with(".txt") {
+        match day_dir_date(&path) {
+            Some(day) if path.is_dir() => {
+                // Days before `since` can't hold matching runs; a day of slack covers UTC names
+                if since.is_some_and(|since| day < since.date_naive() - chrono::Duration::days(1)) {
+                    continue;
+                }
+                if let Ok(day_entries) = fs::read_dir(&path) {
+                    paths.extend(day_entries.flatten().map(|entry| entry.path()));
+                }
+            }
+            _ => paths.push(path),
+        }
+    }
+
+    let mut runs = Vec::new();
+
+    for path in paths {
+        let is_capture = path.file_name().and_then(|n| n.to_str()).and_then(capture_file_stem).is_some();
+        if !path.is_file() || !is_capture {
             continue;
         }
-        
+
+        // Skip foreign files without reading them in full
+        if !has_metadata_marker(&path) {
+            continue;
+        }
+
         let metadata = match parse_metadata_from_file(&path) {
             Some(m) => m,
             None => continue,
         };
-        
-        // Only include commands within the time window
-        if metadata.timestamp >= cutoff_time {
-            recent_commands.push((metadata.command, metadata.exit_code, metadata.timestamp));
+
+        let after_since = since.is_none_or(|since| metadata.timestamp >= since);
+        let before_until = until.is_none_or(|until| metadata.timestamp <= until);
+        if after_since && before_until {
+            runs.push(RunRecord { path, metadata });
         }
     }
-    
+
     // Sort chronologically (oldest first)
-    recent_commands.sort_by_key(|(_, _, timestamp)| *timestamp);
-    
-    recent_commands
+    runs.sort_by_key(|run| run.metadata.timestamp);
+
+    runs
+}
+
+/// Aggregate information about a set of captures, as reported by `cg stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CaptureStats {
+    pub total_files: usize,
+    pub total_bytes: u64,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub oldest: Option<DateTime<FixedOffset>>,
+    pub newest: Option<DateTime<FixedOffset>>,
+    /// Most frequently run commands with their run counts, most frequent first
+    pub top_commands: Vec<(String, usize)>,
+}
+
+/// Computes `CaptureStats` for `runs`, listing up to `top_n` most frequent commands.
+pub fn compute_stats(runs: &[RunRecord], top_n: usize) -> CaptureStats {
+    let mut counts: std::collections::HashMap<&str, usize> = std::collections::HashMap::new();
+    for run in runs {
+        *counts.entry(run.metadata.command.as_str()).or_default() += 1;
+    }
+    let mut top_commands: Vec<(String, usize)> = counts.into_iter().map(|(cmd, n)| (cmd.to_string(), n)).collect();
+    // Ties are ordered by command so the output is stable
+    top_commands.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    top_commands.truncate(top_n);
+
+    let succeeded = runs.iter().filter(|run| run.metadata.exit_code == 0).count();
+    CaptureStats {
+        total_files: runs.len(),
+        total_bytes: runs.iter().filter_map(|run| fs::metadata(&run.path).ok()).map(|m| m.len()).sum(),
+        succeeded,
+        failed: runs.len() - succeeded,
+        oldest: runs.iter().map(|run| run.metadata.timestamp).min(),
+        newest: runs.iter().map(|run| run.metadata.timestamp).max(),
+        top_commands,
+    }
+}
+
+/// Validates a `--tag` value: a non-empty string of letters, digits, `-` and `_`.
+pub fn parse_tag(value: &str) -> Result<String, OutputError> {
+    let valid = !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(value.to_string())
+    } else {
+        Err(OutputError::InvalidTag(value.to_string()))
+    }
+}
+
+/// Parses a `--since`/`--until` value as local time. Accepts a date (midnight), a date
+/// and time with `T` or a space as separator, or an RFC 3339 timestamp with an offset.
+pub fn parse_time_bound(value: &str) -> Result<DateTime<Local>, OutputError> {
+    let value = value.trim();
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&Local));
+    }
+
+    const FORMATS: &[&str] = &["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"];
+    let naive = FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| OutputError::InvalidTime(value.to_string()))?;
+
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| OutputError::InvalidTime(value.to_string()))
+}
+
+/// The `count` most recently run commands, oldest first, however long ago they ran. Times
+/// are in UTC when `use_utc` is set, otherwise in local time.
+pub fn get_last_commands(count: usize, use_utc: bool) -> Vec<(String, i32, DateTime<FixedOffset>)> {
+    let offset = *current_time(use_utc).offset();
+    let runs = list_runs(None, None);
+    let skip = runs.len().saturating_sub(count);
+
+    runs.into_iter()
+        .skip(skip)
+        .map(|run| (run.metadata.command, run.metadata.exit_code, run.metadata.timestamp.with_timezone(&offset)))
+        .collect()
+}
+
+/// Commands run in the last `minutes`, oldest first, with their run times in UTC when
+/// `use_utc` is set, otherwise in local time.
+pub fn get_recent_commands(minutes: u32, use_utc: bool) -> Vec<(String, i32, DateTime<FixedOffset>)> {
+    let cutoff_time = Local::now() - chrono::Duration::minutes(minutes as i64);
+    let offset = *current_time(use_utc).offset();
+
+    list_runs(Some(cutoff_time), None)
+        .into_iter()
+        .map(|run| (run.metadata.command, run.metadata.exit_code, run.metadata.timestamp.with_timezone(&offset)))
+        .collect()
+}
+
+/// Path of the file holding just the summary of the capture at `capture_path`:
+/// `<capture>.summary.txt` next to it.
+pub fn summary_sidecar_path(capture_path: &Path) -> PathBuf {
+    let mut name = capture_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".summary.txt");
+    capture_path.with_file_name(name)
+}
+
+/// Writes `summary` to the sidecar of the capture at `capture_path`, for tools that
+/// don't want to parse capture metadata. The sidecar gets the capture's permissions.
+pub fn write_summary_sidecar(capture_path: &Path, summary: &str) -> Result<PathBuf, OutputError> {
+    let sidecar = summary_sidecar_path(capture_path);
+    let mut file = create_capture_file(&sidecar, true)?;
+    file.set_permissions(fs::metadata(capture_path)?.permissions())?;
+    writeln!(file, "{}", summary.trim_end())?;
+    Ok(sidecar)
 }
 
 pub fn update_output_file_summary(file_path: &PathBuf, summary: &str) -> Result<(), OutputError> {
-    let content = fs::read_to_string(file_path)?;
-    
-    if !content.starts_with(METADATA_START) {
-        // File doesn't have metadata, can't update
-        return Ok(());
+    update_output_file_metadata(file_path, |metadata| metadata.summary = Some(summary.to_string()))
+}
+
+/// Reads the start of a capture, up to the end of its metadata header and the start of the
+/// output after it (or the end of the file). The file is left positioned after what was read.
+fn read_head(file: &mut fs::File) -> std::io::Result<Vec<u8>> {
+    // Anything longer isn't a header we wrote
+    const MAX_HEADER_BYTES: usize = 1024 * 1024;
+
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 8192];
+    loop {
+        let header_read = head
+            .windows(METADATA_END.len())
+            .position(|window| window == METADATA_END.as_bytes())
+            .is_some_and(|pos| head[pos + METADATA_END.len()..].iter().any(|&b| b != b'\n' && b != b'\r'));
+        if header_read || head.len() > MAX_HEADER_BYTES {
+            break;
+        }
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        head.extend_from_slice(&buffer[..read]);
     }
-    
-    let metadata_end_pos = match content.find(METADATA_END) {
-        Some(pos) => pos,
+    Ok(head)
+}
+
+/// Rewrites the metadata of a capture file with `update` applied, keeping the output as is.
+/// Files without (parseable) metadata are left untouched. Only the header is read into
+/// memory; the output is copied over to the rewritten file.
+pub fn update_output_file_metadata(file_path: &PathBuf, update: impl FnOnce(&mut CommandMetadata)) -> Result<(), OutputError> {
+    let mut file = fs::File::open(file_path)?;
+    let head = read_head(&mut file)?;
+
+    // File doesn't have metadata, can't update
+    let (metadata_section, output_start) = match split_metadata(&head) {
+        Some(parts) => parts,
         None => return Ok(()),
     };
-    
-    let metadata_section = &content[..metadata_end_pos + METADATA_END.len()];
-    let output_section = &content[metadata_end_pos + METADATA_END.len()..];
-    
-    // Parse existing metadata
-    let mut command = None;
-    let mut exit_code = None;
-    let mut timestamp = None;
-    
-    for line in metadata_section.lines() {
-        if line.starts_with("command: ") {
-            command = Some(line[9..].trim().to_string());
-        } else if line.starts_with("exit_code: ") {
-            exit_code = line[11..].trim().parse().ok();
-        } else if line.starts_with("timestamp: ") {
-            timestamp = DateTime::parse_from_rfc3339(line[11..].trim())
-                .ok()
-                .map(|dt| dt.with_timezone(&Local));
+
+    // If we can't parse the metadata, we can't update it
+    let mut updated_metadata = match parse_metadata_section(metadata_section) {
+        Some(metadata) => metadata,
+        None => return Ok(()),
+    };
+    update(&mut updated_metadata);
+
+    let file_name = file_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let tmp_path = file_path.with_file_name(format!(".{}.tmp{}", file_name, std::process::id()));
+    let permissions = file.metadata()?.permissions();
+    // Private until it takes over the original's permissions
+    let mut updated = create_capture_file(&tmp_path, true)?;
+    let written = updated
+        .set_permissions(permissions)
+        .and_then(|_| write!(updated, "{}\n\n", format_metadata(&updated_metadata)))
+        .and_then(|_| updated.write_all(output_start))
+        .and_then(|_| std::io::copy(&mut file, &mut updated).map(|_| ()))
+        .and_then(|_| fs::rename(&tmp_path, file_path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// Heuristically decides whether output is binary rather than text by inspecting its
+/// first chunk: any NUL byte, or a high ratio of control/undecodable characters.
+pub fn is_likely_binary(output: &[u8]) -> bool {
+    const SAMPLE_BYTES: usize = 8192;
+    const MAX_NON_PRINTABLE_RATIO: f64 = 0.3;
+
+    let sample = &output[..output.len().min(SAMPLE_BYTES)];
+    if sample.is_empty() {
+        return false;
+    }
+    if sample.contains(&0) {
+        return true;
+    }
+
+    let decoded = String::from_utf8_lossy(sample);
+    let mut total = 0usize;
+    let mut non_printable = 0usize;
+    for c in decoded.chars() {
+        total += 1;
+        let is_whitespace_or_escape = matches!(c, '\n' | '\r' | '\t' | '\x0c' | '\x08' | '\x1b');
+        if c == char::REPLACEMENT_CHARACTER || (c.is_control() && !is_whitespace_or_escape) {
+            non_printable += 1;
         }
     }
-    
-    if let (Some(cmd), Some(code), Some(ts)) = (command, exit_code, timestamp) {
-        let updated_metadata = CommandMetadata {
-            command: cmd,
-            exit_code: code,
-            timestamp: ts,
-            summary: Some(summary.to_string()),
-        };
-        
-        let updated_content = format!("{}\n\n{}", format_metadata(&updated_metadata), output_section.trim_start_matches('\n'));
-        fs::write(file_path, updated_content)?;
+
+    non_printable as f64 / total as f64 > MAX_NON_PRINTABLE_RATIO
+}
+
+/// Describes the size of captured output, e.g. "4,213 lines / 182 KB".
+pub fn format_output_size(output: &str) -> String {
+    let lines = output.lines().count();
+    let bytes = output.len();
+    let size = if bytes < 1024 {
+        format!("{} bytes", bytes)
+    } else if bytes < 1024 * 1024 {
+        format!("{} KB", group_thousands(bytes.div_ceil(1024)))
     } else {
-        // If we can't parse the metadata, we can't update it
-        return Ok(());
+        format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+    };
+    let line_word = if lines == 1 { "line" } else { "lines" };
+    format!("{} {} / {}", group_thousands(lines), line_word, size)
+}
+
+fn group_thousands(value: usize) -> String {
+    let digits = value.to_string();
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
     }
-    
-    Ok(())
+    grouped
 }
 
-pub fn format_fallback_output(output: &str, max_lines: usize) -> String {
-    let lines: Vec<&str> = output.lines().collect();
-    
-    if lines.len() <= max_lines * 2 {
+/// Returns the last `max_bytes` bytes of `output` (or fewer, to start on a whole line
+/// and character), and whether anything was cut off.
+pub fn tail_bytes(output: &str, max_bytes: usize) -> (&str, bool) {
+    if output.len() <= max_bytes {
+        return (output, false);
+    }
+    let mut start = output.len() - max_bytes;
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    let tail = &output[start..];
+    // Drop the partial first line, unless the cut fell on a line start
+    let tail = match tail.find('\n') {
+        Some(newline) if !output[..start].ends_with('\n') => &tail[newline + 1..],
+        _ => tail,
+    };
+    (tail, true)
+}
+
+/// Returns the last `max_lines` lines of the output (or all of it if shorter).
+pub fn tail_lines(output: &str, max_lines: usize) -> String {
+    let lines: Vec<&str> = output.trim_end().lines().collect();
+    let start = lines.len().saturating_sub(max_lines);
+    lines[start..].join("\n")
+}
+
+/// Cuts `text` after its first `max_words` words, adding "..." if anything was cut. The
+/// text before the cut keeps its spacing and line breaks. A limit of 0 keeps everything.
+pub fn truncate_words(text: &str, max_words: usize) -> String {
+    if max_words == 0 {
+        return text.to_string();
+    }
+    let mut words = 0;
+    let mut in_word = false;
+    for (i, c) in text.char_indices() {
+        if !c.is_whitespace() {
+            in_word = true;
+            continue;
+        }
+        if in_word {
+            words += 1;
+            in_word = false;
+            if words == max_words {
+                if text[i..].trim().is_empty() {
+                    break;
+                }
+                return format!("{}...", &text[..i]);
+            }
+        }
+    }
+    text.to_string()
+}
+
+/// Lists the lines removed from `old` ("- " prefix) and added in `new` ("+ " prefix),
+/// in output order, without unchanged context. Empty if the outputs have the same lines.
+pub fn diff_lines(old: &str, new: &str) -> String {
+    // Above this many comparison cells the changed region is listed as removed then added
+    const MAX_LCS_CELLS: usize = 4_000_000;
+
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let prefix = old_lines.iter().zip(&new_lines).take_while(|(a, b)| a == b).count();
+    let suffix = old_lines[prefix..]
+        .iter()
+        .rev()
+        .zip(new_lines[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_changed = &old_lines[prefix..old_lines.len() - suffix];
+    let new_changed = &new_lines[prefix..new_lines.len() - suffix];
+
+    let mut diff = Vec::new();
+    if (old_changed.len() + 1) * (new_changed.len() + 1) > MAX_LCS_CELLS {
+        diff.extend(old_changed.iter().map(|line| format!("- {}", line)));
+        diff.extend(new_changed.iter().map(|line| format!("+ {}", line)));
+        return diff.join("\n");
+    }
+
+    // lcs[i][j]: length of the longest common subsequence of old_changed[i..] and new_changed[j..]
+    let width = new_changed.len() + 1;
+    let mut lcs = vec![0u32; (old_changed.len() + 1) * width];
+    for i in (0..old_changed.len()).rev() {
+        for j in (0..new_changed.len()).rev() {
+            lcs[i * width + j] = if old_changed[i] == new_changed[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    while i < old_changed.len() || j < new_changed.len() {
+        if i < old_changed.len() && j < new_changed.len() && old_changed[i] == new_changed[j] {
+            i += 1;
+            j += 1;
+        } else if j == new_changed.len() || (i < old_changed.len() && lcs[(i + 1) * width + j] >= lcs[i * width + j + 1]) {
+            diff.push(format!("- {}", old_changed[i]));
+            i += 1;
+        } else {
+            diff.push(format!("+ {}", new_changed[j]));
+            j += 1;
+        }
+    }
+    diff.join("\n")
+}
+
+/// Collapses carriage-return redraws (progress bars, spinners) to what a terminal would
+/// show: within a line, each `\r` moves back to the start and later text overwrites
+/// earlier text. Erase-line sequences (`ESC[K`, `ESC[2K`) are honoured and other escape
+/// sequences on redrawn lines are dropped. Lines without a bare `\r` are left unchanged.
+pub fn collapse_carriage_returns(output: &str) -> String {
+    if !output.contains('\r') {
         return output.to_string();
     }
-    
-    let first_lines: Vec<&str> = lines.iter().take(max_lines).copied().collect();
-    let last_lines: Vec<&str> = lines.iter().rev().take(max_lines).rev().copied().collect();
-    
+
+    output
+        .split('\n')
+        .map(|line| {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            if line.contains('\r') {
+                render_redrawn_line(line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn render_redrawn_line(line: &str) -> String {
+    let mut screen: Vec<char> = Vec::new();
+    let mut cursor = 0;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\r' => cursor = 0,
+            '\x1b' if chars.peek() == Some(&'[') => {
+                chars.next();
+                // CSI parameters run until the final byte in '@'..='~'
+                let mut params = String::new();
+                let mut final_byte = None;
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        final_byte = Some(c);
+                        break;
+                    }
+                    params.push(c);
+                }
+                if final_byte == Some('K') {
+                    match params.as_str() {
+                        "" | "0" => screen.truncate(cursor),
+                        "2" => {
+                            screen.clear();
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {
+                if cursor < screen.len() {
+                    screen[cursor] = c;
+                } else {
+                    screen.resize(cursor, ' ');
+                    screen.push(c);
+                }
+                cursor += 1;
+            }
+        }
+    }
+
+    screen.into_iter().collect::<String>().trim_end().to_string()
+}
+
+/// Returns the first `max_lines` lines of the output (or all of it if shorter).
+pub fn head_lines(output: &str, max_lines: usize) -> String {
+    output.lines().take(max_lines).collect::<Vec<_>>().join("\n")
+}
+
+/// Which part of the output is sent to the LLM. The capture file always keeps everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum SummaryWindow {
+    #[default]
+    Whole,
+    /// The first N lines
+    Head(usize),
+    /// The last N lines
+    Tail(usize),
+    /// The first and last N lines
+    Both(usize),
+}
+
+impl std::str::FromStr for SummaryWindow {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if value.eq_ignore_ascii_case("whole") {
+            return Ok(SummaryWindow::Whole);
+        }
+        let invalid = || format!("invalid summary_window '{}': expected \"whole\", \"head:N\", \"tail:N\" or \"both:N\"", value);
+        let (kind, count) = value.split_once(':').ok_or_else(invalid)?;
+        let count: usize = count.trim().parse().map_err(|_| invalid())?;
+        match kind.trim().to_ascii_lowercase().as_str() {
+            "head" => Ok(SummaryWindow::Head(count)),
+            "tail" => Ok(SummaryWindow::Tail(count)),
+            "both" => Ok(SummaryWindow::Both(count)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for SummaryWindow {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl std::fmt::Display for SummaryWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SummaryWindow::Whole => write!(f, "whole"),
+            SummaryWindow::Head(n) => write!(f, "head:{}", n),
+            SummaryWindow::Tail(n) => write!(f, "tail:{}", n),
+            SummaryWindow::Both(n) => write!(f, "both:{}", n),
+        }
+    }
+}
+
+impl From<SummaryWindow> for String {
+    fn from(window: SummaryWindow) -> Self {
+        window.to_string()
+    }
+}
+
+/// Selects the part of the output covered by `window`, marking where lines were left out.
+pub fn apply_summary_window(output: &str, window: SummaryWindow) -> String {
+    let total_lines = output.lines().count();
+    match window {
+        SummaryWindow::Whole => output.to_string(),
+        SummaryWindow::Head(n) if total_lines > n => {
+            format!("{}\n\n... ({} more lines omitted) ...", head_lines(output, n), total_lines - n)
+        }
+        SummaryWindow::Tail(n) if total_lines > n => {
+            format!("... ({} earlier lines omitted) ...\n\n{}", total_lines - n, tail_lines(output, n))
+        }
+        SummaryWindow::Both(n) => join_head_and_tail(output, n),
+        _ => output.to_string(),
+    }
+}
+
+/// Limits output to `max_lines` lines, keeping the first and last half and noting how many
+/// lines were dropped. Used for `--max-output-lines`.
+pub fn cap_output_lines(output: &str, max_lines: usize) -> String {
+    let total_lines = output.lines().count();
+    if total_lines <= max_lines {
+        return output.to_string();
+    }
+
+    let head = max_lines / 2;
+    let tail = max_lines - head;
+    format!(
+        "{}{}{}",
+        head_lines(output, head),
+        capped_lines_marker(total_lines - max_lines, max_lines),
+        tail_lines(output, tail)
+    )
+}
+
+/// What `cap_output_lines` puts between the lines it keeps.
+fn capped_lines_marker(omitted: usize, max_lines: usize) -> String {
+    format!("\n\n... ({} lines omitted; output capped at {} lines) ...\n\n", omitted, max_lines)
+}
+
+/// Keeps the first and last `max_lines` lines with an omission marker between them.
+fn join_head_and_tail(output: &str, max_lines: usize) -> String {
+    let total_lines = output.lines().count();
+    if total_lines <= max_lines * 2 {
+        return output.to_string();
+    }
+
     format!(
         "{}\n\n... ({} lines omitted) ...\n\n{}",
-        first_lines.join("\n"),
-        lines.len() - (max_lines * 2),
-        last_lines.join("\n")
+        head_lines(output, max_lines),
+        total_lines - (max_lines * 2),
+        tail_lines(output, max_lines)
     )
 }
 
+/// Upper bound on the size of `format_fallback_output`, so output with very long
+/// lines (e.g. minified files) can't slip through the line-based limit.
+pub const FALLBACK_MAX_BYTES: usize = 8 * 1024;
+
+pub fn format_fallback_output(output: &str, max_lines: usize) -> String {
+    truncate_middle(&join_head_and_tail(output, max_lines), FALLBACK_MAX_BYTES)
+}
+
+/// Keeps the first and last `max_bytes / 2` bytes of `text` (on character boundaries)
+/// and replaces the middle with a marker saying how much was dropped.
+fn truncate_middle(text: &str, max_bytes: usize) -> String {
+    if text.len() <= max_bytes {
+        return text.to_string();
+    }
+
+    let mut head_end = max_bytes / 2;
+    while !text.is_char_boundary(head_end) {
+        head_end -= 1;
+    }
+    let mut tail_start = text.len() - max_bytes / 2;
+    while !text.is_char_boundary(tail_start) {
+        tail_start += 1;
+    }
+
+    format!(
+        "{} ... ({} bytes omitted) ... {}",
+        &text[..head_end],
+        tail_start - head_end,
+        &text[tail_start..]
+    )
+}
+
+/// Parses the timestamp at the end of a capture filename.
+/// Format: {command_slug}_{YYYYMMDD_HHMMSS}.{extension}, where the timestamp is always the
+/// last two underscore-separated parts before the extension.
+fn filename_timestamp(filename: &str) -> Option<NaiveDateTime> {
+    let without_ext = capture_file_stem(filename)?;
+    let parts: Vec<&str> = without_ext.split('_').collect();
+
+    // Need at least 3 parts: command_slug, date (YYYYMMDD), time (HHMMSS)
+    if parts.len() < 3 {
+        return None;
+    }
+    let date_part = parts[parts.len() - 2];
+    let time_part = parts[parts.len() - 1];
+
+    // Verify they match the expected format (8 digits for date, 6 digits for time)
+    let is_digits = |part: &str, len: usize| part.len() == len && part.chars().all(|c| c.is_ascii_digit());
+    if !is_digits(date_part, 8) || !is_digits(time_part, 6) {
+        return None;
+    }
+    NaiveDateTime::parse_from_str(&format!("{}_{}", date_part, time_part), "%Y%m%d_%H%M%S").ok()
+}
+
+fn file_timestamp(time: &NaiveDateTime, use_utc: bool) -> Option<DateTime<FixedOffset>> {
+    if use_utc {
+        return Some(Utc.from_utc_datetime(time).fixed_offset());
+    }
+    match Local.from_local_datetime(time) {
+        chrono::LocalResult::Single(dt) => Some(dt.fixed_offset()),
+        _ => None,
+    }
+}
+
 /// Clean up old files from the output directory that are older than the specified number of days.
-/// Files that don't match the expected naming pattern are skipped.
+/// Files that don't match the expected naming pattern are skipped, as are files without the
+/// ctx_guard metadata header unless `include_unmarked` is set (for captures from older versions).
+/// Filename timestamps are read as UTC when `use_utc` is set, matching `generate_output_filename`.
+/// Day directories (see `subdir_by_date`) are only looked into once their day has reached the
+/// cutoff, and removed when that leaves them empty.
 /// Errors during cleanup are logged but don't cause the function to fail.
-pub fn cleanup_old_files(days: u32) {
-    let output_dir = match ensure_output_dir() {
-        Ok(dir) => dir,
-        Err(e) => {
-            eprintln!("Warning: Failed to access output directory for cleanup: {}", e);
-            return;
-        }
-    };
+pub fn cleanup_old_files(days: u32, include_unmarked: bool, use_utc: bool) {
+    let output_dir = output_dir();
+    // Nothing written yet, so nothing to clean up
+    if !output_dir.exists() {
+        return;
+    }
 
     let cutoff_time = Local::now() - chrono::Duration::days(days as i64);
-    
-    let entries = match fs::read_dir(&output_dir) {
+    cleanup_dir(&output_dir, cutoff_time, include_unmarked, use_utc);
+}
+
+fn cleanup_dir(dir: &Path, cutoff_time: DateTime<Local>, include_unmarked: bool, use_utc: bool) {
+    let entries = match fs::read_dir(dir) {
         Ok(entries) => entries,
         Err(e) => {
             eprintln!("Warning: Failed to read output directory for cleanup: {}", e);
             return;
         }
     };
+    // Day directories are named in the same timezone as the filenames in them
+    let cutoff_day = if use_utc {
+        cutoff_time.with_timezone(&Utc).date_naive()
+    } else {
+        cutoff_time.date_naive()
+    };
 
     for entry in entries {
         let entry = match entry {
@@ -279,74 +1398,60 @@ pub fn cleanup_old_files(days: u32) {
         };
 
         let path = entry.path();
-        
-        // Skip if not a file
-        if !path.is_file() {
-            continue;
-        }
-
-        let filename = match path.file_name().and_then(|n| n.to_str()) {
-            Some(name) => name,
-            None => {
-                eprintln!("Warning: Skipping file with invalid name: {:?}", path);
-                continue;
+        if path.is_dir() {
+            // Later days can't hold anything old enough, so they aren't read at all
+            if day_dir_date(&path).is_some_and(|day| day <= cutoff_day) {
+                cleanup_dir(&path, cutoff_time, include_unmarked, use_utc);
+                // Fails (harmlessly) while the directory still has files in it
+                let _ = fs::remove_dir(&path);
             }
-        };
-
-        // Parse timestamp from filename
-        // Format: {command_slug}_{YYYYMMDD_HHMMSS}.txt
-        // The timestamp is always the last two underscore-separated parts before .txt
-        if !filename.ends_with(".txt") {
             continue;
         }
-
-        let without_ext = &filename[..filename.len() - 4];
-        let parts: Vec<&str> = without_ext.split('_').collect();
-        
-        // Need at least 3 parts: command_slug, date (YYYYMMDD), time (HHMMSS)
-        if parts.len() < 3 {
-            // Doesn't match expected pattern, skip
-            continue;
+        if path.is_file() {
+            cleanup_file(&path, cutoff_time, include_unmarked, use_utc);
         }
+    }
+}
 
-        // Get the last two parts (date and time)
-        let date_part = parts[parts.len() - 2];
-        let time_part = parts[parts.len() - 1];
-        
-        // Verify they match the expected format (8 digits for date, 6 digits for time)
-        if date_part.len() != 8 || time_part.len() != 6 {
-            continue;
+fn cleanup_file(path: &Path, cutoff_time: DateTime<Local>, include_unmarked: bool, use_utc: bool) {
+    let filename = match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name,
+        None => {
+            eprintln!("Warning: Skipping file with invalid name: {:?}", path);
+            return;
         }
-        
-        // Check if they're numeric
-        if !date_part.chars().all(|c| c.is_ascii_digit()) || 
-           !time_part.chars().all(|c| c.is_ascii_digit()) {
-            continue;
+    };
+
+    // Files without a capture name and timestamp aren't ours; skip them
+    let file_time = match filename_timestamp(filename) {
+        Some(time) => time,
+        None => return,
+    };
+
+    // Interpret the filename in the same timezone it was written in
+    let file_datetime = match file_timestamp(&file_time, use_utc) {
+        Some(dt) => dt,
+        None => {
+            eprintln!("Warning: Invalid datetime for file: {}", filename);
+            return;
         }
-        
-        let timestamp_str = format!("{}_{}", date_part, time_part);
-        
-        let file_time = match NaiveDateTime::parse_from_str(&timestamp_str, "%Y%m%d_%H%M%S") {
-            Ok(dt) => dt,
-            Err(_) => {
-                // Doesn't match expected timestamp format, skip
-                continue;
-            }
-        };
+    };
 
-        // Convert to Local DateTime for comparison
-        let file_datetime = match Local.from_local_datetime(&file_time) {
-            chrono::LocalResult::Single(dt) => dt,
-            _ => {
-                eprintln!("Warning: Invalid datetime for file: {}", filename);
-                continue;
-            }
-        };
+    // Never delete files we didn't write unless explicitly asked to
+    if !include_unmarked && !has_metadata_marker(path) {
+        return;
+    }
 
-        // Delete if older than cutoff
-        if file_datetime < cutoff_time {
-            if let Err(e) = fs::remove_file(&path) {
-                eprintln!("Warning: Failed to delete old file {}: {}", filename, e);
+    // Delete if older than cutoff, together with its summary sidecar
+    if file_datetime < cutoff_time {
+        if let Err(e) = fs::remove_file(path) {
+            eprintln!("Warning: Failed to delete old file {}: {}", filename, e);
+            return;
+        }
+        let sidecar = summary_sidecar_path(path);
+        if sidecar.exists() {
+            if let Err(e) = fs::remove_file(&sidecar) {
+                eprintln!("Warning: Failed to delete old file {}: {}", sidecar.display(), e);
             }
         }
     }
@@ -359,7 +1464,7 @@ mod tests {
 
     #[test]
     fn test_generate_output_filename() {
-        let filename = generate_output_filename("npx jest");
+        let filename = generate_output_filename("npx jest", false, "txt", false);
         assert!(filename.starts_with("npx_jest_"));
         assert!(filename.ends_with(".txt"));
         assert!(filename.contains("_"));
@@ -367,7 +1472,7 @@ mod tests {
 
     #[test]
     fn test_generate_output_filename_special_chars() {
-        let filename = generate_output_filename("curl -v https://example.com");
+        let filename = generate_output_filename("curl -v https://example.com", false, "txt", false);
         assert!(filename.contains("curl"));
         // The space before -v should be replaced with underscore
         assert!(filename.contains("-v") || filename.contains("_v"));
@@ -378,12 +1483,77 @@ mod tests {
     #[test]
     fn test_generate_output_filename_long_command() {
         let long_command = "a".repeat(100);
-        let filename = generate_output_filename(&long_command);
+        let filename = generate_output_filename(&long_command, false, "txt", false);
         // Should be truncated to 50 chars for command slug
         let parts: Vec<&str> = filename.split('_').collect();
         assert!(parts[0].len() <= 50);
     }
 
+    #[test]
+    fn test_generate_output_filename_with_hash() {
+        let filename = generate_output_filename("cargo test", true, "txt", false);
+        let prefix = format!("cargo_test_{}_", command_hash("cargo test"));
+        assert!(filename.starts_with(&prefix));
+        assert!(filename.ends_with(".txt"));
+        // The timestamp stays last so cleanup can still parse it
+        let parts: Vec<&str> = filename.trim_end_matches(".txt").split('_').collect();
+        assert_eq!(parts[parts.len() - 2].len(), 8);
+        assert_eq!(parts[parts.len() - 1].len(), 6);
+    }
+
+    #[test]
+    fn test_detect_output_extension() {
+        assert_eq!(detect_output_extension("curl api", "{\"ok\": true}\n"), "json");
+        assert_eq!(detect_output_extension("jq . data.json", "[1, 2, 3]"), "json");
+        assert_eq!(detect_output_extension("echo", "{not json"), "txt");
+        assert_eq!(detect_output_extension("git diff", "diff --git a/x b/x\n--- a/x\n+++ b/x\n@@ -1 +1 @@\n"), "diff");
+        assert_eq!(detect_output_extension("diff a b", "--- a\n+++ b\n@@ -1 +1 @@\n-x\n+y"), "diff");
+        assert_eq!(detect_output_extension("cargo test", "thread 'main' panicked at src/main.rs:1:1"), "log");
+        assert_eq!(detect_output_extension("ls", "Cargo.toml\nsrc"), "txt");
+    }
+
+    #[test]
+    fn test_cleanup_old_files_handles_smart_extensions() {
+        let old_date = Local::now() - chrono::Duration::days(10);
+        let metadata = CommandMetadata {
+            command: "curl api".to_string(),
+            exit_code: 0,
+            timestamp: old_date.fixed_offset(),
+            summary: None,
+            ..Default::default()
+        };
+        let filename = format!("test_smart_ext_{}.json", old_date.format("%Y%m%d_%H%M%S"));
+        let path = write_output_file(&filename, "{}", Some(&metadata), CaptureOptions::default()).unwrap();
+
+        cleanup_old_files(5, false, false);
+        assert!(!path.exists(), "Old .json capture should be deleted");
+    }
+
+    #[test]
+    fn test_command_hash() {
+        let hash = command_hash("cargo test");
+        assert_eq!(hash.len(), 8);
+        assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(hash, command_hash("cargo test"));
+        // Commands that share a slug still get distinct hashes
+        assert_ne!(command_hash("cargo test"), command_hash("cargo/test"));
+
+        let hashes: std::collections::HashSet<String> = (0..10_000)
+            .map(|i| command_hash(&format!("run {}", i)))
+            .collect();
+        assert_eq!(hashes.len(), 10_000);
+    }
+
+    #[test]
+    fn test_generate_run_id() {
+        let run_id = generate_run_id();
+        assert_eq!(run_id.len(), 12);
+        assert!(run_id.chars().all(|c| c.is_ascii_hexdigit()));
+
+        let run_ids: std::collections::HashSet<String> = (0..10_000).map(|_| generate_run_id()).collect();
+        assert_eq!(run_ids.len(), 10_000);
+    }
+
     #[test]
     fn test_format_fallback_output_short() {
         let output = "line1\nline2\nline3";
@@ -403,9 +1573,460 @@ mod tests {
         assert!(!formatted.contains("line50")); // Should be in omitted section
     }
 
+    #[test]
+    fn test_format_fallback_output_single_long_line() {
+        // A minified blob without newlines must still be bounded
+        let output = format!("start{}end", "é".repeat(1_000_000));
+        let formatted = format_fallback_output(&output, 20);
+
+        assert!(formatted.len() < FALLBACK_MAX_BYTES + 100);
+        assert!(formatted.starts_with("start"));
+        assert!(formatted.ends_with("end"));
+
+        let (head, rest) = formatted.split_once(" ... (").unwrap();
+        let (omitted, tail) = rest.split_once(" bytes omitted) ... ").unwrap();
+        assert_eq!(head.len() + omitted.parse::<usize>().unwrap() + tail.len(), output.len());
+    }
+
+    #[test]
+    fn test_list_runs_in_time_window() {
+        let dir = std::env::temp_dir().join(format!("ctx_guard_list_runs_{}", std::process::id()));
+        let at = |hour| Local.with_ymd_and_hms(2024, 1, 1, hour, 0, 0).unwrap();
+        for (name, hour) in [("early", 8), ("incident", 12), ("late", 18)] {
+            let metadata = CommandMetadata {
+                command: name.to_string(),
+                timestamp: at(hour).fixed_offset(),
+                ..Default::default()
+            };
+            write_output_file_in(&dir, &format!("{}_20240101_{:02}0000.txt", name, hour), "output", Some(&metadata), CaptureOptions::default()).unwrap();
+        }
+        fs::write(dir.join("foreign_20240101_120000.txt"), "no metadata").unwrap();
+
+        let all: Vec<String> = list_runs_in(&dir, None, None).into_iter().map(|r| r.metadata.command).collect();
+        assert_eq!(all, vec!["early", "incident", "late"]);
+
+        let window: Vec<String> = list_runs_in(&dir, Some(at(10)), Some(at(12)))
+            .into_iter()
+            .map(|r| r.metadata.command)
+            .collect();
+        assert_eq!(window, vec!["incident"]);
+
+        let since: Vec<String> = list_runs_in(&dir, Some(at(12)), None).into_iter().map(|r| r.metadata.command).collect();
+        assert_eq!(since, vec!["incident", "late"]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_list_runs_in_reads_day_directories() {
+        let dir = std::env::temp_dir().join(format!("ctx_guard_list_runs_days_{}", std::process::id()));
+        let at = |day, hour| Local.with_ymd_and_hms(2024, 1, day, hour, 0, 0).unwrap();
+        for (subdir, name, day) in [("", "flat", 1), ("2024-01-02", "bucketed", 2), ("2023-12-01", "older", 1), ("notes", "elsewhere", 1)] {
+            let metadata = CommandMetadata {
+                command: name.to_string(),
+                timestamp: at(day, 12).fixed_offset(),
+                ..Default::default()
+            };
+            let filename = format!("{}_202401{:02}_120000.txt", name, day);
+            write_output_file_in(&dir.join(subdir), &filename, "output", Some(&metadata), CaptureOptions::default()).unwrap();
+        }
+
+        // Both layouts are read, but only directories named after a day
+        let all: Vec<String> = list_runs_in(&dir, None, None).into_iter().map(|r| r.metadata.command).collect();
+        assert_eq!(all, vec!["flat", "older", "bucketed"]);
+
+        // Day directories well before `since` are skipped without being read
+        let since: Vec<String> = list_runs_in(&dir, Some(at(1, 0)), None).into_iter().map(|r| r.metadata.command).collect();
+        assert_eq!(since, vec!["flat", "bucketed"]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_capture_dir_names() {
+        assert_eq!(
+            filename_timestamp("cargo_test_20240102_030405.txt"),
+            NaiveDate::from_ymd_opt(2024, 1, 2).unwrap().and_hms_opt(3, 4, 5)
+        );
+        assert_eq!(filename_timestamp("cargo_test_1a2b3c4d_20240102_030405.log").unwrap().format(DAY_DIR_FORMAT).to_string(), "2024-01-02");
+        assert_eq!(filename_timestamp("notes.txt"), None);
+        assert_eq!(filename_timestamp("cargo_test_20240102_030405.txt.summary.txt"), None);
+
+        assert_eq!(day_dir_date(Path::new("/tmp/ctx_guard/2024-01-02")), NaiveDate::from_ymd_opt(2024, 1, 2));
+        assert_eq!(day_dir_date(Path::new("/tmp/ctx_guard/2024-1-2")), None);
+        assert_eq!(day_dir_date(Path::new("/tmp/ctx_guard/notes")), None);
+
+        // Off by default, so captures stay in the output directory itself
+        assert_eq!(capture_dir("cargo_test_20240102_030405.txt", CaptureOptions::default()), output_dir());
+        let by_date = CaptureOptions { subdir_by_date: true, ..Default::default() };
+        assert_eq!(capture_dir("cargo_test_20240102_030405.txt", by_date), output_dir().join("2024-01-02"));
+        assert_eq!(capture_dir("notes.txt", by_date), output_dir());
+    }
+
+    #[test]
+    fn test_cleanup_removes_expired_day_directories() {
+        let dir = std::env::temp_dir().join(format!("ctx_guard_cleanup_days_{}", std::process::id()));
+        let capture = |date: DateTime<Local>, name: &str, marked: bool| {
+            let day_dir = dir.join(date.format(DAY_DIR_FORMAT).to_string());
+            let filename = format!("{}_{}.txt", name, date.format("%Y%m%d_%H%M%S"));
+            let metadata = CommandMetadata { command: name.to_string(), timestamp: date.fixed_offset(), ..Default::default() };
+            write_output_file_in(&day_dir, &filename, "output", marked.then_some(&metadata), CaptureOptions::default()).unwrap();
+            day_dir
+        };
+        let old_day = capture(Local::now() - chrono::Duration::days(10), "old", true);
+        let foreign_day = capture(Local::now() - chrono::Duration::days(20), "foreign", false);
+        let recent_day = capture(Local::now() - chrono::Duration::hours(1), "recent", true);
+
+        cleanup_dir(&dir, Local::now() - chrono::Duration::days(5), false, false);
+        assert!(!old_day.exists(), "Emptied day directory should be removed");
+        // Files we didn't write are kept, and so is their directory
+        assert_eq!(fs::read_dir(&foreign_day).unwrap().count(), 1);
+        assert_eq!(fs::read_dir(&recent_day).unwrap().count(), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_truncate_words() {
+        assert_eq!(truncate_words("Build failed in src/main.rs: missing semicolon.", 3), "Build failed in...");
+        assert_eq!(truncate_words("Tests passed.\n\nNo warnings.", 3), "Tests passed.\n\nNo...");
+        // Text within the limit, including trailing whitespace, is left alone
+        assert_eq!(truncate_words("Tests passed.\n", 2), "Tests passed.\n");
+        assert_eq!(truncate_words("Tests passed.", 5), "Tests passed.");
+        assert_eq!(truncate_words("Tests passed.", 0), "Tests passed.");
+        assert_eq!(truncate_words("Prüfung fehlgeschlagen wegen Fehler", 2), "Prüfung fehlgeschlagen...");
+    }
+
+    #[test]
+    fn test_compute_stats() {
+        let dir = std::env::temp_dir().join(format!("ctx_guard_stats_{}", std::process::id()));
+        let runs: Vec<RunRecord> = [("cargo test", 0, 9), ("cargo test", 101, 10), ("ls", 0, 11)]
+            .iter()
+            .map(|&(command, exit_code, hour)| {
+                let metadata = CommandMetadata {
+                    command: command.to_string(),
+                    exit_code,
+                    timestamp: Local.with_ymd_and_hms(2024, 1, 1, hour, 0, 0).unwrap().fixed_offset(),
+                    ..Default::default()
+                };
+                let filename = format!("run_20240101_{:02}0000.txt", hour);
+                let path = write_output_file_in(&dir, &filename, "output", Some(&metadata), CaptureOptions::default()).unwrap();
+                RunRecord { path, metadata }
+            })
+            .collect();
+
+        let stats = compute_stats(&runs, 1);
+        assert_eq!(stats.total_files, 3);
+        assert_eq!(stats.succeeded, 2);
+        assert_eq!(stats.failed, 1);
+        assert!(stats.total_bytes > 0);
+        assert_eq!(stats.oldest, Some(runs[0].metadata.timestamp));
+        assert_eq!(stats.newest, Some(runs[2].metadata.timestamp));
+        assert_eq!(stats.top_commands, vec![("cargo test".to_string(), 2)]);
+
+        assert_eq!(compute_stats(&[], 5), CaptureStats::default());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_parse_time_bound() {
+        let expected = Local.with_ymd_and_hms(2024, 1, 1, 13, 30, 0).unwrap();
+        assert_eq!(parse_time_bound("2024-01-01T13:30").unwrap(), expected);
+        assert_eq!(parse_time_bound("2024-01-01 13:30:00").unwrap(), expected);
+        assert_eq!(
+            parse_time_bound("2024-01-01").unwrap(),
+            Local.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            parse_time_bound("2024-01-01T12:00:00Z").unwrap(),
+            DateTime::parse_from_rfc3339("2024-01-01T12:00:00Z").unwrap()
+        );
+
+        assert!(matches!(parse_time_bound("yesterday"), Err(OutputError::InvalidTime(_))));
+        assert!(matches!(parse_time_bound("2024-13-01"), Err(OutputError::InvalidTime(_))));
+    }
+
+    #[test]
+    fn test_summary_window_parse() {
+        assert_eq!("whole".parse::<SummaryWindow>().unwrap(), SummaryWindow::Whole);
+        assert_eq!("head:10".parse::<SummaryWindow>().unwrap(), SummaryWindow::Head(10));
+        assert_eq!("tail: 50".parse::<SummaryWindow>().unwrap(), SummaryWindow::Tail(50));
+        assert_eq!("Both:5".parse::<SummaryWindow>().unwrap(), SummaryWindow::Both(5));
+        assert!("middle:5".parse::<SummaryWindow>().is_err());
+        assert!("tail".parse::<SummaryWindow>().is_err());
+        assert!("tail:-1".parse::<SummaryWindow>().is_err());
+        assert_eq!(SummaryWindow::Tail(50).to_string(), "tail:50");
+    }
+
+    #[test]
+    fn test_apply_summary_window() {
+        let lines: Vec<String> = (1..=10).map(|i| format!("line{}", i)).collect();
+        let output = lines.join("\n");
+
+        assert_eq!(apply_summary_window(&output, SummaryWindow::Whole), output);
+        assert_eq!(apply_summary_window(&output, SummaryWindow::Tail(20)), output);
+        assert_eq!(
+            apply_summary_window(&output, SummaryWindow::Head(2)),
+            "line1\nline2\n\n... (8 more lines omitted) ..."
+        );
+        assert_eq!(
+            apply_summary_window(&output, SummaryWindow::Tail(2)),
+            "... (8 earlier lines omitted) ...\n\nline9\nline10"
+        );
+        assert_eq!(
+            apply_summary_window(&output, SummaryWindow::Both(1)),
+            "line1\n\n... (8 lines omitted) ...\n\nline10"
+        );
+    }
+
+    #[test]
+    fn test_collapse_carriage_returns() {
+        let output = "Downloading\n 10% [=>   ]\r 50% [===> ]\r100% [=====]\nDone\n";
+        assert_eq!(collapse_carriage_returns(output), "Downloading\n100% [=====]\nDone\n");
+
+        // Shorter redraws only overwrite the start, like a terminal; erase-line clears the rest
+        assert_eq!(collapse_carriage_returns("Compiling foo\rBuilt"), "Builtling foo");
+        assert_eq!(collapse_carriage_returns("Compiling foo\r\x1b[KBuilt"), "Built");
+        assert_eq!(collapse_carriage_returns("\x1b[32mworking\x1b[0m\r\x1b[2Kfinished"), "finished");
+
+        // CRLF line endings are not redraws
+        assert_eq!(collapse_carriage_returns("one\r\ntwo\r\n"), "one\ntwo\n");
+        assert_eq!(collapse_carriage_returns("plain\x1b[31mred\x1b[0m\n"), "plain\x1b[31mred\x1b[0m\n");
+    }
+
+    #[test]
+    fn test_format_output_size() {
+        assert_eq!(format_output_size("one line\n"), "1 line / 9 bytes");
+        assert_eq!(format_output_size(&"x\n".repeat(4213)), "4,213 lines / 9 KB");
+        assert_eq!(format_output_size(&"y".repeat(3 * 1024 * 1024)), "1 line / 3.0 MB");
+        assert_eq!(group_thousands(1_234_567), "1,234,567");
+        assert_eq!(group_thousands(999), "999");
+    }
+
+    #[test]
+    fn test_generate_output_filename_fits_path_limits() {
+        let long_command = "é".repeat(5000);
+        let filename = generate_output_filename(&long_command, true, "txt", false);
+        assert!(filename.len() <= MAX_FILENAME_BYTES);
+        assert!(filename.ends_with(".txt"));
+
+        // A deep directory leaves less room, and the slug is shortened to fit
+        let deep_dir = PathBuf::from("/").join("d".repeat(MAX_PATH_BYTES - 40));
+        let filename = generate_output_filename_in(&deep_dir, &long_command, false, "txt", false);
+        assert!(deep_dir.join(&filename).as_os_str().len() <= MAX_PATH_BYTES);
+        assert!(filename.starts_with('é') && filename.ends_with(".txt"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_write_output_file_reports_long_path() {
+        let filename = format!("{}.txt", "x".repeat(MAX_FILENAME_BYTES + 10));
+        match write_output_file(&filename, "output", None, CaptureOptions::default()) {
+            Err(OutputError::PathTooLong(path)) => assert!(path.ends_with(&filename)),
+            other => panic!("expected a path length error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_use_utc_timestamps() {
+        let filename = generate_output_filename("cargo test", false, "txt", true);
+        let date = Utc::now().format("%Y%m%d").to_string();
+        // Allow for the date rolling over between the two calls
+        let yesterday = (Utc::now() - chrono::Duration::minutes(1)).format("%Y%m%d").to_string();
+        assert!(filename.contains(&date) || filename.contains(&yesterday));
+
+        let metadata = CommandMetadata {
+            command: "cargo test".to_string(),
+            timestamp: current_time(true),
+            ..Default::default()
+        };
+        assert!(format_metadata(&metadata).contains("+00:00\n"));
+
+        // The cleanup parser reads filename timestamps in the same timezone
+        let naive = NaiveDateTime::parse_from_str("20240101_120000", "%Y%m%d_%H%M%S").unwrap();
+        assert_eq!(file_timestamp(&naive, true), Some(Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap().fixed_offset()));
+    }
+
+    #[test]
+    fn test_start_output_file() {
+        let path = std::env::temp_dir()
+            .join(format!("ctx_guard_partial_{}", std::process::id()))
+            .join("make_20240101_120000.txt");
+        let metadata = CommandMetadata {
+            command: "make".to_string(),
+            exit_code: -1,
+            timestamp: Local::now().fixed_offset(),
+            ..Default::default()
+        };
+
+        let mut file = start_output_file(&path, &metadata, CaptureOptions::default()).unwrap();
+        file.write_all(b"compiling...\n").unwrap();
+        drop(file);
+
+        // An interrupted run leaves a readable capture marked as partial
+        let parsed = parse_metadata_from_file(&path).unwrap();
+        assert_eq!(parsed.command, "make");
+        assert_eq!(parsed.summary.as_deref(), Some(PARTIAL_CAPTURE_SUMMARY));
+        assert_eq!(read_output_from_file(&path).unwrap(), b"compiling...\n");
+
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn test_parse_metadata_values_with_colons() {
+        let section = "---CTX_GUARD_METADATA---\n\
+            command: curl -H 'Accept: application/json' http://localhost:8080/api\n\
+            exit_code: 0\n\
+            timestamp: 2024-01-01T12:00:00+01:00\n\
+            future_field: ignored: entirely\n\
+            summary: Status: 200 OK; body: {\"ok\": true}\n\
+            ---END_METADATA---";
+        let parsed = parse_metadata_section(section).unwrap();
+        assert_eq!(parsed.command, "curl -H 'Accept: application/json' http://localhost:8080/api");
+        assert_eq!(parsed.timestamp.to_rfc3339(), "2024-01-01T12:00:00+01:00");
+        assert_eq!(parsed.summary.as_deref(), Some("Status: 200 OK; body: {\"ok\": true}"));
+
+        // An empty value, with or without the space after the colon
+        let section = "command: ls\nexit_code: 0\ntimestamp: 2024-01-01T12:00:00+00:00\nsummary:";
+        assert_eq!(parse_metadata_section(section).unwrap().summary, None);
+    }
+
+    #[test]
+    fn test_parse_metadata_with_crlf_line_endings() {
+        let content = "---CTX_GUARD_METADATA---\r\ncommand: cargo test\r\nexit_code: 101\r\n\
+            timestamp: 2024-01-01T12:00:00+00:00\r\ntags: ticket-1,ci\r\nsummary: \r\n\
+            ---END_METADATA---\r\n\r\ntest result: FAILED\r\n";
+        let file_path = output_dir().join("test_crlf_metadata.txt");
+        ensure_output_dir(true).unwrap();
+        fs::write(&file_path, content).unwrap();
+
+        let parsed = parse_metadata_from_file(&file_path).unwrap();
+        assert_eq!(parsed.command, "cargo test");
+        assert_eq!(parsed.exit_code, 101);
+        assert_eq!(parsed.tags, vec!["ticket-1", "ci"]);
+        assert_eq!(parsed.summary, None);
+        assert_eq!(read_output_from_file(&file_path).unwrap(), b"test result: FAILED\r\n");
+
+        // Updating the summary keeps the output intact, without stray line breaks
+        update_output_file_summary(&file_path, "Tests failed").unwrap();
+        assert_eq!(parse_metadata_from_file(&file_path).unwrap().summary.as_deref(), Some("Tests failed"));
+        assert_eq!(read_output_from_file(&file_path).unwrap(), b"test result: FAILED\r\n");
+
+        let _ = fs::remove_file(&file_path);
+    }
+
+    #[test]
+    fn test_multiline_command_round_trip() {
+        let command = "python3 - <<'EOF'\nprint(\"hi\")\nEOF";
+        let metadata = CommandMetadata {
+            command: command.to_string(),
+            exit_code: 0,
+            timestamp: Local::now().fixed_offset(),
+            ..Default::default()
+        };
+        let file_path = write_output_file("test_multiline_command.txt", "hi", Some(&metadata), CaptureOptions::default()).unwrap();
+        update_output_file_summary(&file_path, "Printed hi").unwrap();
+
+        let parsed = parse_metadata_from_file(&file_path).unwrap();
+        assert_eq!(parsed.command, command);
+        assert_eq!(read_output_from_file(&file_path).unwrap(), b"hi");
+
+        // Single-line commands are stored as is, and old unquoted values still parse
+        assert_eq!(encode_command("cargo test"), "cargo test");
+        assert_eq!(decode_command("\"unterminated"), "\"unterminated");
+
+        let filename = generate_output_filename(command, false, "txt", false);
+        assert!(!filename.contains('\n'));
+        assert!(filename.starts_with("python3_-____EOF__print"));
+
+        let _ = fs::remove_file(&file_path);
+    }
+
+    #[test]
+    fn test_parse_tag() {
+        assert_eq!(parse_tag("ticket-123").unwrap(), "ticket-123");
+        assert_eq!(parse_tag("perf_run2").unwrap(), "perf_run2");
+        assert!(matches!(parse_tag(""), Err(OutputError::InvalidTag(_))));
+        assert!(matches!(parse_tag("a,b"), Err(OutputError::InvalidTag(_))));
+        assert!(matches!(parse_tag("two words"), Err(OutputError::InvalidTag(_))));
+    }
+
+    #[test]
+    fn test_cap_output_lines() {
+        let output: String = (1..=10).map(|i| format!("line{}\n", i)).collect();
+        assert_eq!(cap_output_lines(&output, 10), output);
+        assert_eq!(
+            cap_output_lines(&output, 3),
+            "line1\n\n... (7 lines omitted; output capped at 3 lines) ...\n\nline9\nline10"
+        );
+    }
+
+    #[test]
+    fn test_is_likely_binary() {
+        assert!(!is_likely_binary(b""));
+        assert!(!is_likely_binary(b"plain text\nwith lines\tand tabs\r\n"));
+        assert!(!is_likely_binary("unicode: åäö → ✓".as_bytes()));
+        assert!(!is_likely_binary(b"\x1b[31mcolored\x1b[0m output"));
+        assert!(is_likely_binary(b"ELF\x00\x01\x02"));
+        assert!(is_likely_binary(&[0xff, 0xfe, 0x01, 0x02, 0x03, 0x80, 0x81, b'a']));
+    }
+
+    #[test]
+    fn test_binary_capture_round_trip() {
+        let metadata = CommandMetadata {
+            command: "cat somebinary".to_string(),
+            exit_code: 0,
+            timestamp: Local::now().fixed_offset(),
+            summary: None,
+            ..Default::default()
+        };
+        let raw: Vec<u8> = vec![0x7f, b'E', b'L', b'F', 0x00, 0xff, 0xfe, b'\n'];
+        let file_path = write_output_file("test_binary_capture.txt", &raw, Some(&metadata), CaptureOptions::default()).unwrap();
+
+        update_output_file_summary(&file_path, "binary output, not summarized").unwrap();
+
+        let parsed = parse_metadata_from_file(&file_path).unwrap();
+        assert_eq!(parsed.command, "cat somebinary");
+        assert_eq!(parsed.summary, Some("binary output, not summarized".to_string()));
+        let content = fs::read(&file_path).unwrap();
+        assert!(content.ends_with(&raw));
+
+        let _ = fs::remove_file(&file_path);
+    }
+
+    #[test]
+    fn test_tail_lines() {
+        let output = "line1\nline2\nline3\nline4\n\n";
+        assert_eq!(tail_lines(output, 2), "line3\nline4");
+        assert_eq!(tail_lines(output, 10), "line1\nline2\nline3\nline4");
+        assert_eq!(tail_lines(output, 0), "");
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let old = "Compiling app\ntest a ... ok\ntest b ... FAILED\ntest c ... FAILED\n2 failed\n";
+        let new = "Compiling app\ntest a ... ok\ntest b ... ok\ntest c ... FAILED\ntest d ... ok\n1 failed\n";
+        assert_eq!(
+            diff_lines(old, new),
+            "- test b ... FAILED\n+ test b ... ok\n- 2 failed\n+ test d ... ok\n+ 1 failed"
+        );
+        assert_eq!(diff_lines("same\n", "same"), "");
+        assert_eq!(diff_lines("", "new"), "+ new");
+    }
+
+    #[test]
+    fn test_tail_bytes() {
+        assert_eq!(tail_bytes("short\n", 100), ("short\n", false));
+        // The partial first line is dropped
+        assert_eq!(tail_bytes("first line\nsecond\nthird\n", 12), ("third\n", true));
+        assert_eq!(tail_bytes("first line\nsecond\nthird\n", 13), ("second\nthird\n", true));
+        // Never splits a character
+        assert_eq!(tail_bytes("ab\u{e9}\u{e9}", 3), ("\u{e9}", true));
+    }
+
     #[test]
     fn test_ensure_output_dir() {
-        let result = ensure_output_dir();
+        let result = ensure_output_dir(true);
         assert!(result.is_ok());
         let dir = result.unwrap();
         assert!(dir.exists());
@@ -417,7 +2038,7 @@ mod tests {
         let filename = "test_output.txt";
         let content = "test content";
         
-        let result = write_output_file(filename, content, None);
+        let result = write_output_file(filename, content, None, CaptureOptions::default());
         assert!(result.is_ok());
         
         let file_path = result.unwrap();
@@ -430,6 +2051,18 @@ mod tests {
         let _ = fs::remove_file(&file_path);
     }
 
+    #[test]
+    fn test_write_output_file_in_unwritable_dir() {
+        // A directory "inside" a regular file can't be created, even with elevated permissions
+        let blocker = std::env::temp_dir().join(format!("ctx_guard_unwritable_{}", std::process::id()));
+        fs::write(&blocker, "not a directory").unwrap();
+
+        let result = write_output_file_in(&blocker.join("captures"), "test_unwritable.txt", "content", None, CaptureOptions::default());
+        assert!(matches!(result, Err(OutputError::DirectoryError(_))));
+
+        let _ = fs::remove_file(&blocker);
+    }
+
     #[test]
     fn test_write_output_file_with_metadata() {
         let filename = "test_output_metadata.txt";
@@ -437,11 +2070,12 @@ mod tests {
         let metadata = CommandMetadata {
             command: "echo test".to_string(),
             exit_code: 0,
-            timestamp: Local::now(),
+            timestamp: Local::now().fixed_offset(),
             summary: None,
+            ..Default::default()
         };
         
-        let result = write_output_file(filename, content, Some(&metadata));
+        let result = write_output_file(filename, content, Some(&metadata), CaptureOptions::default());
         assert!(result.is_ok());
         
         let file_path = result.unwrap();
@@ -463,11 +2097,12 @@ mod tests {
         let metadata = CommandMetadata {
             command: "ls -la".to_string(),
             exit_code: 0,
-            timestamp: Local::now(),
+            timestamp: Local::now().fixed_offset(),
             summary: Some("Listed files".to_string()),
+            ..Default::default()
         };
         
-        let file_path = write_output_file(filename, "output content", Some(&metadata)).unwrap();
+        let file_path = write_output_file(filename, "output content", Some(&metadata), CaptureOptions::default()).unwrap();
         
         let parsed = parse_metadata_from_file(&file_path);
         assert!(parsed.is_some());
@@ -480,16 +2115,190 @@ mod tests {
         let _ = fs::remove_file(&file_path);
     }
 
+    #[test]
+    fn test_update_output_file_metadata_keeps_large_body() {
+        let metadata = CommandMetadata { command: "yes".to_string(), ..Default::default() };
+        let body = "y\n".repeat(100_000);
+        let file_path = write_output_file("test_update_large_body.txt", &body, Some(&metadata), CaptureOptions::default()).unwrap();
+
+        update_output_file_metadata(&file_path, |metadata| metadata.summary = Some("Printed y".to_string())).unwrap();
+
+        assert_eq!(parse_metadata_from_file(&file_path).unwrap().summary.as_deref(), Some("Printed y"));
+        assert_eq!(read_output_from_file(&file_path).unwrap(), body.as_bytes());
+
+        let _ = fs::remove_file(&file_path);
+    }
+
+    #[test]
+    fn test_write_streamed_output_file() {
+        let dir = std::env::temp_dir().join(format!("ctx_guard_streamed_capture_{}", std::process::id()));
+        let partial = dir.join("partial.txt");
+        let metadata = CommandMetadata { command: "build".to_string(), exit_code: 2, ..Default::default() };
+        let body = "\nstep 1\n10%\r50%\r100%\nerror: failed\nstep 2\n";
+        let mut file = start_output_file(&partial, &metadata, CaptureOptions::default()).unwrap();
+        file.write_all(body.as_bytes()).unwrap();
+        drop(file);
+        // Everything but the first line break, then a literal
+        let parts = [CapturePart::Output { start: 1, len: body.len() as u64 - 1 }, CapturePart::Text("end".to_string())];
+        let text = "step 1\n10%\r50%\r100%\nerror: failed\nstep 2\nend";
+        let write = |filename: &str, text: Option<StreamedText>, options: CaptureOptions| {
+            let path = write_streamed_output_file(&partial, &parts, &dir, filename, &metadata, text, options).unwrap();
+            String::from_utf8(read_output_from_file(&path).unwrap()).unwrap()
+        };
+
+        // Processed as the same output held in memory would be
+        assert_eq!(write("raw.txt", None, CaptureOptions::default()), text);
+        let collapse = StreamedText { collapse_progress: true, max_lines: None };
+        assert_eq!(write("collapsed.txt", Some(collapse), CaptureOptions::default()), collapse_carriage_returns(text));
+        let capped = StreamedText { collapse_progress: true, max_lines: Some(3) };
+        assert_eq!(write("capped.txt", Some(capped), CaptureOptions::default()), collapse_carriage_returns(&cap_output_lines(text, 3)));
+        let options = CaptureOptions { transcript_header: true, ..Default::default() };
+        let path = write_streamed_output_file(&partial, &parts, &dir, "header.txt", &metadata, Some(StreamedText::default()), options).unwrap();
+        assert!(fs::read_to_string(&path).unwrap().contains("\n$ build\nexit: 2\n\nstep 1\n"));
+        assert_eq!(read_output_from_file(&path).unwrap(), text.as_bytes());
+
+        // The partial capture can be replaced by the finished one
+        let path = write_streamed_output_file(&partial, &parts, &dir, "partial.txt", &metadata, None, CaptureOptions::default()).unwrap();
+        assert_eq!(read_output_from_file(&path).unwrap(), text.as_bytes());
+        assert_eq!(parse_metadata_from_file(&path).unwrap().exit_code, 2);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_parse_metadata_with_resources() {
+        let metadata = CommandMetadata {
+            command: "cargo build".to_string(),
+            exit_code: 0,
+            timestamp: Local::now().fixed_offset(),
+            summary: None,
+            resources: Some(ResourceUsage {
+                max_rss_kb: 4096,
+                user_time: std::time::Duration::from_millis(1500),
+                system_time: std::time::Duration::from_millis(200),
+            }),
+            argv: Some(vec!["sh".to_string(), "-c".to_string(), "echo 'a  b'".to_string()]),
+            tags: vec!["ticket-123".to_string(), "nightly".to_string()],
+            summary_source: Some("llm (ollama, llama3)".to_string()),
+            cwd: Some("/home/dev/my project".to_string()),
+            run_id: Some("0123456789ab".to_string()),
+        };
+        let file_path = write_output_file("test_metadata_resources.txt", "built", Some(&metadata), CaptureOptions::default()).unwrap();
+        update_output_file_summary(&file_path, "Build succeeded").unwrap();
+
+        let parsed = parse_metadata_from_file(&file_path).unwrap();
+        assert_eq!(parsed.resources, metadata.resources);
+        assert_eq!(parsed.argv, metadata.argv);
+        assert_eq!(parsed.tags, metadata.tags);
+        assert_eq!(parsed.summary_source, metadata.summary_source);
+        assert_eq!(parsed.cwd, metadata.cwd);
+        assert_eq!(parsed.run_id, metadata.run_id);
+        assert_eq!(parsed.summary, Some("Build succeeded".to_string()));
+
+        let _ = fs::remove_file(&file_path);
+    }
+
+    #[test]
+    fn test_transcript_header_is_skipped_when_reading() {
+        let metadata = CommandMetadata {
+            command: "cargo test".to_string(),
+            exit_code: 101,
+            timestamp: Local::now().fixed_offset(),
+            ..Default::default()
+        };
+        let header = format_transcript_header(&metadata);
+        assert_eq!(header, "$ cargo test\nexit: 101\n\n");
+
+        let content = "test result: FAILED\n";
+        let options = CaptureOptions { transcript_header: true, ..Default::default() };
+        let file_path = write_output_file("test_transcript_header.txt", content, Some(&metadata), options).unwrap();
+
+        let raw = fs::read_to_string(&file_path).unwrap();
+        assert!(raw.contains("---END_METADATA---\n\n\n$ cargo test\nexit: 101\n\ntest result: FAILED\n"));
+        assert_eq!(parse_metadata_from_file(&file_path).unwrap().exit_code, 101);
+        assert_eq!(read_output_from_file(&file_path).unwrap(), b"test result: FAILED\n");
+
+        // Output that merely looks similar is left alone
+        let content = format!("{}{}", header, content);
+        let other = CommandMetadata { exit_code: 0, ..metadata };
+        assert_eq!(strip_transcript_header(&other, content.as_bytes()), content.as_bytes());
+
+        let _ = fs::remove_file(&file_path);
+    }
+
+    #[test]
+    fn test_read_output_from_file() {
+        let metadata = CommandMetadata {
+            command: "cargo test".to_string(),
+            exit_code: 101,
+            timestamp: Local::now().fixed_offset(),
+            summary: None,
+            ..Default::default()
+        };
+        let file_path = write_output_file("test_read_output.txt", "line1\nline2\n", Some(&metadata), CaptureOptions::default()).unwrap();
+        update_output_file_summary(&file_path, "Tests failed").unwrap();
+
+        assert_eq!(read_output_from_file(&file_path), Some(b"line1\nline2\n".to_vec()));
+
+        let unmarked = write_output_file("test_read_output_unmarked.txt", "raw", None, CaptureOptions::default()).unwrap();
+        assert_eq!(read_output_from_file(&unmarked), None);
+
+        let _ = fs::remove_file(&file_path);
+        let _ = fs::remove_file(&unmarked);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_restricted_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        let path = write_output_file("test_restricted_permissions.txt", "secret", None, CaptureOptions::default()).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(mode(&output_dir()), 0o700);
+
+        let sidecar = write_summary_sidecar(&path, "summary").unwrap();
+        assert_eq!(mode(&sidecar), 0o600);
+        let _ = fs::remove_file(&sidecar);
+
+        // Sidecars and metadata updates keep the capture's mode, however it was written
+        let metadata = CommandMetadata { command: "ls".to_string(), ..Default::default() };
+        let unrestricted = CaptureOptions { restrict_permissions: false, ..Default::default() };
+        let shared = write_output_file("test_unrestricted_permissions.txt", "shared", Some(&metadata), unrestricted).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o640)).unwrap();
+        update_output_file_summary(&shared, "Listed files").unwrap();
+        assert_eq!(mode(&shared), 0o640);
+        let sidecar = write_summary_sidecar(&shared, "summary").unwrap();
+        assert_eq!(mode(&sidecar), 0o640);
+
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&shared);
+        let _ = fs::remove_file(&sidecar);
+    }
+
+    #[test]
+    fn test_check_output_dir_writable() {
+        let dir = check_output_dir_writable(true).unwrap();
+        assert_eq!(dir, output_dir());
+        assert!(!dir.join(format!(".write_check_{}", std::process::id())).exists());
+    }
+
     #[test]
     fn test_cleanup_old_files_deletes_old() {
-        let dir = ensure_output_dir().unwrap();
+        let dir = ensure_output_dir(true).unwrap();
         
         // Create a file with an old timestamp (10 days ago)
         let old_date = Local::now() - chrono::Duration::days(10);
         let old_timestamp = old_date.format("%Y%m%d_%H%M%S").to_string();
         let old_filename = format!("test_command_{}.txt", old_timestamp);
-        let old_path = dir.join(&old_filename);
-        fs::write(&old_path, "old content").unwrap();
+        let old_metadata = CommandMetadata {
+            command: "test command".to_string(),
+            exit_code: 0,
+            timestamp: old_date.fixed_offset(),
+            summary: None,
+            ..Default::default()
+        };
+        let old_path = write_output_file(&old_filename, "old content", Some(&old_metadata), CaptureOptions::default()).unwrap();
         assert!(old_path.exists());
         
         // Create a file with a recent timestamp (1 day ago)
@@ -501,7 +2310,7 @@ mod tests {
         assert!(recent_path.exists());
         
         // Clean up files older than 5 days
-        cleanup_old_files(5);
+        cleanup_old_files(5, false, false);
         
         // Old file should be deleted
         assert!(!old_path.exists(), "Old file should have been deleted");
@@ -513,9 +2322,67 @@ mod tests {
         let _ = fs::remove_file(&recent_path);
     }
 
+    #[test]
+    fn test_cleanup_old_files_deletes_summary_sidecars() {
+        let old_date = Local::now() - chrono::Duration::days(10);
+        let filename = format!("sidecar_cleanup_{}.txt", old_date.format("%Y%m%d_%H%M%S"));
+        let metadata = CommandMetadata {
+            command: "sidecar cleanup".to_string(),
+            timestamp: old_date.fixed_offset(),
+            ..Default::default()
+        };
+        let path = write_output_file(&filename, "old content", Some(&metadata), CaptureOptions::default()).unwrap();
+        let sidecar = write_summary_sidecar(&path, "Old summary").unwrap();
+        assert_eq!(sidecar, path.with_file_name(format!("{}.summary.txt", filename)));
+        assert_eq!(fs::read_to_string(&sidecar).unwrap(), "Old summary\n");
+
+        cleanup_old_files(5, false, false);
+
+        assert!(!path.exists());
+        assert!(!sidecar.exists(), "the sidecar goes with its capture");
+    }
+
+    #[test]
+    fn test_cleanup_old_files_skips_unmarked() {
+        let dir = ensure_output_dir(true).unwrap();
+
+        // An old file that matches the naming pattern but wasn't written by ctx_guard
+        let old_date = Local::now() - chrono::Duration::days(10);
+        let old_timestamp = old_date.format("%Y%m%d_%H%M%S").to_string();
+        let foreign_path = dir.join(format!("foreign_notes_{}.txt", old_timestamp));
+        fs::write(&foreign_path, "not ours").unwrap();
+
+        cleanup_old_files(5, false, false);
+        assert!(foreign_path.exists(), "Unmarked file should be preserved");
+
+        // Legacy opt-out: unmarked files are cleaned up too
+        cleanup_old_files(5, true, false);
+        assert!(!foreign_path.exists(), "Unmarked file should be deleted when opted in");
+    }
+
+    #[test]
+    fn test_has_metadata_marker() {
+        let metadata = CommandMetadata {
+            command: "echo marker".to_string(),
+            exit_code: 0,
+            timestamp: Local::now().fixed_offset(),
+            summary: None,
+            ..Default::default()
+        };
+        let marked = write_output_file("test_marker_marked.txt", "content", Some(&metadata), CaptureOptions::default()).unwrap();
+        let unmarked = write_output_file("test_marker_unmarked.txt", "content", None, CaptureOptions::default()).unwrap();
+
+        assert!(has_metadata_marker(&marked));
+        assert!(!has_metadata_marker(&unmarked));
+        assert!(!has_metadata_marker(Path::new("/nonexistent/ctx_guard/file.txt")));
+
+        let _ = fs::remove_file(&marked);
+        let _ = fs::remove_file(&unmarked);
+    }
+
     #[test]
     fn test_cleanup_old_files_preserves_recent() {
-        let dir = ensure_output_dir().unwrap();
+        let dir = ensure_output_dir(true).unwrap();
         
         // Create a file with a recent timestamp (2 days ago)
         let recent_date = Local::now() - chrono::Duration::days(2);
@@ -526,7 +2393,7 @@ mod tests {
         assert!(recent_path.exists());
         
         // Clean up files older than 5 days
-        cleanup_old_files(5);
+        cleanup_old_files(5, false, false);
         
         // Recent file should still exist
         assert!(recent_path.exists(), "Recent file should still exist");
@@ -537,7 +2404,7 @@ mod tests {
 
     #[test]
     fn test_cleanup_old_files_skips_invalid_names() {
-        let dir = ensure_output_dir().unwrap();
+        let dir = ensure_output_dir(true).unwrap();
         
         // Create files with invalid names
         let invalid1 = dir.join("invalid_file.txt");
@@ -553,7 +2420,7 @@ mod tests {
         assert!(invalid3.exists());
         
         // Clean up files older than 5 days
-        cleanup_old_files(5);
+        cleanup_old_files(5, false, false);
         
         // Invalid files should still exist (they should be skipped)
         assert!(invalid1.exists(), "Invalid file should be skipped");
@@ -569,12 +2436,12 @@ mod tests {
     #[test]
     fn test_cleanup_old_files_handles_empty_directory() {
         // This should not panic or error
-        cleanup_old_files(5);
+        cleanup_old_files(5, false, false);
     }
 
     #[test]
     fn test_cleanup_old_files_exact_cutoff() {
-        let dir = ensure_output_dir().unwrap();
+        let dir = ensure_output_dir(true).unwrap();
         
         // Create a file just under the cutoff (4 days and 23 hours ago, so it's less than 5 days old)
         // This ensures the file is definitely within the cutoff when cleanup runs
@@ -586,7 +2453,7 @@ mod tests {
         assert!(cutoff_path.exists());
         
         // Clean up files older than 5 days (this file is less than 5 days old, so it should be kept)
-        cleanup_old_files(5);
+        cleanup_old_files(5, false, false);
         
         // File within cutoff should still exist (we delete files OLDER than the cutoff)
         assert!(cutoff_path.exists(), "File within cutoff should still exist");
@@ -0,0 +1,382 @@
+use crate::config::Config;
+use crate::executor::Shell;
+use std::fs;
+use std::path::Path;
+
+/// Words that start shell compound commands. Splitting at `;` inside them (e.g.
+/// `if a; then b; fi`) would break the command, so commands using them aren't traced.
+const RESERVED_WORDS: &[&str] = &[
+    "if", "then", "else", "elif", "fi", "for", "while", "until", "do", "done", "case", "esac", "select", "function",
+];
+
+/// Shell variable the instrumented command keeps each step's exit status in.
+const STATUS_VARIABLE: &str = "__ctx_guard_status";
+
+/// A compound command split at its top-level `&&`, `||`, `;` and newlines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pipeline {
+    pub steps: Vec<String>,
+    /// `separators[i]` joins `steps[i]` and `steps[i + 1]`; newlines are stored as `;`
+    pub separators: Vec<&'static str>,
+}
+
+/// What the trace file recorded about a step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    NotRun,
+    /// Started but never reported its exit code (killed, timed out or called `exit`)
+    Unfinished,
+    Exited(i32),
+}
+
+/// Returns true if the shell runs POSIX shell syntax, which `Pipeline::instrument` emits.
+pub fn supports_tracing(shell: &Shell) -> bool {
+    let name = Path::new(&shell.program)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    matches!(name.as_str(), "sh" | "bash" | "zsh" | "dash" | "ksh" | "ash" | "mksh")
+}
+
+impl Pipeline {
+    /// Splits a command into its steps. Returns None for commands with fewer than two
+    /// steps, and for syntax the splitter doesn't follow (unbalanced quotes, here-documents,
+    /// comments, background jobs, `if`/`for`/`case` blocks), which then run as written.
+    /// Parenthesized, braced and quoted parts are never split.
+    pub fn parse(command: &str) -> Option<Self> {
+        let chars: Vec<char> = command.chars().collect();
+        let mut steps = Vec::new();
+        let mut separators = Vec::new();
+        let mut current = String::new();
+        let mut depth = 0usize;
+        let mut quote: Option<char> = None;
+        let mut i = 0;
+
+        while i < chars.len() {
+            let c = chars[i];
+            let next = chars.get(i + 1).copied();
+
+            if let Some(open) = quote {
+                current.push(c);
+                if c == '\\' && open != '\'' {
+                    if let Some(next) = next {
+                        current.push(next);
+                        i += 1;
+                    }
+                } else if c == open {
+                    quote = None;
+                }
+                i += 1;
+                continue;
+            }
+
+            let separator = match (c, next) {
+                ('\\', _) => {
+                    current.push(c);
+                    if let Some(next) = next {
+                        current.push(next);
+                        i += 1;
+                    }
+                    i += 1;
+                    continue;
+                }
+                ('\'' | '"' | '`', _) => {
+                    quote = Some(c);
+                    None
+                }
+                // `[[ … ]]` may hold `&&` and `||` of its own, so it nests like parentheses
+                ('[', Some('[')) if at_word_start(&current) && chars.get(i + 2).is_none_or(|c| c.is_whitespace()) => {
+                    depth += 1;
+                    current.push_str("[[");
+                    i += 2;
+                    continue;
+                }
+                (']', Some(']')) if at_word_start(&current) && chars.get(i + 2).is_none_or(|c| c.is_whitespace() || ";&|)".contains(*c)) => {
+                    depth = depth.checked_sub(1)?;
+                    current.push_str("]]");
+                    i += 2;
+                    continue;
+                }
+                ('(' | '{', _) => {
+                    depth += 1;
+                    None
+                }
+                (')' | '}', _) => {
+                    depth = depth.checked_sub(1)?;
+                    None
+                }
+                ('#', _) if current.chars().last().is_none_or(char::is_whitespace) => return None,
+                ('<', Some('<')) => return None,
+                (';', Some(';')) => return None,
+                _ if depth > 0 => None,
+                ('&', Some('&')) => Some("&&"),
+                ('|', Some('|')) => Some("||"),
+                (';', _) => Some(";"),
+                ('\n', _) => Some(";"),
+                // `2>&1`, `>&2` and `&>` are redirections; any other `&` starts a background job
+                ('&', next) if current.ends_with(['>', '<']) || next == Some('>') => None,
+                ('&', _) => return None,
+                _ => None,
+            };
+
+            match separator {
+                Some(separator) => {
+                    steps.push(std::mem::take(&mut current).trim().to_string());
+                    separators.push(separator);
+                    i += separator.len().max(1);
+                }
+                None => {
+                    current.push(c);
+                    i += 1;
+                }
+            }
+        }
+        if quote.is_some() || depth != 0 {
+            return None;
+        }
+        steps.push(current.trim().to_string());
+
+        // A trailing `;` or newline ends the last step rather than starting another
+        while steps.last().is_some_and(String::is_empty) && separators.last() == Some(&";") {
+            steps.pop();
+            separators.pop();
+        }
+        if steps.len() < 2 || steps.iter().any(|step| step.is_empty()) {
+            return None;
+        }
+        let uses_reserved_word = steps.iter().any(|step| {
+            step.split_whitespace().next().is_some_and(|word| RESERVED_WORDS.contains(&word))
+        });
+        if uses_reserved_word {
+            return None;
+        }
+        Some(Self { steps, separators })
+    }
+
+    /// Rewrites the command so each step records its start and exit code in `trace_file`.
+    /// Steps run in the same shell as before (so `cd` and variables carry over), and each
+    /// step's exit status is passed on unchanged, so `&&`, `||` and `$?` behave as written.
+    pub fn instrument(&self, trace_file: &Path) -> String {
+        let file = shell_quote(&trace_file.to_string_lossy());
+        let mut instrumented = String::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            if i > 0 {
+                instrumented.push_str(&format!(" {} ", self.separators[i - 1]));
+            }
+            let number = i + 1;
+            instrumented.push_str(&format!(
+                "{{ {var}=$?; printf 'start {number}\\n' 2>/dev/null >> {file}; (exit ${var}); {step}\n\
+                 {var}=$?; printf 'end {number} %s\\n' \"${var}\" 2>/dev/null >> {file}; (exit ${var}); }}",
+                var = STATUS_VARIABLE,
+            ));
+        }
+        instrumented
+    }
+
+    /// Reads what the instrumented command recorded. With retries, the trace holds every
+    /// attempt; only the last one counts.
+    pub fn read_trace(&self, trace_file: &Path) -> Vec<StepOutcome> {
+        let mut outcomes = vec![StepOutcome::NotRun; self.steps.len()];
+        let trace = fs::read_to_string(trace_file).unwrap_or_default();
+        for line in trace.lines() {
+            let mut fields = line.split_whitespace();
+            let (Some(event), Some(Some(number))) = (fields.next(), fields.next().map(|n| n.parse::<usize>().ok())) else {
+                continue;
+            };
+            let Some(index) = number.checked_sub(1).filter(|&index| index < outcomes.len()) else {
+                continue;
+            };
+            match event {
+                "start" => {
+                    // A new attempt starts over from the first step
+                    if index == 0 {
+                        outcomes.fill(StepOutcome::NotRun);
+                    }
+                    outcomes[index] = StepOutcome::Unfinished;
+                }
+                "end" => {
+                    if let Some(Ok(code)) = fields.next().map(str::parse) {
+                        outcomes[index] = StepOutcome::Exited(code);
+                    }
+                }
+                _ => {}
+            }
+        }
+        outcomes
+    }
+
+    /// Describes how each step went, for the prompt. Returns None if no step was traced.
+    pub fn describe(&self, outcomes: &[StepOutcome], config: &Config) -> Option<String> {
+        if outcomes.iter().all(|outcome| *outcome == StepOutcome::NotRun) {
+            return None;
+        }
+        let mut lines = vec![format!("The command ran as {} steps:", self.steps.len())];
+        for (i, (step, outcome)) in self.steps.iter().zip(outcomes).enumerate() {
+            let status = match outcome {
+                StepOutcome::NotRun => "not run".to_string(),
+                StepOutcome::Unfinished => "started, but did not finish".to_string(),
+                StepOutcome::Exited(code) => format!("{} (exit code {})", config.status_label(*code), code),
+            };
+            lines.push(format!("{}. {}: {}", i + 1, step, status));
+        }
+
+        // The last step that ran is where the command stopped
+        let last_run = outcomes.iter().rposition(|outcome| *outcome != StepOutcome::NotRun);
+        let failed = outcomes.iter().rposition(|outcome| matches!(outcome, StepOutcome::Exited(code) if *code != 0));
+        match (last_run, failed) {
+            (Some(last), _) if outcomes[last] == StepOutcome::Unfinished => {
+                lines.push(format!("The command stopped during step {}.", last + 1));
+            }
+            (_, Some(failed)) => lines.push(format!("Step {} is the one that failed.", failed + 1)),
+            _ => {}
+        }
+        Some(lines.join("\n"))
+    }
+}
+
+/// True if the next character of a step would start a new word.
+fn at_word_start(current: &str) -> bool {
+    current.chars().last().is_none_or(|c| c.is_whitespace() || c == '(' || c == '!')
+}
+
+/// Quotes a value as a single-quoted shell word.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Rebuilds a command line from the arguments it was given as. A single argument is
+/// already a command line and is used as is; otherwise arguments that aren't plain words
+/// are quoted so each one stays a single word (`["grep", "a b"]` becomes `grep 'a b'`).
+pub fn join_argv(argv: &[String]) -> String {
+    if let [command] = argv {
+        return command.clone();
+    }
+    let is_plain = |arg: &str| !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+    argv.iter()
+        .map(|arg| if is_plain(arg) { arg.clone() } else { shell_quote(arg) })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps(command: &str) -> Option<Vec<String>> {
+        Pipeline::parse(command).map(|pipeline| pipeline.steps)
+    }
+
+    #[test]
+    fn test_join_argv() {
+        let argv = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(join_argv(&argv(&["cargo", "test", "--lib"])), "cargo test --lib");
+        assert_eq!(join_argv(&argv(&["grep", "a b", "it's", ""])), "grep 'a b' 'it'\\''s' ''");
+        assert_eq!(join_argv(&argv(&["ls | wc -l"])), "ls | wc -l");
+    }
+
+    #[test]
+    fn test_parse_splits_top_level_separators() {
+        let pipeline = Pipeline::parse("./build.sh && ./test.sh || echo failed; echo done").unwrap();
+        assert_eq!(pipeline.steps, vec!["./build.sh", "./test.sh", "echo failed", "echo done"]);
+        assert_eq!(pipeline.separators, vec!["&&", "||", ";"]);
+
+        assert_eq!(steps("make\nmake install\n").unwrap(), vec!["make", "make install"]);
+        // Pipes, redirections and quoted or grouped separators stay inside their step
+        assert_eq!(
+            steps("cargo build 2>&1 | tail -n 5 && echo 'a && b; c' && (cd x; make) && echo \"${HOME}\"").unwrap(),
+            vec!["cargo build 2>&1 | tail -n 5", "echo 'a && b; c'", "(cd x; make)", "echo \"${HOME}\""]
+        );
+        assert_eq!(steps("echo a\\;b; echo c").unwrap(), vec!["echo a\\;b", "echo c"]);
+    }
+
+    #[test]
+    fn test_parse_rejects_what_it_cannot_follow() {
+        assert_eq!(steps("cargo test"), None);
+        assert_eq!(steps("make;"), None);
+        assert_eq!(steps("sleep 1 & wait"), None);
+        assert_eq!(steps("if true; then echo yes; fi"), None);
+        assert_eq!(steps("for i in 1 2; do echo $i; done"), None);
+        assert_eq!(steps("cat <<EOF; echo\nEOF"), None);
+        assert_eq!(steps("echo a; # comment"), None);
+        assert_eq!(steps("echo 'unterminated; ls"), None);
+        assert_eq!(steps("echo a; ; echo b"), None);
+        assert_eq!(steps("echo ]] && ls"), None);
+    }
+
+    #[test]
+    fn test_parse_keeps_conditional_expressions_whole() {
+        assert_eq!(steps("[[ -f x && -f y ]] && echo ok"), Some(vec!["[[ -f x && -f y ]]".to_string(), "echo ok".to_string()]));
+        assert_eq!(steps("[[ -n $a || -n $b ]]; echo $?"), Some(vec!["[[ -n $a || -n $b ]]".to_string(), "echo $?".to_string()]));
+        assert_eq!(steps("[[ -f x && -f y ]]"), None);
+        // Plain brackets are left alone
+        assert_eq!(steps("echo a[[b && ls"), Some(vec!["echo a[[b".to_string(), "ls".to_string()]));
+    }
+
+    #[test]
+    fn test_supports_tracing() {
+        assert!(supports_tracing(&Shell::new("sh", None)));
+        assert!(supports_tracing(&Shell::new("/bin/bash", None)));
+        assert!(!supports_tracing(&Shell::new("cmd", None)));
+        assert!(!supports_tracing(&Shell::new("fish", None)));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_instrumented_command_keeps_behavior_and_records_steps() {
+        let trace = std::env::temp_dir().join(format!("ctx_guard_test_trace_{}", std::process::id()));
+        let _ = fs::remove_file(&trace);
+        let pipeline = Pipeline::parse("echo one && (exit 3) || echo \"rescued $?\"; false && echo skipped; echo $?").unwrap();
+
+        let output = std::process::Command::new("sh").arg("-c").arg(pipeline.instrument(&trace)).output().unwrap();
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "one\nrescued 3\n1\n");
+
+        let outcomes = pipeline.read_trace(&trace);
+        assert_eq!(
+            outcomes,
+            vec![
+                StepOutcome::Exited(0),
+                StepOutcome::Exited(3),
+                StepOutcome::Exited(0),
+                StepOutcome::Exited(1),
+                StepOutcome::NotRun,
+                StepOutcome::Exited(0),
+            ]
+        );
+        let _ = fs::remove_file(&trace);
+    }
+
+    #[test]
+    fn test_read_trace_keeps_the_last_attempt() {
+        let trace = std::env::temp_dir().join(format!("ctx_guard_test_trace_retry_{}", std::process::id()));
+        fs::write(&trace, "start 1\nend 1 0\nstart 2\nend 2 1\nstart 1\nend 1 0\nstart 2\n").unwrap();
+        let pipeline = Pipeline::parse("make && make test").unwrap();
+        assert_eq!(pipeline.read_trace(&trace), vec![StepOutcome::Exited(0), StepOutcome::Unfinished]);
+        let _ = fs::remove_file(&trace);
+    }
+
+    #[test]
+    fn test_describe() {
+        let config = Config::default();
+        let pipeline = Pipeline::parse("./build.sh && ./test.sh && ./deploy.sh").unwrap();
+
+        let description = pipeline
+            .describe(&[StepOutcome::Exited(0), StepOutcome::Exited(1), StepOutcome::NotRun], &config)
+            .unwrap();
+        assert_eq!(
+            description,
+            "The command ran as 3 steps:\n\
+             1. ./build.sh: succeeded (exit code 0)\n\
+             2. ./test.sh: failed (exit code 1)\n\
+             3. ./deploy.sh: not run\n\
+             Step 2 is the one that failed."
+        );
+
+        let description = pipeline
+            .describe(&[StepOutcome::Exited(0), StepOutcome::Unfinished, StepOutcome::NotRun], &config)
+            .unwrap();
+        assert!(description.ends_with("The command stopped during step 2."));
+
+        assert_eq!(pipeline.describe(&[StepOutcome::NotRun; 3], &config), None);
+    }
+}
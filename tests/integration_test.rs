@@ -18,7 +18,6 @@ fn test_binary_exists() {
         let release_binary = binary.parent().unwrap().parent().unwrap().join("release").join("cg");
         if !release_binary.exists() {
             println!("Binary not found, skipping integration test");
-            return;
         }
     }
 }
@@ -26,19 +25,20 @@ fn test_binary_exists() {
 #[test]
 fn test_help_output() {
     let output = Command::new("cargo")
-        .args(&["run", "--bin", "cg", "--", "--help"])
+        .args(["run", "--bin", "cg", "--", "--help"])
         .output()
         .expect("Failed to execute command");
     
     assert!(output.status.success());
     let stdout = String::from_utf8_lossy(&output.stdout);
     assert!(stdout.contains("cg") || stdout.contains("Context guard"));
+    assert!(stdout.contains("Use `--` to mark where the command starts"));
 }
 
 #[test]
 fn test_empty_command() {
     let output = Command::new("cargo")
-        .args(&["run", "--bin", "cg", "--"])
+        .args(["run", "--bin", "cg", "--"])
         .output()
         .expect("Failed to execute command");
     
@@ -53,7 +53,7 @@ fn test_simple_command() {
     let test_cmd = "echo test";
     
     let output = Command::new("cargo")
-        .args(&["run", "--bin", "cg", "--", test_cmd])
+        .args(["run", "--bin", "cg", "--", test_cmd])
         .output()
         .expect("Failed to execute command");
     
@@ -73,7 +73,7 @@ fn test_output_file_creation() {
     let filename = "test_integration.txt";
     let content = "integration test content";
     
-    let result = output::write_output_file(filename, content, None);
+    let result = output::write_output_file(filename, content, None, ctx_guard::output::CaptureOptions::default());
     assert!(result.is_ok());
     
     let file_path = result.unwrap();
@@ -92,7 +92,8 @@ fn test_config_default() {
     
     let config = Config::default();
     assert_eq!(config.provider.summary_words, 100);
-    assert!(!config.is_command_disabled("some command"));
+    assert!(!config.is_summary_disabled("some command"));
+    assert!(!config.is_command_blocked("some command"));
 }
 
 #[test]
@@ -109,3 +110,982 @@ fn test_executor_basic() {
     assert!(result.is_success());
 }
 
+
+#[test]
+fn test_print_path_only() {
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--print-path-only", "echo", "path-only"])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let path = PathBuf::from(stdout.trim());
+    assert_eq!(stdout.lines().count(), 1);
+    assert!(path.is_absolute());
+    assert!(fs::read_to_string(&path).unwrap().contains("path-only"));
+
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn test_summarize_stdin() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--summarize-stdin", "--exit-code", "3"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to execute command");
+    child.stdin.take().unwrap().write_all(b"piped tool output\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    // The reported exit code is mirrored, and the piped text is treated as the output
+    assert_eq!(output.status.code(), Some(3));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("<stdin>"));
+    assert!(stdout.contains("piped tool output"));
+}
+
+#[test]
+fn test_wrap_file() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_wrap_{}", std::process::id()));
+    let target = dir.join("nested").join("capture.log");
+
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--print-path-only", "--wrap-file"])
+        .arg(&target)
+        .args(["echo", "wrapped"])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(PathBuf::from(stdout.trim()), fs::canonicalize(&target).unwrap());
+    let content = fs::read_to_string(&target).unwrap();
+    assert!(content.contains("command: echo wrapped"));
+    assert!(content.contains("wrapped"));
+
+    // A directory target keeps the generated filename
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--print-path-only", "--wrap-file"])
+        .arg(&dir)
+        .args(["echo", "into-dir"])
+        .output()
+        .expect("Failed to execute command");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let path = PathBuf::from(stdout.trim());
+    assert_eq!(path.parent().unwrap(), fs::canonicalize(&dir).unwrap());
+    assert!(path.file_name().unwrap().to_string_lossy().starts_with("echo_into-dir_"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_disable_env_var_passthrough() {
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "echo passthrough-output; exit 4"])
+        .env("CTX_GUARD_DISABLE", "1")
+        .output()
+        .expect("Failed to execute command");
+
+    // Output is passed through untouched, with no summary or capture path
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "passthrough-output\n");
+
+    // A broken config is still reported instead of being replaced by the defaults
+    let config = std::env::temp_dir().join(format!("ctx_guard_disable_bad_config_{}.toml", std::process::id()));
+    fs::write(&config, "clean_up_days = \"soon\"\n").unwrap();
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--config", config.to_str().unwrap(), "echo passthrough-output"])
+        .env("CTX_GUARD_DISABLE", "1")
+        .output()
+        .expect("Failed to execute command");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Fix the config file"));
+    assert!(output.stdout.is_empty());
+
+    let _ = fs::remove_file(&config);
+}
+
+#[test]
+fn test_plan_does_not_execute() {
+    let marker = std::env::temp_dir().join(format!("ctx_guard_plan_marker_{}", std::process::id()));
+    let command = format!("touch {}", marker.display());
+
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--plan", &command])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("Command: {}", command)));
+    assert!(stdout.contains("Summarization disabled: no"));
+    assert!(stdout.contains("Output file: /tmp/ctx_guard/touch_"));
+    assert!(!marker.exists());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_max_output_lines_caps_file_and_prompt() {
+    let output = Command::new("cargo")
+        .args([
+            "run", "--bin", "cg", "--",
+            "--max-output-lines", "4", "--force-summary", "--no-cache", "--debug-llm", "--print-path-only",
+            "seq 1 100",
+        ])
+        .output()
+        .expect("Failed to execute command");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let path = PathBuf::from(stdout.trim());
+    let content = fs::read_to_string(&path).unwrap();
+    assert!(content.contains("1\n2\n\n... (96 lines omitted; output capped at 4 lines) ...\n\n99\n100"));
+    assert!(!content.contains("\n50\n"));
+
+    // The prompt (printed by --debug-llm) is built from the same capped output
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("(96 lines omitted; output capped at 4 lines)"));
+    assert!(!stderr.contains("\n50\n"));
+
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_skip_small_captures() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_skip_small_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let config_path = dir.join("config.toml");
+    fs::write(&config_path, "skip_small_captures = true\nsmall_capture_max_ms = 60000\n").unwrap();
+
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--config", config_path.to_str().unwrap(), "echo skip_small_marker"])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("skip_small_marker"));
+    assert!(!stdout.contains("complete output"));
+
+    // No capture was written for the run
+    let written = fs::read_dir("/tmp/ctx_guard")
+        .map(|entries| entries.flatten().any(|entry| entry.file_name().to_string_lossy().starts_with("echo_skip_small_marker")))
+        .unwrap_or(false);
+    assert!(!written);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_no_summarize_returns_raw_output() {
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--no-summarize", "seq 1 500"])
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("summarization skipped with --no-summarize"));
+    // Every line is returned, not a truncated excerpt
+    assert!(stdout.contains("\n250\n"));
+    assert!(stdout.contains("\n500\n"));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_full_on_failure() {
+    let run = |command: &str| {
+        let output = Command::new("cargo")
+            .args(["run", "--bin", "cg", "--", "--full-on-failure", "--no-cache", command])
+            .output()
+            .expect("Failed to execute command");
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    // No provider is running, so the summary falls back to an excerpt; the flag adds the rest
+    let failed = run("seq 1 500; exit 2");
+    assert!(failed.contains("\nFull output:\n\n1\n2\n"));
+    assert!(failed.contains("\n250\n"));
+
+    let succeeded = run("seq 1 500");
+    assert!(!succeeded.contains("Full output:"));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_context_flags() {
+    let run = |flag: &str| {
+        let output = Command::new("cargo")
+            .args(["run", "--bin", "cg", "--", flag, "--force-summary", "--no-cache", "--debug-llm", "echo context_flag_marker"])
+            .output()
+            .expect("Failed to execute command");
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+
+    // The capture of this run is among the most recent commands (other tests may run in between)
+    let with_count = run("--context-count=50");
+    assert!(with_count.contains("recently run commands:\n"));
+    assert!(with_count.contains("- echo context_flag_marker, "));
+
+    let with_minutes = run("--recent-minutes=5");
+    assert!(with_minutes.contains("- echo context_flag_marker, "));
+
+    let without = run("--no-recent-context");
+    assert!(!without.contains("recently run commands"));
+}
+
+#[test]
+fn test_flags_before_and_after_command() {
+    let plan = |args: &[&str]| {
+        let output = Command::new("cargo")
+            .args(["run", "--bin", "cg", "--", "--plan"])
+            .args(args)
+            .output()
+            .expect("Failed to execute command");
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    // Flags after the command's first word belong to the command
+    assert!(plan(&["--retry", "2", "cargo", "test", "--verbose"]).contains("Command: cargo test --verbose\n"));
+    assert!(plan(&["--retry", "2", "cargo", "test", "--verbose"]).contains("Retries: 2\n"));
+    assert!(plan(&["cargo", "test", "--retry", "2"]).contains("Command: cargo test --retry 2\n"));
+
+    // `--` separates cg's flags from the command, and only the first one is consumed
+    let separated = plan(&["--retry", "3", "--", "cargo", "test", "--release", "--", "--nocapture"]);
+    assert!(separated.contains("Command: cargo test --release -- --nocapture\n"));
+    assert!(separated.contains("Retries: 3\n"));
+    assert!(plan(&["--", "--not-a-cg-flag"]).contains("Command: --not-a-cg-flag\n"));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_allowlist_only_passes_other_commands_through() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_allowlist_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+    fs::write(&config, "allowlist_only = true\n\n[commands]\n\"echo listed\".summary_words = 50\n").unwrap();
+
+    let run = |command: &str| {
+        Command::new("cargo")
+            .args(["run", "--bin", "cg", "--", "--config", config.to_str().unwrap(), "--print-path-only", command])
+            .output()
+            .expect("Failed to execute command")
+    };
+
+    // Not listed: the output is passed through and no capture is written
+    let output = run("echo unlisted; exit 4");
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "unlisted\n");
+
+    // Listed: captured as usual
+    let output = run("echo listed");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.trim().ends_with(".txt"), "expected a capture path, got {:?}", stdout);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_doctor() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_doctor_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let doctor = |extra: &str| {
+        let config = dir.join("config.toml");
+        fs::write(&config, format!("{}\n[provider]\nurl = \"http://127.0.0.1:{}\"\n", extra, port)).unwrap();
+        Command::new("cargo")
+            .args(["run", "--bin", "cg", "--", "--config", config.to_str().unwrap(), "doctor"])
+            .output()
+            .expect("Failed to execute command")
+    };
+
+    // An unreachable provider is only a warning
+    let output = doctor("");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("[ok  ] Config: "));
+    assert!(stdout.contains("[ok  ] Output directory: /tmp/ctx_guard is writable"));
+    assert!(stdout.contains("[ok  ] Shell: sh -c"));
+    assert!(stdout.contains("[warn] Provider: nothing answers at"));
+
+    // A missing shell is critical
+    let output = doctor("shell = \"no-such-shell-for-cg\"");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout.contains("[FAIL] Shell: Shell 'no-such-shell-for-cg' was not found"));
+    assert!(stdout.contains("1 critical check(s) failed"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_provider_env_vars_override_config() {
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--plan", "echo hi"])
+        .env("CTX_GUARD_PROVIDER_TYPE", "ollama")
+        .env("CTX_GUARD_PROVIDER_URL", "http://model-host:11434")
+        .env("CTX_GUARD_MODEL", "llama3")
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Provider: llama3 at http://model-host:11434 (type ollama)"));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_summarize_session() {
+    let session = format!("it-session-{}", std::process::id());
+    for step in ["echo session_step_one", "sh -c 'echo session_step_two; exit 3'"] {
+        Command::new("cargo")
+            .args(["run", "--bin", "cg", "--", "--session", &session, step])
+            .output()
+            .expect("Failed to execute command");
+    }
+
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "summarize-session", &session])
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+    // No provider is running, so the runs of the session are listed instead
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("exit 0    echo session_step_one"));
+    assert!(stdout.contains("exit 3    sh -c 'echo session_step_two; exit 3'"));
+
+    let missing = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "summarize-session", "no-such-session"])
+        .output()
+        .expect("Failed to execute command");
+    assert!(!missing.status.success());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_diff_captures() {
+    let input = std::env::temp_dir().join(format!("cg_diff_input_{}.txt", std::process::id()));
+    let command = format!("cat {}", input.display());
+    let mut captures = Vec::new();
+    for contents in ["test a ... ok\ntest b ... FAILED\n", "test a ... ok\ntest b ... ok\n"] {
+        fs::write(&input, contents).unwrap();
+        let output = Command::new("cargo")
+            .args(["run", "--bin", "cg", "--", "--print-path-only", &command])
+            .output()
+            .expect("Failed to execute command");
+        captures.push(String::from_utf8_lossy(&output.stdout).trim().to_string());
+        // Capture filenames have a resolution of one second
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+    }
+
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "diff", &captures[0], &captures[1]])
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+    // No provider is running, so the changed lines are printed instead
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("- test b ... FAILED\n+ test b ... ok"));
+    assert!(!stdout.contains("test a"));
+
+    let missing = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "diff", &captures[0], "/nonexistent/capture.txt"])
+        .output()
+        .expect("Failed to execute command");
+    assert!(!missing.status.success());
+
+    let _ = fs::remove_file(&input);
+    for capture in &captures {
+        let _ = fs::remove_file(capture);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn test_quiet_trivial_success() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_quiet_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+    fs::write(&config, "quiet_trivial_success = true\nskip_trivial_captures = true\n").unwrap();
+
+    let run = |command: &str| {
+        Command::new("cargo")
+            .args(["run", "--bin", "cg", "--", "--config", config.to_str().unwrap(), command])
+            .output()
+            .expect("Failed to execute command")
+    };
+
+    let output = run("true");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+
+    // Failures and output are never hidden
+    let output = run("sh -c 'exit 3'");
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("exit code 3"));
+
+    let output = run("echo quiet_trivial_output");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("quiet_trivial_output"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_missing_shell_is_reported() {
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--shell", "/nonexistent/sh", "echo", "never_runs"])
+        .output()
+        .expect("Failed to execute command");
+
+    assert_eq!(output.status.code(), Some(127));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Shell '/nonexistent/sh' was not found. The command was not run"));
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("never_runs"));
+}
+
+#[test]
+fn test_explain_uses_remediation_prompt() {
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--explain", "--no-cache", "--debug-llm", "echo explain_marker; exit 2"])
+        .output()
+        .expect("Failed to execute command");
+
+    assert_eq!(output.status.code(), Some(2));
+    // The output is short, but --explain still sends it to the model
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("list the specific commands to run or edits to make"));
+    assert!(stderr.contains("explain_marker"));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_project_config_is_discovered() {
+    let project = std::env::temp_dir().join(format!("ctx_guard_project_config_{}", std::process::id()));
+    let nested = project.join("src");
+    fs::create_dir_all(project.join(".ctx_guard")).unwrap();
+    fs::create_dir_all(&nested).unwrap();
+    fs::write(
+        project.join(".ctx_guard").join("config.toml"),
+        "[commands]\n\"echo project_blocked\".block = true\n",
+    )
+    .unwrap();
+
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_cg"))
+            .args(args)
+            .current_dir(&nested)
+            .output()
+            .expect("Failed to execute command")
+    };
+
+    // The project config in a parent directory applies
+    let output = run(&["echo project_blocked"]);
+    assert!(!output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("project_blocked\n"));
+
+    // --config still wins
+    let explicit = project.join("explicit.toml");
+    fs::write(&explicit, "").unwrap();
+    let output = run(&["--config", explicit.to_str().unwrap(), "echo project_blocked"]);
+    assert!(output.status.success());
+
+    let _ = fs::remove_dir_all(&project);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_timeout_stops_command() {
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--timeout", "1", "--timeout-signal", "KILL", "echo timeout_marker; sleep 30"])
+        .output()
+        .expect("Failed to execute command");
+
+    assert_eq!(output.status.code(), Some(124));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("timeout_marker"));
+    assert!(stdout.contains("The command was stopped after its timeout of 1 seconds (SIGKILL)."));
+}
+
+#[test]
+fn test_run_id_and_show() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_run_id_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+    fs::write(&config, "show_run_id = true\n").unwrap();
+
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--config", config.to_str().unwrap(), "echo run_id_marker"])
+        .output()
+        .expect("Failed to execute command");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let run_id = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("Run ID: "))
+        .expect("the run ID is printed")
+        .to_string();
+    assert_eq!(run_id.len(), 12);
+
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "show", &run_id])
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Command: echo run_id_marker"));
+    assert!(stdout.contains("Exit code: 0"));
+    let capture = stdout.lines().find_map(|line| line.strip_prefix("Capture: ")).unwrap();
+    assert!(fs::read_to_string(capture).unwrap().contains(&format!("run_id: {}", run_id)));
+
+    let missing = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "show", "000000000000"])
+        .output()
+        .expect("Failed to execute command");
+    assert!(!missing.status.success());
+
+    let _ = fs::remove_file(capture);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_summarize_all_failures() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_all_failures_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+    fs::write(&config, "summarize_all_failures = true\ncache_ttl_minutes = 0\n").unwrap();
+
+    let run = |command: &str| {
+        let output = Command::new("cargo")
+            .args(["run", "--bin", "cg", "--", "--config", config.to_str().unwrap(), "--debug-llm", command])
+            .output()
+            .expect("Failed to execute command");
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    };
+
+    // Short failure output is sent to the model; short success output isn't
+    assert!(run("echo short_failure; exit 1").contains("--- ctx_guard debug: prompt for"));
+    assert!(!run("echo short_success").contains("--- ctx_guard debug: prompt for"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_summarize_if_duration_exceeds_secs() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_duration_threshold_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+    fs::write(&config, "summarize_if_duration_exceeds_secs = 60\ncache_ttl_minutes = 0\n").unwrap();
+
+    let run = |command: &str| {
+        let output = Command::new("cargo")
+            .args(["run", "--bin", "cg", "--", "--config", config.to_str().unwrap(), "--debug-llm", command])
+            .output()
+            .expect("Failed to execute command");
+        (String::from_utf8_lossy(&output.stdout).into_owned(), String::from_utf8_lossy(&output.stderr).into_owned())
+    };
+
+    // Long output of a quick success is returned as a bounded excerpt; a failure is
+    // still summarized
+    let (stdout, stderr) = run("seq 1 200");
+    assert!(stdout.contains("finished within 60 seconds"));
+    assert!(stdout.contains("\n200"));
+    assert!(stdout.contains("(160 lines omitted)"));
+    assert!(!stdout.contains("\n100\n"));
+    assert!(!stderr.contains("--- ctx_guard debug: prompt for"));
+    let (_, stderr) = run("seq 1 200; exit 1");
+    assert!(stderr.contains("--- ctx_guard debug: prompt for"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_passthrough_commands_are_not_captured() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_passthrough_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+    fs::write(&config, "passthrough_commands = [\"printf\"]\n").unwrap();
+
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--config", config.to_str().unwrap(), "--print-path-only", "printf 'passthrough_marker\\n'; exit 5"])
+        .output()
+        .expect("Failed to execute command");
+
+    // The output goes straight through, and no capture path is printed
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "passthrough_marker\n");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_transcript_header_in_capture() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_transcript_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+    fs::write(&config, "transcript_header = true\n").unwrap();
+
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--config", config.to_str().unwrap(), "--print-path-only", "echo transcript_marker; exit 3"])
+        .output()
+        .expect("Failed to execute command");
+    assert_eq!(output.status.code(), Some(3));
+
+    let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let content = fs::read_to_string(&path).unwrap();
+    assert!(content.contains("---END_METADATA---\n\n\n$ echo transcript_marker; exit 3\nexit: 3\n\ntranscript_marker\n"));
+    assert_eq!(ctx_guard::output::parse_metadata_from_file(std::path::Path::new(&path)).unwrap().exit_code, 3);
+    assert_eq!(ctx_guard::output::read_output_from_file(std::path::Path::new(&path)).unwrap(), b"transcript_marker\n");
+
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_trace_pipeline_reports_failed_step() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_trace_pipeline_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+    fs::write(&config, "trace_pipeline = true\n").unwrap();
+
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--config", config.to_str().unwrap(), "--force-summary", "--debug-llm", "echo step_one && sh -c 'exit 3' && echo step_three"])
+        .output()
+        .expect("Failed to execute command");
+
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("The command ran as 3 steps:"), "stderr: {}", stderr);
+    assert!(stderr.contains("2. sh -c 'exit 3': failed (exit code 3)"));
+    assert!(stderr.contains("3. echo step_three: not run"));
+    assert!(stderr.contains("Step 2 is the one that failed."));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_prompt_file_overrides_prompt() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_prompt_file_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let prompt_file = dir.join("prompt.txt");
+    fs::write(&prompt_file, "PROMPT_FILE_MARKER for ${command}:\n${output}\n").unwrap();
+
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--prompt-file", prompt_file.to_str().unwrap(), "--plan", "echo hi"])
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("PROMPT_FILE_MARKER for echo hi:\n[command output]"), "stdout: {}", stdout);
+
+    // Templates without ${output} are refused before the command runs
+    let bad_file = dir.join("bad.txt");
+    fs::write(&bad_file, "Summarize ${command}").unwrap();
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--prompt-file", bad_file.to_str().unwrap(), "echo prompt_file_should_not_run"])
+        .output()
+        .expect("Failed to execute command");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("prompt_file_should_not_run"));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("must contain ${output}"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_subdir_by_date_layout() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_subdir_by_date_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+    fs::write(&config, "subdir_by_date = true\n").unwrap();
+    let command = format!("echo subdir_by_date_{}", std::process::id());
+
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--config", config.to_str().unwrap(), "--print-path-only", &command])
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+
+    // The capture lands in a YYYY-MM-DD directory named after the date in its filename
+    let path = std::path::PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    let day = path.parent().unwrap().file_name().unwrap().to_string_lossy().to_string();
+    let filename = path.file_name().unwrap().to_string_lossy().to_string();
+    assert_eq!(path.parent().unwrap().parent().unwrap(), ctx_guard::output::output_dir());
+    assert!(filename.contains(&format!("_{}_", day.replace('-', ""))), "{} in {}", filename, day);
+
+    // History still finds it
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "history"])
+        .output()
+        .expect("Failed to execute command");
+    assert!(String::from_utf8_lossy(&output.stdout).contains(&command));
+
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_raw_stdout_passes_output_through() {
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--raw-stdout", "printf 'raw\\001bytes'; printf 'raw_stdout_err\\n' >&2; exit 7"])
+        .output()
+        .expect("Failed to execute command");
+
+    // Stdout holds exactly the command's stdout; everything cg adds goes to stderr
+    assert_eq!(output.status.code(), Some(7));
+    assert_eq!(output.stdout, b"raw\x01bytes");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("raw_stdout_err"));
+    assert!(stderr.contains("The complete output is available at"), "stderr: {}", stderr);
+
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--raw-stdout", "--print-path-only", "echo raw_stdout_path"])
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "raw_stdout_path\n");
+    let path = String::from_utf8_lossy(&output.stderr).lines().last().unwrap().to_string();
+    assert!(fs::read_to_string(&path).unwrap().contains("raw_stdout_path"));
+    let _ = fs::remove_file(&path);
+
+    // Output passed on can't be taken back, so the command isn't retried
+    let dir = std::env::temp_dir().join(format!("ctx_guard_raw_stdout_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+    fs::write(&config, "retry_count = 2\n").unwrap();
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--config", config.to_str().unwrap(), "--raw-stdout", "printf attempt; exit 1"])
+        .output()
+        .expect("Failed to execute command");
+    assert_eq!(output.stdout, b"attempt");
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--raw-stdout", "--retry", "2", "echo raw_stdout_retry"])
+        .output()
+        .expect("Failed to execute command");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_file_and_summary_streams() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_streams_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+
+    let run = |streams: &str| {
+        fs::write(&config, streams).unwrap();
+        let output = Command::new("cargo")
+            .args([
+                "run", "--bin", "cg", "--", "--config", config.to_str().unwrap(),
+                "--force-summary", "--no-cache", "--debug-llm", "--print-path-only",
+                // The markers are only spelled out in the output, not in the command
+                "printf 'stdout_%s\\n' marker; printf 'stderr_%s\\n' marker >&2",
+            ])
+            .output()
+            .expect("Failed to execute command");
+        let path = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+        // The body only: the fallback summary in the metadata quotes both streams
+        let content = String::from_utf8(ctx_guard::output::read_output_from_file(&path).unwrap()).unwrap();
+        let _ = fs::remove_file(&path);
+        (content, String::from_utf8_lossy(&output.stderr).into_owned())
+    };
+
+    // Only stdout is saved, while the model still sees stderr
+    let (content, prompt) = run("file_streams = \"stdout\"\nsummary_streams = \"stderr\"\n");
+    assert!(content.contains("stdout_marker"));
+    assert!(!content.contains("stderr_marker"));
+    assert!(prompt.contains("stderr_marker"));
+    assert!(!prompt.contains("stdout_marker"));
+
+    // And the other way around
+    let (content, prompt) = run("file_streams = \"stderr\"\nsummary_streams = \"stdout\"\n");
+    assert!(content.contains("stderr_marker"));
+    assert!(!content.contains("stdout_marker"));
+    assert!(prompt.contains("stdout_marker"));
+    assert!(!prompt.contains("stderr_marker"));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_stats_time_window() {
+    let run = |args: &[&str]| {
+        Command::new("cargo")
+            .args(["run", "--bin", "cg", "--", "stats"])
+            .args(args)
+            .output()
+            .expect("Failed to execute command")
+    };
+
+    // No capture can be from the future
+    let output = run(&["--since", "2999-01-01", "--json"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("\"total_files\": 0"));
+
+    let output = run(&["--since", "2024-02-01", "--until", "2024-01-01"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is after --until"));
+}
+
+#[test]
+#[cfg(unix)]
+fn test_summarize_anyway_respects_disabled_commands() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_summarize_anyway_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+    fs::write(&config, "[commands]\n\"echo disabled_marker\".summarize = false\n").unwrap();
+
+    let prompted = |args: &[&str]| {
+        let output = Command::new("cargo")
+            .args(["run", "--bin", "cg", "--", "--config", config.to_str().unwrap(), "--no-cache", "--debug-llm"])
+            .args(args)
+            .output()
+            .expect("Failed to execute command");
+        String::from_utf8_lossy(&output.stderr).contains("--- ctx_guard debug: prompt for")
+    };
+
+    // Short output is summarized, but not for a command with summarization disabled,
+    // and not with --no-summarize; --force-summary overrides the command's setting
+    assert!(prompted(&["--summarize-anyway", "echo short_marker"]));
+    assert!(!prompted(&["--summarize-anyway", "echo disabled_marker"]));
+    assert!(!prompted(&["--summarize-anyway", "--no-summarize", "echo short_marker"]));
+    assert!(prompted(&["--force-summary", "echo disabled_marker"]));
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_stream_to_file_keeps_long_output() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_stream_long_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+    fs::write(&config, "stream_to_file = true\n").unwrap();
+
+    // Over the 1 MiB kept in memory while streaming
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--config", config.to_str().unwrap(), "--print-path-only", "--no-cache"])
+        .arg("printf 'first_%s\\n' marker; head -c 3000000 /dev/zero | tr '\\0' x; printf '\\nlast_%s\\n' marker")
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+
+    let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let body = ctx_guard::output::read_output_from_file(std::path::Path::new(&path)).unwrap();
+    assert_eq!(body.len(), "first_marker\n".len() + 3_000_000 + "\nlast_marker\n".len());
+    assert!(body.starts_with(b"first_marker\n"));
+    assert!(body.ends_with(b"\nlast_marker\n"));
+    assert!(ctx_guard::output::parse_metadata_from_file(std::path::Path::new(&path)).unwrap().summary.is_some());
+
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_stream_to_file_long_output_honours_capture_settings() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_stream_settings_{}", std::process::id()));
+    let captures = dir.join("captures");
+    fs::create_dir_all(&captures).unwrap();
+    let config = dir.join("config.toml");
+    fs::write(&config, "stream_to_file = true\nfile_streams = \"stdout\"\ncollapse_progress = true\n").unwrap();
+
+    // Over the 1 MiB kept in memory while streaming, with stderr written in between
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--config", config.to_str().unwrap(), "--print-path-only", "--no-cache", "--wrap-file"])
+        .arg(format!("{}/", captures.display()))
+        .arg("printf 'first_%s\\n' marker; printf 'stderr_%s\\n' marker >&2; head -c 3000000 /dev/zero | tr '\\0' x; printf '\\nbar 1%%\\rbar 100%%\\n'; printf 'last_%s\\n' marker")
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success());
+
+    // The capture goes where --wrap-file says, holds stdout only and has progress collapsed
+    let path = std::path::PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    assert_eq!(path.parent(), Some(captures.as_path()));
+    let body = ctx_guard::output::read_output_from_file(&path).unwrap();
+    assert!(body.starts_with(b"first_marker\n"));
+    assert!(body.ends_with(b"\nbar 100%\nlast_marker\n"));
+    assert_eq!(body.len(), "first_marker\n".len() + 3_000_000 + "\nbar 100%\nlast_marker\n".len());
+    assert!(!String::from_utf8_lossy(&body).contains("stderr_marker"));
+    // Only the finished capture is left
+    assert_eq!(fs::read_dir(&captures).unwrap().count(), 1);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_rerun_keeps_argument_boundaries() {
+    let dir = std::env::temp_dir().join(format!("ctx_guard_rerun_argv_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let capture = dir.join("capture.txt");
+    let argv: Vec<String> = ["printf", "[%s]\\n", "a b", "it's"].iter().map(|arg| arg.to_string()).collect();
+    let metadata = ctx_guard::output::CommandMetadata { command: argv.join(" "), argv: Some(argv), ..Default::default() };
+    ctx_guard::output::write_output_file_in(&dir, "capture.txt", "", Some(&metadata), ctx_guard::output::CaptureOptions::default()).unwrap();
+
+    let output = Command::new("cargo")
+        .args(["run", "--bin", "cg", "--", "--print-path-only", "rerun", capture.to_str().unwrap()])
+        .output()
+        .expect("Failed to execute command");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    assert_eq!(ctx_guard::output::read_output_from_file(std::path::Path::new(&path)).unwrap(), b"[a b]\n[it's]\n");
+
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_signal_reaches_command_under_timeout() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let dir = std::env::temp_dir().join(format!("ctx_guard_forward_signal_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let pid_file = dir.join("pid");
+
+    // The command runs in its own process group because of --timeout
+    let mut cg = Command::new(env!("CARGO_BIN_EXE_cg"))
+        .args(["--timeout", "60", "--no-summarize"])
+        .arg(format!("sleep 60 & echo $! > {}; wait", pid_file.display()))
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let sleep_pid = loop {
+        if let Some(pid) = fs::read_to_string(&pid_file).ok().and_then(|pid| pid.trim().parse::<u32>().ok()) {
+            break pid;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    };
+
+    Command::new("kill").args(["-TERM", &cg.id().to_string()]).status().unwrap();
+    let status = cg.wait().unwrap();
+    assert_eq!(status.signal(), Some(libc::SIGTERM));
+
+    // Gone, or a zombie left for init to reap, once the signal has been handled
+    let stopped = (0..100).any(|_| {
+        let state = fs::read_to_string(format!("/proc/{}/stat", sleep_pid)).unwrap_or_default();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        state.is_empty() || state.contains(") Z ")
+    });
+    assert!(stopped);
+
+    let _ = fs::remove_dir_all(&dir);
+}
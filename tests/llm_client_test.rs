@@ -0,0 +1,428 @@
+use ctx_guard::config::ProviderConfig;
+use ctx_guard::llm::{LlmClient, LlmError};
+use serde_json::json;
+use wiremock::matchers::{body_partial_json, body_string_contains, header, method, path};
+use std::time::{Duration, Instant};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn client_for(server: &MockServer, provider_type: &str) -> LlmClient {
+    let provider = ProviderConfig {
+        r#type: provider_type.to_string(),
+        url: server.uri(),
+        ..Default::default()
+    };
+    LlmClient::from_provider(&provider).unwrap()
+}
+
+fn chat_completion(content: &str) -> serde_json::Value {
+    json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": content },
+            "finish_reason": "stop"
+        }]
+    })
+}
+
+#[tokio::test]
+async fn test_summarize_sends_chat_completion_request() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .and(header("content-type", "application/json"))
+        // temperature is left out: as an f32 it doesn't round-trip to exactly 0.7
+        .and(body_partial_json(json!({
+            "model": "test-model",
+            "messages": [{ "role": "user", "content": "summarize this" }],
+            "max_tokens": 500
+        })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(chat_completion("  The build passed.\n")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let summary = client_for(&server, "lmstudio").summarize("test-model", "summarize this").await.unwrap();
+    assert_eq!(summary, "The build passed.");
+}
+
+#[tokio::test]
+async fn test_summarize_skips_empty_first_choice() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "choices": [
+                { "index": 0, "message": { "role": "assistant", "content": "" }, "finish_reason": "length" },
+                { "index": 1, "message": { "role": "assistant", "content": "Lint passed." }, "finish_reason": "stop" }
+            ]
+        })))
+        .mount(&server)
+        .await;
+
+    let summary = client_for(&server, "lmstudio").summarize("test-model", "prompt").await.unwrap();
+    assert_eq!(summary, "Lint passed.");
+}
+
+#[tokio::test]
+async fn test_summarize_retries_empty_summary_once() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(body_string_contains("Provide a concise summary of the above output."))
+        .respond_with(ResponseTemplate::new(200).set_body_json(chat_completion("Second try worked.")))
+        .with_priority(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(chat_completion("<think>hmm</think>")))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let provider = ProviderConfig {
+        url: server.uri(),
+        retry_empty_summary: true,
+        ..Default::default()
+    };
+    let client = LlmClient::from_provider(&provider).unwrap();
+    assert_eq!(client.summarize("test-model", "prompt").await.unwrap(), "Second try worked.");
+
+    // Off by default: the empty answer is returned as is
+    let summary = client_for(&server, "lmstudio").summarize("test-model", "prompt").await.unwrap();
+    assert_eq!(summary, "<think>hmm</think>");
+}
+
+#[tokio::test]
+async fn test_summarize_ollama_backend() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/api/chat"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "model": "llama3",
+            "message": { "role": "assistant", "content": "All tests passed." },
+            "done": true
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let summary = client_for(&server, "ollama").summarize("llama3", "summarize this").await.unwrap();
+    assert_eq!(summary, "All tests passed.");
+}
+
+#[tokio::test]
+async fn test_summarize_custom_chat_path() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/api/v1/chat/completions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(chat_completion("Gateway reply.")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let provider = ProviderConfig {
+        url: server.uri(),
+        chat_path: Some("/api/v1/chat/completions".to_string()),
+        ..Default::default()
+    };
+    let summary = LlmClient::from_provider(&provider).unwrap().summarize("test-model", "prompt").await.unwrap();
+    assert_eq!(summary, "Gateway reply.");
+}
+
+#[tokio::test]
+async fn test_summarize_falls_back_to_streaming() {
+    let server = MockServer::start().await;
+    let stream_body = concat!(
+        "data: {\"choices\":[{\"delta\":{\"content\":\"Streamed \"}}]}\n\n",
+        "data: {\"choices\":[{\"delta\":{\"content\":\"summary.\"}}]}\n\n",
+        "data: [DONE]\n\n"
+    );
+    Mock::given(method("POST"))
+        .and(body_partial_json(json!({ "stream": true })))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(stream_body, "text/event-stream"))
+        .with_priority(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(400).set_body_json(json!({ "error": "Only stream=true is supported" })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let summary = client_for(&server, "lmstudio").summarize("test-model", "prompt").await.unwrap();
+    assert_eq!(summary, "Streamed summary.");
+}
+
+#[tokio::test]
+async fn test_summarize_reads_unrequested_stream() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"Done.\"},\"done\":true}\n",
+            "application/x-ndjson",
+        ))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let summary = client_for(&server, "ollama").summarize("llama3", "prompt").await.unwrap();
+    assert_eq!(summary, "Done.");
+}
+
+#[tokio::test]
+async fn test_summarize_and_verify_uses_corrected_summary() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(body_string_contains("omits or misstates"))
+        .and(body_string_contains("Summary:\\nAll good."))
+        .respond_with(ResponseTemplate::new(200).set_body_json(chat_completion("Build failed: error[E0308] in src/lib.rs.")))
+        .with_priority(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(chat_completion("All good.")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let summary = client_for(&server, "lmstudio")
+        .summarize_and_verify("test-model", "summarize this", "error[E0308]: mismatched types")
+        .await
+        .unwrap();
+    assert_eq!(summary, "Build failed: error[E0308] in src/lib.rs.");
+}
+
+#[tokio::test]
+async fn test_summarize_and_verify_keeps_summary_when_check_fails() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(body_string_contains("omits or misstates"))
+        .respond_with(ResponseTemplate::new(500))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(chat_completion("All good.")))
+        .mount(&server)
+        .await;
+
+    let summary = client_for(&server, "lmstudio").summarize_and_verify("test-model", "prompt", "output").await.unwrap();
+    assert_eq!(summary, "All good.");
+}
+
+#[tokio::test]
+async fn test_unreachable_provider() {
+    // A port that was just free has nothing listening on it
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let provider = ProviderConfig {
+        url: format!("http://127.0.0.1:{}", port),
+        ..Default::default()
+    };
+    let client = LlmClient::from_provider(&provider).unwrap();
+
+    let result = client.summarize("test-model", "prompt").await;
+    assert!(result.unwrap_err().is_unreachable());
+}
+
+#[tokio::test]
+async fn test_check_reachable() {
+    // Any response counts, even a 404 for the base URL
+    let server = MockServer::start().await;
+    client_for(&server, "lmstudio").check_reachable().await.unwrap();
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let provider = ProviderConfig {
+        url: format!("http://127.0.0.1:{}", port),
+        ..Default::default()
+    };
+    let result = LlmClient::from_provider(&provider).unwrap().check_reachable().await;
+    assert!(result.unwrap_err().is_unreachable());
+}
+
+#[tokio::test]
+async fn test_summarize_rate_limited() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(429).set_body_string("Too Many Requests"))
+        .mount(&server)
+        .await;
+
+    let result = client_for(&server, "lmstudio").summarize("test-model", "prompt").await;
+    match result {
+        Err(LlmError::RequestError(e)) => assert_eq!(e.status().map(|s| s.as_u16()), Some(429)),
+        other => panic!("expected a request error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_summarize_server_error() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server)
+        .await;
+
+    let result = client_for(&server, "lmstudio").summarize("test-model", "prompt").await;
+    match result {
+        Err(LlmError::RequestError(e)) => assert_eq!(e.status().map(|s| s.as_u16()), Some(500)),
+        other => panic!("expected a request error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_summarize_empty_choices() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "choices": [] })))
+        .mount(&server)
+        .await;
+
+    let result = client_for(&server, "lmstudio").summarize("test-model", "prompt").await;
+    assert!(matches!(result, Err(LlmError::NoContent)));
+}
+
+#[tokio::test]
+async fn test_summarize_error_payload_with_success_status() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "error": { "message": "No models loaded. Please load a model first.", "type": "invalid_request_error" }
+        })))
+        .mount(&server)
+        .await;
+
+    let result = client_for(&server, "lmstudio").summarize("test-model", "prompt").await;
+    match result {
+        Err(LlmError::ErrorResponse(message)) => assert_eq!(message, "No models loaded. Please load a model first."),
+        other => panic!("expected an error response, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_summarize_malformed_json() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw("{\"choices\": [", "application/json"))
+        .mount(&server)
+        .await;
+
+    let result = client_for(&server, "lmstudio").summarize("test-model", "prompt").await;
+    assert!(result.is_err());
+    assert!(!matches!(result, Err(LlmError::NoContent)));
+}
+
+#[tokio::test]
+async fn test_oversized_prompt_is_never_sent() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(chat_completion("unused")))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let provider = ProviderConfig {
+        url: server.uri(),
+        max_prompt_chars: 5,
+        ..Default::default()
+    };
+    let client = LlmClient::from_provider(&provider).unwrap();
+    let result = client.summarize("test-model", "far too long").await;
+    assert!(matches!(result, Err(LlmError::PromptTooLarge { .. })));
+}
+
+#[tokio::test]
+async fn test_summarize_sends_extra_params() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .and(body_partial_json(json!({ "model": "test-model", "seed": 42, "top_p": 0.5 })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(chat_completion("Build passed.")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let mut extra_params = toml::Table::new();
+    extra_params.insert("seed".to_string(), toml::Value::Integer(42));
+    extra_params.insert("top_p".to_string(), toml::Value::Float(0.5));
+    let provider = ProviderConfig {
+        url: server.uri(),
+        extra_params,
+        ..Default::default()
+    };
+    let client = LlmClient::from_provider(&provider).unwrap();
+    assert_eq!(client.summarize("test-model", "summarize this").await.unwrap(), "Build passed.");
+}
+
+#[tokio::test]
+async fn test_warm_up_sends_minimal_request() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/v1/chat/completions"))
+        .and(body_partial_json(json!({ "model": "test-model", "max_tokens": 1 })))
+        .respond_with(ResponseTemplate::new(200).set_body_json(chat_completion("OK")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    client_for(&server, "lmstudio").warm_up("test-model").await.unwrap();
+}
+
+#[tokio::test]
+async fn test_concurrent_requests_are_limited() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(chat_completion("done"))
+                .set_delay(Duration::from_millis(200)),
+        )
+        .expect(4)
+        .mount(&server)
+        .await;
+
+    let provider = ProviderConfig {
+        url: server.uri(),
+        max_concurrent_requests: 2,
+        ..Default::default()
+    };
+    let client = LlmClient::from_provider(&provider).unwrap();
+
+    // Four requests through a limit of two take at least two delay periods
+    let start = Instant::now();
+    let mut tasks = tokio::task::JoinSet::new();
+    for i in 0..4 {
+        let client = client.clone();
+        tasks.spawn(async move { client.summarize("test-model", &format!("chunk {}", i)).await });
+    }
+    while let Some(result) = tasks.join_next().await {
+        assert_eq!(result.unwrap().unwrap(), "done");
+    }
+    assert!(start.elapsed() >= Duration::from_millis(400));
+}
+
+#[tokio::test]
+async fn test_requests_per_minute_are_limited() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(chat_completion("done")))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let provider = ProviderConfig {
+        url: server.uri(),
+        max_requests_per_minute: 1,
+        rate_limit_wait_secs: 0,
+        ..Default::default()
+    };
+    let client = LlmClient::from_provider(&provider).unwrap();
+
+    // The limit is shared by clones of the client, and the second request would have
+    // to wait a minute, so it is refused without being sent
+    assert_eq!(client.summarize("test-model", "chunk 1").await.unwrap(), "done");
+    let result = client.clone().summarize("test-model", "chunk 2").await;
+    assert!(matches!(result, Err(LlmError::RateLimited(0))));
+}